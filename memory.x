@@ -3,7 +3,9 @@
 MEMORY
 {
   /* FLASH and RAM are mandatory memory regions */
-  FLASH  : ORIGIN = 0x08000000, LENGTH = 256K
+  FLASH  : ORIGIN = 0x08000000, LENGTH = 254K
+  /* The last 2K page holds the saved keymap, see src/flash.rs */
+  SETTINGS : ORIGIN = 0x0803F800, LENGTH = 2K
   /* .bss, .data and the heap go in this region */
   RAM : ORIGIN = 0x20000000, LENGTH = 40K
   /* Core coupled (faster) RAM dedicated to hold the stack */
@@ -0,0 +1,271 @@
+#![deny(unsafe_code)]
+#![deny(warnings)]
+#![no_main]
+#![no_std]
+
+use cortex_m::asm::delay;
+use my_app as _;
+
+use my_app::hid::{HidClass, HidError};
+use my_app::keyboard::Keyboard;
+use my_app::leds::{CapsLockLed, LedPin, LedSink, Polarity};
+use my_app::power::RemoteWakeup;
+use my_app::raw::{self, CommandHandler, RawHid, RawReportSize, Status};
+use rtic::app;
+use stm32f3xx_hal::gpio::{gpioa, gpioc, Input, Output, PullUp, PushPull};
+use stm32f3xx_hal::prelude::*;
+use stm32f3xx_hal::timer;
+use stm32f3xx_hal::timer::Timer;
+use stm32f3xx_hal::usb::Peripheral;
+use stm32f3xx_hal::usb::UsbBus;
+use usb_device::bus::UsbBusAllocator;
+use usb_device::class::UsbClass as _;
+use usb_device::device::UsbDeviceBuilder;
+use usb_device::device::UsbVidPid;
+
+type UsbDevice = usb_device::device::UsbDevice<'static, UsbBus<Peripheral>>;
+type KeyboardClass<L = ()> = HidClass<'static, UsbBus<Peripheral>, Keyboard<L>>;
+type RawClass = HidClass<'static, UsbBus<Peripheral>, RawHid>;
+
+const RAW_REPORT_SIZE: RawReportSize = RawReportSize::Bytes32;
+
+/// The boot keyboard shows caps lock on the board LED.
+type BootLeds = CapsLockLed<gpioc::PC13<Output<PushPull>>>;
+
+/// Answers with the number of reports dropped by each keyboard, two little endian `u32`.
+const CMD_DROPPED_REPORTS: u8 = 0x10;
+
+/// Raw HID commands for host tools.
+struct Diagnostics {
+    dropped_reports: [u32; 2],
+}
+
+impl CommandHandler for Diagnostics {
+    fn handle(
+        &mut self,
+        command: u8,
+        _request: &[u8],
+        response: &mut [u8],
+    ) -> Result<usize, Status> {
+        match command {
+            CMD_DROPPED_REPORTS => {
+                let response = response.get_mut(..8).ok_or(Status::BadLength)?;
+                for (bytes, dropped) in response.chunks_mut(4).zip(self.dropped_reports.iter()) {
+                    bytes.copy_from_slice(&dropped.to_le_bytes());
+                }
+                Ok(8)
+            }
+            _ => Err(Status::UnknownCommand),
+        }
+    }
+}
+
+// Generic keyboard from
+// https://github.com/obdev/v-usb/blob/master/usbdrv/USB-IDs-for-free.txt
+const PID: u16 = 0x27db;
+const VID: u16 = 0x16c0;
+
+// One device exposing two keyboard interfaces and a raw HID one for host tools, each with its own
+// endpoints and report descriptor
+#[app(device = stm32f3xx_hal::pac, peripherals = true)]
+const APP: () = {
+    // Global resources (global variables) are defined here and initialized with the
+    // `LateResources` struct in init
+    struct Resources {
+        usb_device: UsbDevice,
+        boot_keyboard: KeyboardClass<BootLeds>,
+        keyboard: KeyboardClass,
+        raw_hid: RawClass,
+        boot_button: gpioa::PA4<Input<PullUp>>,
+        button: gpioa::PA6<Input<PullUp>>,
+        timer: Timer<stm32f3xx_hal::stm32::TIM3>,
+        remote_wakeup: RemoteWakeup,
+    }
+
+    #[init]
+    fn init(cx: init::Context) -> init::LateResources {
+        static mut USB_BUS: Option<UsbBusAllocator<UsbBus<Peripheral>>> = None;
+
+        defmt::info!("hi");
+
+        let device: stm32f3xx_hal::stm32::Peripherals = cx.device;
+
+        // Setup clocks
+        let mut flash = device.FLASH.constrain();
+        let mut rcc = device.RCC.constrain();
+        let clocks = rcc
+            .cfgr
+            .use_hse(8.mhz())
+            .sysclk(48.mhz())
+            .pclk1(24.mhz())
+            .pclk2(24.mhz())
+            .freeze(&mut flash.acr);
+        assert!(clocks.usbclk_valid());
+
+        let mut gpioa = device.GPIOA.split(&mut rcc.ahb);
+        let mut gpioc = device.GPIOC.split(&mut rcc.ahb);
+
+        // Pull the D+ pin down to send a RESET condition to the USB bus.
+        let mut usb_dp = gpioa
+            .pa12
+            .into_push_pull_output(&mut gpioa.moder, &mut gpioa.otyper);
+        usb_dp.set_low().expect("Couldn't reset the USB bus!");
+        delay(clocks.sysclk().0 / 100); // USB Startup time for STM32F303 is 1µs
+
+        let usb_dm = gpioa.pa11.into_af14(&mut gpioa.moder, &mut gpioa.afrh);
+        let usb_dp = usb_dp.into_af14(&mut gpioa.moder, &mut gpioa.afrh);
+
+        let usb = Peripheral {
+            usb: device.USB,
+            pin_dm: usb_dm,
+            pin_dp: usb_dp,
+        };
+        *USB_BUS = Some(UsbBus::new(usb));
+        let usb_bus = USB_BUS
+            .as_ref()
+            .expect("Couldn't make the USB_BUS a static reference");
+
+        // Interfaces are numbered in allocation order, and must be polled in that order
+        let led = gpioc
+            .pc13
+            .into_push_pull_output(&mut gpioc.moder, &mut gpioc.otyper);
+        let mut caps_lock = LedPin::new(led, Polarity::ActiveLow);
+        caps_lock.set(false).unwrap();
+        let boot_keyboard = Keyboard::new()
+            .with_name("Boot keyboard")
+            .with_leds(CapsLockLed(caps_lock));
+        let boot_keyboard = HidClass::new(boot_keyboard, usb_bus);
+        let keyboard = HidClass::builder(Keyboard::new().with_name("Fast keyboard"), usb_bus)
+            .poll_interval(1)
+            .build();
+        let raw_hid = HidClass::builder(RawHid::new(RAW_REPORT_SIZE), usb_bus)
+            .max_packet_size(RAW_REPORT_SIZE as u16)
+            .interrupt_out(true)
+            .build();
+        let usb_device = UsbDeviceBuilder::new(usb_bus, UsbVidPid(VID, PID))
+            .manufacturer("ando")
+            .product("nano composite")
+            .serial_number(env!("CARGO_PKG_VERSION"))
+            .supports_remote_wakeup(true)
+            .build();
+
+        let mut timer = timer::Timer::tim3(device.TIM3, 1.khz(), clocks, &mut rcc.apb1);
+        timer.listen(timer::Event::Update);
+
+        let boot_button = gpioa
+            .pa4
+            .into_pull_up_input(&mut gpioa.moder, &mut gpioa.pupdr);
+        let button = gpioa
+            .pa6
+            .into_pull_up_input(&mut gpioa.moder, &mut gpioa.pupdr);
+
+        init::LateResources {
+            usb_device,
+            boot_keyboard,
+            keyboard,
+            raw_hid,
+            boot_button,
+            button,
+            timer,
+            remote_wakeup: RemoteWakeup::new(clocks.sysclk()),
+        }
+    }
+
+    #[idle]
+    fn idle(_ctx: idle::Context) -> ! {
+        loop {}
+    }
+
+    #[task(binds=USB_HP_CAN_TX, priority = 2, resources = [usb_device, boot_keyboard, keyboard, raw_hid])]
+    fn hp_handler(cx: hp_handler::Context) {
+        let r = cx.resources;
+        usb_poll(r.usb_device, r.boot_keyboard, r.keyboard, r.raw_hid);
+    }
+
+    #[task(binds=USB_LP_CAN_RX0, priority = 2, resources = [usb_device, boot_keyboard, keyboard, raw_hid])]
+    fn lp_handler(cx: lp_handler::Context) {
+        let r = cx.resources;
+        usb_poll(r.usb_device, r.boot_keyboard, r.keyboard, r.raw_hid);
+    }
+
+    #[task(binds=USB_LP, priority=2, resources=[usb_device, boot_keyboard, keyboard, raw_hid])]
+    fn usb_lp_handler(cx: usb_lp_handler::Context) {
+        let r = cx.resources;
+        usb_poll(r.usb_device, r.boot_keyboard, r.keyboard, r.raw_hid);
+    }
+
+    #[task(binds=TIM3, priority=1, resources=[timer, boot_button, button, boot_keyboard, keyboard, usb_device, remote_wakeup])]
+    fn tick(mut cx: tick::Context) {
+        // The key states the host last received on each interface
+        static mut SENT_BOOT: Option<bool> = None;
+        static mut SENT: Option<bool> = None;
+        static mut HELD: bool = false;
+
+        cx.resources.timer.clear_update_interrupt_flag();
+
+        // Type `a` on the boot keyboard and `b` on the other one
+        let boot_pressed = cx.resources.boot_button.is_low().unwrap();
+        cx.resources
+            .boot_keyboard
+            .lock(|k| send_key(k, 0x04, boot_pressed, SENT_BOOT));
+        let pressed = cx.resources.button.is_low().unwrap();
+        // A new press wakes a suspended host, the reports go out once it resumed the bus
+        if (boot_pressed || pressed) && !*HELD {
+            let remote_wakeup = &cx.resources.remote_wakeup;
+            cx.resources
+                .usb_device
+                .lock(|usb_device| remote_wakeup.wake_host(usb_device));
+        }
+        *HELD = boot_pressed || pressed;
+        cx.resources
+            .keyboard
+            .lock(|k| send_key(k, 0x05, pressed, SENT));
+    }
+};
+
+fn send_key<L: LedSink>(
+    keyboard: &mut KeyboardClass<L>,
+    keycode: u8,
+    pressed: bool,
+    sent: &mut Option<bool>,
+) {
+    if *sent != Some(pressed) {
+        if pressed {
+            keyboard.device_mut().press(keycode);
+        } else {
+            keyboard.device_mut().release(keycode);
+        }
+        match keyboard.write_device_report(0) {
+            Ok(_) => *sent = Some(pressed),
+            // The report queue is full, try again on the next tick
+            Err(HidError::Busy) => {}
+            Err(err) => defmt::error!("Couldn't send report: {:?}", err),
+        }
+    }
+    keyboard.tick();
+}
+
+fn usb_poll(
+    usb_device: &mut UsbDevice,
+    boot_keyboard: &mut KeyboardClass<BootLeds>,
+    keyboard: &mut KeyboardClass,
+    raw_hid: &mut RawClass,
+) {
+    if usb_device.poll(&mut [boot_keyboard, keyboard, raw_hid]) {
+        boot_keyboard.poll();
+        keyboard.poll();
+        raw_hid.poll();
+    }
+
+    if let Some(request) = raw_hid.device_mut().take_report() {
+        let mut handler = Diagnostics {
+            dropped_reports: [boot_keyboard.dropped_reports(), keyboard.dropped_reports()],
+        };
+        let mut response = [0; RAW_REPORT_SIZE as usize];
+        raw::process(&mut handler, &request, &mut response);
+        match raw_hid.write(&response) {
+            Ok(_) => raw_hid.device_mut().set_sent_report(&response),
+            Err(err) => defmt::error!("Couldn't send raw HID response: {:?}", err),
+        }
+    }
+}
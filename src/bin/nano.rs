@@ -8,14 +8,26 @@ use cortex_m::asm::delay;
 use embedded_hal::digital::v2::{InputPin, OutputPin};
 use generic_array::typenum::{U5, U6};
 // use keyberon::action::{k, Action::*};
-use keyberon::action::k;
+use keyberon::action::{d, k, Action, Action::Custom, Action::NoOp};
 use keyberon::debounce::Debouncer;
 use keyberon::impl_heterogenous_array;
-use keyberon::key_code::KbHidReport;
-use keyberon::key_code::KeyCode::{self, *};
-use keyberon::layout::Layout;
+use keyberon::key_code::KeyCode::*;
+use keyberon::layout::Event;
 use keyberon::matrix::{Matrix, PressedKeys};
 use my_app as _;
+use my_app::consumer::{usage, ConsumerControl};
+use my_app::flash::SettingsFlash;
+use my_app::gamepad::{Gamepad, GamepadKey};
+use my_app::hid::{HidClass, HidDevice, HidError};
+use my_app::keyboard::Keyboard;
+use my_app::keymap::{Combo, CustomAction, CustomEvent, Devices, Keymap, KeymapLayers};
+use my_app::leds::{CapsLockLed, LedPin, LedSink, LedState, Polarity};
+use my_app::mouse::{Mouse, MouseButton, MouseKey, MouseKeys, MouseKeysConfig};
+use my_app::power::{self, Port, Power, PowerEvent, SuspendMonitor};
+use my_app::raw::{RawHid, RawReportSize};
+use my_app::storage::{self, Saver};
+use my_app::system::{SystemControl, SystemKey};
+use my_app::via::{self, Via};
 use rtic::app;
 use stm32f3xx_hal::gpio::{gpiob, gpioc, Input, Output, PullUp, PushPull};
 use stm32f3xx_hal::prelude::*;
@@ -28,8 +40,26 @@ use usb_device::class::UsbClass as _;
 use usb_device::device::UsbDeviceBuilder;
 use usb_device::device::UsbVidPid;
 
-type UsbClass = keyberon::Class<'static, UsbBus<Peripheral>, Leds>;
+type KeyboardClass =
+    HidClass<'static, UsbBus<Peripheral>, Keyboard<CapsLockLed<gpioc::PC13<Output<PushPull>>>>>;
 type UsbDevice = usb_device::device::UsbDevice<'static, UsbBus<Peripheral>>;
+type ConsumerClass = HidClass<'static, UsbBus<Peripheral>, ConsumerControl>;
+type SystemClass = HidClass<'static, UsbBus<Peripheral>, SystemControl>;
+type MouseClass = HidClass<'static, UsbBus<Peripheral>, Mouse>;
+type GamepadClass = HidClass<'static, UsbBus<Peripheral>, Gamepad>;
+type RawClass = HidClass<'static, UsbBus<Peripheral>, RawHid>;
+
+const NUM_LAYERS: usize = 2;
+const ROWS: usize = 6;
+const COLS: usize = 5;
+type NanoKeymap = Keymap<NUM_LAYERS, ROWS, COLS>;
+
+/// Holding the top left and bottom right keys for 3 s restores the compiled keymap.
+const FACTORY_RESET_KEYS: [(u8, u8); 2] = [(0, 0), (5, 4)];
+const FACTORY_RESET_HOLD_MS: u16 = 3000;
+/// Keymap edits are saved once VIA has been quiet this long, erasing the page stalls the keys.
+const SAVE_DELAY_MS: u16 = 1000;
+const STORED_LEN: usize = storage::stored_len(NUM_LAYERS, ROWS, COLS);
 
 pub struct Cols(
     gpiob::PB0<Input<PullUp>>,
@@ -60,28 +90,55 @@ impl_heterogenous_array! {
     [0, 1, 2, 3, 4, 5]
 }
 
-pub static LAYERS: keyberon::layout::Layers<()> = &[&[
-    &[k(Kb1), k(Kb1), k(Kb1), k(Kb1), k(Kb1)],
-    &[k(Kb2), k(Kb2), k(Kb2), k(Kb2), k(Kb2)],
-    &[k(Kb3), k(Kb3), k(Kb3), k(Kb3), k(Kb3)],
-    &[k(Kb4), k(Kb4), k(Kb4), k(Kb4), k(Kb4)],
-    &[k(Kb5), k(Kb5), k(Kb5), k(Kb5), k(Kb5)],
-    &[k(Kb6), k(Kb6), k(Kb6), k(Kb6), k(Kb6)],
-]];
-
-pub struct Leds {
-    caps_lock: gpioc::PC13<Output<PushPull>>,
-}
-impl keyberon::keyboard::Leds for Leds {
-    fn caps_lock(&mut self, status: bool) {
-        if status {
-            self.caps_lock.set_low().unwrap()
-        } else {
-            self.caps_lock.set_high().unwrap()
-        }
-    }
+// Matrix pins on GPIOB, the rows are driven low while suspended so any key pulls its column low
+const COL_PINS: u16 = 0b1_1111;
+const ROW_PINS: u16 = 0b1111_1100_0000_0000;
+
+const VOL_DOWN: Action<CustomAction> = Custom(CustomAction::Consumer(usage::VOLUME_DECREMENT));
+const VOL_UP: Action<CustomAction> = Custom(CustomAction::Consumer(usage::VOLUME_INCREMENT));
+const SLEEP: Action<CustomAction> = Custom(CustomAction::System(SystemKey::Sleep));
+const MS_UP: Action<CustomAction> = Custom(CustomAction::Mouse(MouseKey::Up));
+const MS_DOWN: Action<CustomAction> = Custom(CustomAction::Mouse(MouseKey::Down));
+const MS_LEFT: Action<CustomAction> = Custom(CustomAction::Mouse(MouseKey::Left));
+const MS_RIGHT: Action<CustomAction> = Custom(CustomAction::Mouse(MouseKey::Right));
+const MS_WH_UP: Action<CustomAction> = Custom(CustomAction::Mouse(MouseKey::WheelUp));
+const MS_WH_DOWN: Action<CustomAction> = Custom(CustomAction::Mouse(MouseKey::WheelDown));
+const MS_BTN1: Action<CustomAction> =
+    Custom(CustomAction::Mouse(MouseKey::Button(MouseButton::Left)));
+const MS_BTN2: Action<CustomAction> =
+    Custom(CustomAction::Mouse(MouseKey::Button(MouseButton::Right)));
+
+const GP_UP: Action<CustomAction> = Custom(CustomAction::Gamepad(GamepadKey::Up));
+const GP_DOWN: Action<CustomAction> = Custom(CustomAction::Gamepad(GamepadKey::Down));
+const GP_LEFT: Action<CustomAction> = Custom(CustomAction::Gamepad(GamepadKey::Left));
+const GP_RIGHT: Action<CustomAction> = Custom(CustomAction::Gamepad(GamepadKey::Right));
+
+const fn gp(button: u8) -> Action<CustomAction> {
+    Custom(CustomAction::Gamepad(GamepadKey::Button(button)))
 }
 
+// Layer 0 is the keyboard, layer 1 an arcade stick: the direction keys drive the hat switch.
+// Its bottom row also has a key that puts the host to sleep.
+// These are the defaults of the keymap VIA edits in RAM.
+pub static LAYERS: keyberon::layout::Layers<CustomAction> = &[
+    &[
+        &[k(Kb1), k(Kb1), k(Kb1), k(Kb1), k(Kb1)],
+        &[k(Kb2), k(Kb2), k(Kb2), k(Kb2), k(Kb2)],
+        &[k(Kb3), k(Kb3), k(Kb3), MS_WH_DOWN, MS_WH_UP],
+        &[k(Kb4), k(Kb4), k(Kb4), MS_BTN1, MS_BTN2],
+        &[k(Kb5), MS_LEFT, MS_DOWN, MS_UP, MS_RIGHT],
+        &[d(1), k(Kb6), k(Kb6), VOL_DOWN, VOL_UP],
+    ],
+    &[
+        &[gp(8), gp(9), NoOp, NoOp, NoOp],
+        &[NoOp, GP_UP, NoOp, gp(0), gp(1)],
+        &[GP_LEFT, NoOp, GP_RIGHT, gp(2), gp(3)],
+        &[NoOp, GP_DOWN, NoOp, gp(4), gp(5)],
+        &[NoOp, NoOp, NoOp, gp(6), gp(7)],
+        &[d(0), NoOp, NoOp, SLEEP, NoOp],
+    ],
+];
+
 // We need to pass monotonic = rtic::cyccnt::CYCCNT to use schedule feature fo RTIC
 #[app(device = stm32f3xx_hal::pac, peripherals = true)]
 const APP: () = {
@@ -89,16 +146,32 @@ const APP: () = {
     // `LateResources` struct in init
     struct Resources {
         usb_device: UsbDevice,
-        usb_class: UsbClass,
+        keyboard: KeyboardClass,
+        consumer: ConsumerClass,
+        system: SystemClass,
+        mouse: MouseClass,
+        gamepad: GamepadClass,
+        raw_hid: RawClass,
+        via: Via,
+        keymap: NanoKeymap,
+        saver: Saver<SettingsFlash>,
+        #[init(MouseKeys::new(MouseKeysConfig::new()))]
+        mouse_keys: MouseKeys,
         matrix: Matrix<Cols, Rows>,
         debouncer: Debouncer<PressedKeys<U6, U5>>,
-        layout: Layout<()>,
         timer: Timer<stm32f3xx_hal::stm32::TIM3>,
+        power: Power,
+        exti: stm32f3xx_hal::stm32::EXTI,
+        #[init(SuspendMonitor::new())]
+        suspend_monitor: SuspendMonitor,
+        #[init(false)]
+        suspended: bool,
     }
 
     #[init]
     fn init(cx: init::Context) -> init::LateResources {
         static mut USB_BUS: Option<UsbBusAllocator<UsbBus<Peripheral>>> = None;
+        static mut KEYMAP_LAYERS: KeymapLayers<NUM_LAYERS, ROWS, COLS> = KeymapLayers::new();
 
         defmt::info!("hi");
 
@@ -152,18 +225,37 @@ const APP: () = {
             .expect("Couldn't make the USB_BUS a static reference");
 
         // Setup LED
-        let mut led = gpioc
+        let led = gpioc
             .pc13
             .into_push_pull_output(&mut gpioc.moder, &mut gpioc.otyper);
-        led.set_low().unwrap();
-        let leds = Leds { caps_lock: led };
+        let mut caps_lock = LedPin::new(led, Polarity::ActiveLow);
+        caps_lock.set(false).unwrap();
 
-        let usb_class = keyberon::new_class(usb_bus, leds);
+        // Interfaces are numbered in allocation order, and must be polled in that order
+        let keyboard = Keyboard::new()
+            .with_layer_count(NUM_LAYERS as u8)
+            .with_leds(CapsLockLed(caps_lock));
+        let debounce_ms = keyboard.settings().debounce_ms;
+        let keyboard = HidClass::new(keyboard, usb_bus);
+        let consumer = HidClass::new(ConsumerControl::new(), usb_bus);
+        let system = HidClass::new(SystemControl::new(), usb_bus);
+        let mouse = HidClass::builder(Mouse::new(), usb_bus)
+            .poll_interval(1)
+            .build();
+        let gamepad = HidClass::builder(Gamepad::new(), usb_bus)
+            .max_packet_size(16)
+            .poll_interval(1)
+            .build();
+        let raw_hid = HidClass::builder(RawHid::new(RawReportSize::Bytes32), usb_bus)
+            .max_packet_size(via::REPORT_SIZE as u16)
+            .interrupt_out(true)
+            .build();
         // let usb_device = keyberon::new_device(usb_bus);
         let usb_device = UsbDeviceBuilder::new(usb_bus, UsbVidPid(0x16c0, 0x27db))
             .manufacturer("ando")
             .product("nano")
             .serial_number(env!("CARGO_PKG_VERSION"))
+            .supports_remote_wakeup(true)
             .build();
 
         let mut timer = timer::Timer::tim3(device.TIM3, 1.khz(), clocks, &mut rcc.apb1);
@@ -209,57 +301,290 @@ const APP: () = {
             ),
         );
 
+        let mut power = Power::new(device.PWR, cx.core.SCB, &device.EXTI, clocks.sysclk());
+        power.wake_on_keys(&device.EXTI, &device.SYSCFG, Port::B, COL_PINS);
+
+        let settings = SettingsFlash::take().expect("The settings page is already taken");
+        let saver = Saver::new(settings, SAVE_DELAY_MS);
+        let mut keymap = Keymap::new(LAYERS, KEYMAP_LAYERS).with_devices(Devices::from_bits(
+            Devices::CONSUMER | Devices::SYSTEM | Devices::MOUSE | Devices::GAMEPAD,
+        ));
+        let mut via = Via::new();
+        match saver.load(&mut keymap, &mut via) {
+            Ok(()) => defmt::info!("loaded the saved keymap"),
+            Err(err) => defmt::info!("using the default keymap: {:?}", err),
+        }
+
         init::LateResources {
             usb_device,
-            usb_class,
+            keyboard,
+            consumer,
+            system,
+            mouse,
+            gamepad,
+            raw_hid,
+            via,
+            keymap,
+            saver,
             timer,
-            debouncer: Debouncer::new(PressedKeys::default(), PressedKeys::default(), 5),
+            power,
+            exti: device.EXTI,
+            debouncer: debouncer(&[[false; COLS]; ROWS], debounce_ms),
             matrix: matrix.unwrap(),
-            layout: Layout::new(LAYERS),
         }
     }
 
-    #[idle]
-    fn idle(_ctx: idle::Context) -> ! {
-        loop {}
+    #[idle(resources = [power, suspended, usb_device])]
+    fn idle(cx: idle::Context) -> ! {
+        let idle::Resources {
+            power,
+            mut suspended,
+            mut usb_device,
+        } = cx.resources;
+        loop {
+            // With interrupts off a resume can't slip in between the check and going to sleep
+            cortex_m::interrupt::free(|_| {
+                if suspended.lock(|suspended| *suspended) {
+                    power.stop();
+                    // Woken by a key rather than the host
+                    if power.keys_pressed() {
+                        usb_device.lock(|usb_device| power.wake_host(usb_device));
+                    }
+                }
+            });
+        }
     }
 
-    #[task(binds=USB_HP_CAN_TX, priority = 2, resources = [usb_device, usb_class])]
+    #[task(binds=USB_HP_CAN_TX, priority = 2, resources = [usb_device, keyboard, consumer, system, mouse, gamepad, raw_hid, via, keymap, saver, suspend_monitor], spawn = [power_state])]
     fn hp_handler(mut cx: hp_handler::Context) {
         // defmt::info!("hp handler");
-        usb_poll(&mut cx.resources.usb_device, &mut cx.resources.usb_class);
+        if let Some(event) = usb_poll(
+            &mut cx.resources.usb_device,
+            &mut cx.resources.keyboard,
+            &mut cx.resources.consumer,
+            &mut cx.resources.system,
+            &mut cx.resources.mouse,
+            &mut cx.resources.gamepad,
+            &mut cx.resources.raw_hid,
+            &mut cx.resources.via,
+            &mut cx.resources.keymap,
+            &mut cx.resources.saver,
+            &mut cx.resources.suspend_monitor,
+        ) {
+            cx.spawn.power_state(event).unwrap();
+        }
     }
 
-    #[task(binds=USB_LP_CAN_RX0, priority = 2, resources = [usb_device, usb_class])]
+    #[task(binds=USB_LP_CAN_RX0, priority = 2, resources = [usb_device, keyboard, consumer, system, mouse, gamepad, raw_hid, via, keymap, saver, suspend_monitor], spawn = [power_state])]
     fn lp_handler(mut cx: lp_handler::Context) {
         // defmt::info!("lp handler");
-        usb_poll(&mut cx.resources.usb_device, &mut cx.resources.usb_class);
+        if let Some(event) = usb_poll(
+            &mut cx.resources.usb_device,
+            &mut cx.resources.keyboard,
+            &mut cx.resources.consumer,
+            &mut cx.resources.system,
+            &mut cx.resources.mouse,
+            &mut cx.resources.gamepad,
+            &mut cx.resources.raw_hid,
+            &mut cx.resources.via,
+            &mut cx.resources.keymap,
+            &mut cx.resources.saver,
+            &mut cx.resources.suspend_monitor,
+        ) {
+            cx.spawn.power_state(event).unwrap();
+        }
     }
 
-    #[task(binds=USB_LP, priority=2, resources=[usb_device, usb_class])]
+    #[task(binds=USB_LP, priority=2, resources=[usb_device, keyboard, consumer, system, mouse, gamepad, raw_hid, via, keymap, saver, suspend_monitor], spawn=[power_state])]
     fn usb_lp_handler(mut cx: usb_lp_handler::Context) {
         // defmt::info!("usb lp handler");
-        usb_poll(&mut cx.resources.usb_device, &mut cx.resources.usb_class);
+        if let Some(event) = usb_poll(
+            &mut cx.resources.usb_device,
+            &mut cx.resources.keyboard,
+            &mut cx.resources.consumer,
+            &mut cx.resources.system,
+            &mut cx.resources.mouse,
+            &mut cx.resources.gamepad,
+            &mut cx.resources.raw_hid,
+            &mut cx.resources.via,
+            &mut cx.resources.keymap,
+            &mut cx.resources.saver,
+            &mut cx.resources.suspend_monitor,
+        ) {
+            cx.spawn.power_state(event).unwrap();
+        }
+    }
+
+    #[task(binds=USB_WKUP, priority=2, resources=[exti, usb_device, keyboard, consumer, system, mouse, gamepad, raw_hid, via, keymap, saver, suspend_monitor], spawn=[power_state])]
+    fn usb_wakeup(mut cx: usb_wakeup::Context) {
+        power::clear_usb_wakeup(cx.resources.exti);
+        if let Some(event) = usb_poll(
+            &mut cx.resources.usb_device,
+            &mut cx.resources.keyboard,
+            &mut cx.resources.consumer,
+            &mut cx.resources.system,
+            &mut cx.resources.mouse,
+            &mut cx.resources.gamepad,
+            &mut cx.resources.raw_hid,
+            &mut cx.resources.via,
+            &mut cx.resources.keymap,
+            &mut cx.resources.saver,
+            &mut cx.resources.suspend_monitor,
+        ) {
+            cx.spawn.power_state(event).unwrap();
+        }
+    }
+
+    // Suspend and resume may both be pending before this gets to run
+    #[task(capacity = 2, resources = [timer, keyboard, suspended])]
+    fn power_state(mut cx: power_state::Context, event: PowerEvent) {
+        defmt::info!("usb {:?}", event);
+        match event {
+            PowerEvent::Suspend => {
+                // Stop scanning the matrix and turn the LEDs off, idle then enters STOP mode
+                cx.resources.timer.unlisten(timer::Event::Update);
+                cx.resources
+                    .keyboard
+                    .lock(|k| k.device_mut().leds_mut().set_leds(LedState::default()));
+                power::drive_pins(Port::B, ROW_PINS, false);
+                *cx.resources.suspended = true;
+            }
+            PowerEvent::Resume => {
+                *cx.resources.suspended = false;
+                // Leave the rows idle for the scanner
+                power::drive_pins(Port::B, ROW_PINS, true);
+                cx.resources.timer.listen(timer::Event::Update);
+            }
+        }
     }
 
-    #[task(binds=TIM3, priority=1, resources=[timer, usb_class, matrix, debouncer, layout])]
+    #[task(binds=TIM3, priority=1, resources=[timer, keyboard, consumer, system, mouse, mouse_keys, gamepad, matrix, debouncer, via, keymap, saver])]
     fn tick(mut cx: tick::Context) {
+        // Whether the keyboard report changed since the host last received it
+        static mut KEYBOARD_CHANGED: bool = false;
+        static mut FACTORY_RESET: Combo<2> = Combo::new(FACTORY_RESET_KEYS, FACTORY_RESET_HOLD_MS);
+        // The debounced matrix, a new debouncer starts from it
+        static mut HELD: [[bool; COLS]; ROWS] = [[false; COLS]; ROWS];
+
         cx.resources.timer.clear_update_interrupt_flag();
 
+        // Apply what the host wrote to the settings feature report
+        let settings = cx.resources.keyboard.lock(|k| {
+            let keyboard = k.device_mut();
+            Some(keyboard.settings()).filter(|_| keyboard.take_settings_changed())
+        });
+        if let Some(settings) = settings {
+            *cx.resources.debouncer = debouncer(HELD, settings.debounce_ms);
+            cx.resources
+                .keymap
+                .lock(|k| k.set_default_layer(usize::from(settings.layer)));
+        }
+
         for event in cx
             .resources
             .debouncer
             .events(cx.resources.matrix.get().unwrap())
         {
-            // use keyberon::layout::Event;
-            defmt::info!("got an event");
-            cx.resources.layout.event(event);
+            match event {
+                Event::Press(row, col) => HELD[usize::from(row)][usize::from(col)] = true,
+                Event::Release(row, col) => HELD[usize::from(row)][usize::from(col)] = false,
+            }
+            FACTORY_RESET.event(event);
+            cx.resources.keymap.lock(|k| k.event(event));
+        }
+        let (custom, keys) = cx.resources.keymap.lock(|k| (k.tick(), k.pressed_keys()));
+        let consumer_changed = match custom {
+            CustomEvent::Press(CustomAction::Consumer(usage)) => {
+                cx.resources.consumer.lock(|c| c.device_mut().press(usage))
+            }
+            CustomEvent::Release(CustomAction::Consumer(usage)) => cx
+                .resources
+                .consumer
+                .lock(|c| c.device_mut().release(usage)),
+            CustomEvent::Press(CustomAction::System(key)) => {
+                cx.resources.system.lock(|s| s.device_mut().press(key));
+                false
+            }
+            CustomEvent::Release(CustomAction::System(key)) => {
+                cx.resources.system.lock(|s| s.device_mut().release(key));
+                false
+            }
+            CustomEvent::Press(CustomAction::Mouse(key)) => {
+                cx.resources.mouse_keys.press(key);
+                false
+            }
+            CustomEvent::Release(CustomAction::Mouse(key)) => {
+                cx.resources.mouse_keys.release(key);
+                false
+            }
+            CustomEvent::Press(CustomAction::Gamepad(key)) => {
+                cx.resources.gamepad.lock(|g| g.device_mut().press(key));
+                false
+            }
+            CustomEvent::Release(CustomAction::Gamepad(key)) => {
+                cx.resources.gamepad.lock(|g| g.device_mut().release(key));
+                false
+            }
+            CustomEvent::Release(CustomAction::Reset) => cortex_m::peripheral::SCB::sys_reset(),
+            _ => false,
+        };
+        *KEYBOARD_CHANGED |= cx
+            .resources
+            .keyboard
+            .lock(|k| k.device_mut().update_keys(0, &keys));
+        cx.resources.keyboard.lock(|k| {
+            if *KEYBOARD_CHANGED {
+                match k.write_device_report(0) {
+                    Ok(_) => *KEYBOARD_CHANGED = false,
+                    // The report queue is full, try again on the next tick
+                    Err(HidError::Busy) => {}
+                    Err(err) => defmt::error!("Couldn't send report: {:?}", err),
+                }
+            }
+            k.tick();
+        });
+        cx.resources.consumer.lock(|c| {
+            if consumer_changed {
+                write_report(c);
+            }
+            c.tick();
+        });
+
+        cx.resources.system.lock(|s| {
+            // Unchanged reports are coalesced
+            write_report(s);
+            s.tick();
+        });
+
+        let motion = cx.resources.mouse_keys.tick();
+        let buttons = cx.resources.mouse_keys.buttons();
+        cx.resources.mouse.lock(|m| {
+            if m.device_mut().update(buttons, motion) {
+                write_report(m);
+            }
+            m.tick();
+        });
+
+        cx.resources.gamepad.lock(|g| {
+            // Unchanged reports are coalesced
+            write_report(g);
+            g.tick();
+        });
+
+        if FACTORY_RESET.tick() {
+            let (via, saver) = (&mut cx.resources.via, &mut cx.resources.saver);
+            cx.resources
+                .keymap
+                .lock(|k| via.lock(|v| saver.lock(|s| s.factory_reset(k, v))));
         }
-        match cx.resources.layout.tick() {
-            keyberon::layout::CustomEvent::Release(()) => cortex_m::peripheral::SCB::sys_reset(),
-            _ => (),
+
+        if cx.resources.saver.lock(|s| s.tick()) {
+            let mut data = [0; STORED_LEN];
+            let (via, saver) = (&mut cx.resources.via, &mut cx.resources.saver);
+            cx.resources
+                .keymap
+                .lock(|k| via.lock(|v| saver.lock(|s| s.save(k, v, &mut data))));
         }
-        send_report(cx.resources.layout.keycodes(), &mut cx.resources.usb_class);
     }
 
     extern "C" {
@@ -267,16 +592,52 @@ const APP: () = {
     }
 };
 
-fn send_report(iter: impl Iterator<Item = KeyCode>, usb_class: &mut resources::usb_class<'_>) {
-    use rtic::Mutex;
-    let report: KbHidReport = iter.collect();
-    if usb_class.lock(|k| k.device_mut().set_keyboard_report(report.clone())) {
-        while let Ok(0) = usb_class.lock(|k| k.write(report.as_bytes())) {}
+/// Returns a debouncer that takes `held` as the current matrix state.
+fn debouncer(held: &[[bool; COLS]; ROWS], debounce_ms: u8) -> Debouncer<PressedKeys<U6, U5>> {
+    let pressed = || {
+        let mut keys = PressedKeys::<U6, U5>::default();
+        for (keys, held) in keys.0.iter_mut().zip(held.iter()) {
+            keys.copy_from_slice(held);
+        }
+        keys
+    };
+    Debouncer::new(pressed(), pressed(), u16::from(debounce_ms))
+}
+
+/// Queues the current input report of a device, logging reports that don't fit in the queue.
+fn write_report<D: HidDevice>(class: &mut HidClass<'static, UsbBus<Peripheral>, D>) {
+    match class.write_device_report(0) {
+        Ok(_) => {}
+        Err(HidError::Busy) => defmt::warn!("Dropped a report"),
+        Err(err) => defmt::error!("Couldn't send report: {:?}", err),
     }
 }
 
-fn usb_poll(usb_device: &mut UsbDevice, keyboard: &mut UsbClass) {
-    if usb_device.poll(&mut [keyboard]) {
+#[allow(clippy::too_many_arguments)]
+fn usb_poll(
+    usb_device: &mut UsbDevice,
+    keyboard: &mut KeyboardClass,
+    consumer: &mut ConsumerClass,
+    system: &mut SystemClass,
+    mouse: &mut MouseClass,
+    gamepad: &mut GamepadClass,
+    raw_hid: &mut RawClass,
+    via: &mut Via,
+    keymap: &mut NanoKeymap,
+    saver: &mut Saver<SettingsFlash>,
+    suspend_monitor: &mut SuspendMonitor,
+) -> Option<PowerEvent> {
+    if usb_device.poll(&mut [keyboard, consumer, system, mouse, gamepad, raw_hid]) {
         keyboard.poll();
+        consumer.poll();
+        system.poll();
+        mouse.poll();
+        gamepad.poll();
+        raw_hid.poll();
+    }
+    if via.answer(raw_hid, keymap) {
+        // Saved from the tick once the edits settle
+        saver.changed();
     }
+    suspend_monitor.update(usb_device)
 }
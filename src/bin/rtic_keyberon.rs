@@ -11,11 +11,19 @@ use generic_array::typenum::{U1, U2};
 use keyberon::action::k;
 use keyberon::debounce::Debouncer;
 use keyberon::impl_heterogenous_array;
-use keyberon::key_code::KbHidReport;
-use keyberon::key_code::KeyCode::{self, *};
-use keyberon::layout::Layout;
+use keyberon::key_code::KeyCode::*;
+use keyberon::layout::Event;
 use keyberon::matrix::{Matrix, PressedKeys};
 use my_app as _;
+use my_app::flash::SettingsFlash;
+use my_app::hid::{HidClass, HidError};
+use my_app::keyboard::Keyboard;
+use my_app::keymap::{Combo, CustomAction, CustomEvent, Keymap, KeymapLayers};
+use my_app::leds::{CapsLockLed, LedPin, Polarity};
+use my_app::power::RemoteWakeup;
+use my_app::raw::{RawHid, RawReportSize};
+use my_app::storage::{self, Saver};
+use my_app::via::{self, Via};
 use rtic::app;
 use stm32f3xx_hal::gpio::{gpioa, gpioc, Input, Output, PullUp, PushPull};
 use stm32f3xx_hal::prelude::*;
@@ -28,8 +36,22 @@ use usb_device::class::UsbClass as _;
 use usb_device::device::UsbDeviceBuilder;
 use usb_device::device::UsbVidPid;
 
-type UsbClass = keyberon::Class<'static, UsbBus<Peripheral>, Leds>;
+type KeyboardClass =
+    HidClass<'static, UsbBus<Peripheral>, Keyboard<CapsLockLed<gpioc::PC13<Output<PushPull>>>>>;
 type UsbDevice = usb_device::device::UsbDevice<'static, UsbBus<Peripheral>>;
+type RawClass = HidClass<'static, UsbBus<Peripheral>, RawHid>;
+
+const NUM_LAYERS: usize = 1;
+const ROWS: usize = 1;
+const COLS: usize = 2;
+type BoardKeymap = Keymap<NUM_LAYERS, ROWS, COLS>;
+
+/// Holding both keys for 3 s restores the compiled keymap.
+const FACTORY_RESET_KEYS: [(u8, u8); 2] = [(0, 0), (0, 1)];
+const FACTORY_RESET_HOLD_MS: u16 = 3000;
+/// Keymap edits are saved once VIA has been quiet this long.
+const SAVE_DELAY_MS: u16 = 1000;
+const STORED_LEN: usize = storage::stored_len(NUM_LAYERS, ROWS, COLS);
 
 pub struct Cols(gpioa::PA6<Input<PullUp>>, gpioa::PA7<Input<PullUp>>);
 impl_heterogenous_array! {
@@ -47,20 +69,8 @@ impl_heterogenous_array! {
     [0]
 }
 
-pub static LAYERS: keyberon::layout::Layers<()> = &[&[&[k(CapsLock), k(A)]]];
-
-pub struct Leds {
-    caps_lock: gpioc::PC13<Output<PushPull>>,
-}
-impl keyberon::keyboard::Leds for Leds {
-    fn caps_lock(&mut self, status: bool) {
-        if status {
-            self.caps_lock.set_low().unwrap()
-        } else {
-            self.caps_lock.set_high().unwrap()
-        }
-    }
-}
+// The defaults of the keymap VIA edits in RAM
+pub static LAYERS: keyberon::layout::Layers<CustomAction> = &[&[&[k(CapsLock), k(A)]]];
 
 // We need to pass monotonic = rtic::cyccnt::CYCCNT to use schedule feature fo RTIC
 #[app(device = stm32f3xx_hal::pac, peripherals = true)]
@@ -69,16 +79,21 @@ const APP: () = {
     // `LateResources` struct in init
     struct Resources {
         usb_device: UsbDevice,
-        usb_class: UsbClass,
+        keyboard: KeyboardClass,
+        raw_hid: RawClass,
+        via: Via,
+        keymap: BoardKeymap,
+        saver: Saver<SettingsFlash>,
         matrix: Matrix<Cols, Rows>,
         debouncer: Debouncer<PressedKeys<U1, U2>>,
-        layout: Layout<()>,
         timer: Timer<stm32f3xx_hal::stm32::TIM3>,
+        remote_wakeup: RemoteWakeup,
     }
 
     #[init]
     fn init(cx: init::Context) -> init::LateResources {
         static mut USB_BUS: Option<UsbBusAllocator<UsbBus<Peripheral>>> = None;
+        static mut KEYMAP_LAYERS: KeymapLayers<NUM_LAYERS, ROWS, COLS> = KeymapLayers::new();
 
         defmt::info!("hi");
 
@@ -131,18 +146,25 @@ const APP: () = {
             .expect("Couldn't make the USB_BUS a static reference");
 
         // Setup LED
-        let mut led = gpioc
+        let led = gpioc
             .pc13
             .into_push_pull_output(&mut gpioc.moder, &mut gpioc.otyper);
-        led.set_low().unwrap();
-        let leds = Leds { caps_lock: led };
+        let mut caps_lock = LedPin::new(led, Polarity::ActiveLow);
+        caps_lock.set(false).unwrap();
 
-        let usb_class = keyberon::new_class(usb_bus, leds);
+        // Interfaces are numbered in allocation order, and must be polled in that order
+        let keyboard = Keyboard::new().with_leds(CapsLockLed(caps_lock));
+        let keyboard = HidClass::new(keyboard, usb_bus);
+        let raw_hid = HidClass::builder(RawHid::new(RawReportSize::Bytes32), usb_bus)
+            .max_packet_size(via::REPORT_SIZE as u16)
+            .interrupt_out(true)
+            .build();
         // let usb_device = keyberon::new_device(usb_bus);
         let usb_device = UsbDeviceBuilder::new(usb_bus, UsbVidPid(0x16c0, 0x27db))
             .manufacturer("ando")
             .product("nano")
             .serial_number(env!("CARGO_PKG_VERSION"))
+            .supports_remote_wakeup(true)
             .build();
 
         let mut timer = timer::Timer::tim3(device.TIM3, 1.khz(), clocks, &mut rcc.apb1);
@@ -164,13 +186,26 @@ const APP: () = {
             ),
         );
 
+        let settings = SettingsFlash::take().expect("The settings page is already taken");
+        let saver = Saver::new(settings, SAVE_DELAY_MS);
+        let mut keymap = Keymap::new(LAYERS, KEYMAP_LAYERS);
+        let mut via = Via::new();
+        match saver.load(&mut keymap, &mut via) {
+            Ok(()) => defmt::info!("loaded the saved keymap"),
+            Err(err) => defmt::info!("using the default keymap: {:?}", err),
+        }
+
         init::LateResources {
             usb_device,
-            usb_class,
+            keyboard,
+            raw_hid,
+            via,
+            keymap,
+            saver,
             timer,
+            remote_wakeup: RemoteWakeup::new(clocks.sysclk()),
             debouncer: Debouncer::new(PressedKeys::default(), PressedKeys::default(), 5),
             matrix: matrix.unwrap(),
-            layout: Layout::new(LAYERS),
         }
     }
 
@@ -179,26 +214,51 @@ const APP: () = {
         loop {}
     }
 
-    #[task(binds=USB_HP_CAN_TX, priority = 2, resources = [usb_device, usb_class])]
+    #[task(binds=USB_HP_CAN_TX, priority = 2, resources = [usb_device, keyboard, raw_hid, via, keymap, saver])]
     fn hp_handler(mut cx: hp_handler::Context) {
         // defmt::info!("hp handler");
-        usb_poll(&mut cx.resources.usb_device, &mut cx.resources.usb_class);
+        usb_poll(
+            &mut cx.resources.usb_device,
+            &mut cx.resources.keyboard,
+            &mut cx.resources.raw_hid,
+            &mut cx.resources.via,
+            &mut cx.resources.keymap,
+            &mut cx.resources.saver,
+        );
     }
 
-    #[task(binds=USB_LP_CAN_RX0, priority = 2, resources = [usb_device, usb_class])]
+    #[task(binds=USB_LP_CAN_RX0, priority = 2, resources = [usb_device, keyboard, raw_hid, via, keymap, saver])]
     fn lp_handler(mut cx: lp_handler::Context) {
         // defmt::info!("lp handler");
-        usb_poll(&mut cx.resources.usb_device, &mut cx.resources.usb_class);
+        usb_poll(
+            &mut cx.resources.usb_device,
+            &mut cx.resources.keyboard,
+            &mut cx.resources.raw_hid,
+            &mut cx.resources.via,
+            &mut cx.resources.keymap,
+            &mut cx.resources.saver,
+        );
     }
 
-    #[task(binds=USB_LP, priority=2, resources=[usb_device, usb_class])]
+    #[task(binds=USB_LP, priority=2, resources=[usb_device, keyboard, raw_hid, via, keymap, saver])]
     fn usb_lp_handler(mut cx: usb_lp_handler::Context) {
         // defmt::info!("usb lp handler");
-        usb_poll(&mut cx.resources.usb_device, &mut cx.resources.usb_class);
+        usb_poll(
+            &mut cx.resources.usb_device,
+            &mut cx.resources.keyboard,
+            &mut cx.resources.raw_hid,
+            &mut cx.resources.via,
+            &mut cx.resources.keymap,
+            &mut cx.resources.saver,
+        );
     }
 
-    #[task(binds=TIM3, priority=1, resources=[timer, usb_class, matrix, debouncer, layout])]
+    #[task(binds=TIM3, priority=1, resources=[timer, keyboard, matrix, debouncer, via, keymap, saver, usb_device, remote_wakeup])]
     fn tick(mut cx: tick::Context) {
+        // Whether the keyboard report changed since the host last received it
+        static mut KEYBOARD_CHANGED: bool = false;
+        static mut FACTORY_RESET: Combo<2> = Combo::new(FACTORY_RESET_KEYS, FACTORY_RESET_HOLD_MS);
+
         cx.resources.timer.clear_update_interrupt_flag();
 
         for event in cx
@@ -206,15 +266,50 @@ const APP: () = {
             .debouncer
             .events(cx.resources.matrix.get().unwrap())
         {
-            // use keyberon::layout::Event;
-            defmt::info!("got an event");
-            cx.resources.layout.event(event);
+            if let Event::Press(..) = event {
+                // The report goes out once the host resumed the bus
+                let remote_wakeup = &cx.resources.remote_wakeup;
+                cx.resources
+                    .usb_device
+                    .lock(|usb_device| remote_wakeup.wake_host(usb_device));
+            }
+            FACTORY_RESET.event(event);
+            cx.resources.keymap.lock(|k| k.event(event));
+        }
+        let (custom, keys) = cx.resources.keymap.lock(|k| (k.tick(), k.pressed_keys()));
+        if let CustomEvent::Release(CustomAction::Reset) = custom {
+            cortex_m::peripheral::SCB::sys_reset();
         }
-        match cx.resources.layout.tick() {
-            keyberon::layout::CustomEvent::Release(()) => cortex_m::peripheral::SCB::sys_reset(),
-            _ => (),
+        *KEYBOARD_CHANGED |= cx
+            .resources
+            .keyboard
+            .lock(|k| k.device_mut().update_keys(0, &keys));
+        cx.resources.keyboard.lock(|k| {
+            if *KEYBOARD_CHANGED {
+                match k.write_device_report(0) {
+                    Ok(_) => *KEYBOARD_CHANGED = false,
+                    // The report queue is full, try again on the next tick
+                    Err(HidError::Busy) => {}
+                    Err(err) => defmt::error!("Couldn't send report: {:?}", err),
+                }
+            }
+            k.tick();
+        });
+
+        if FACTORY_RESET.tick() {
+            let (via, saver) = (&mut cx.resources.via, &mut cx.resources.saver);
+            cx.resources
+                .keymap
+                .lock(|k| via.lock(|v| saver.lock(|s| s.factory_reset(k, v))));
+        }
+
+        if cx.resources.saver.lock(|s| s.tick()) {
+            let mut data = [0; STORED_LEN];
+            let (via, saver) = (&mut cx.resources.via, &mut cx.resources.saver);
+            cx.resources
+                .keymap
+                .lock(|k| via.lock(|v| saver.lock(|s| s.save(k, v, &mut data))));
         }
-        send_report(cx.resources.layout.keycodes(), &mut cx.resources.usb_class);
     }
 
     extern "C" {
@@ -222,16 +317,20 @@ const APP: () = {
     }
 };
 
-fn send_report(iter: impl Iterator<Item = KeyCode>, usb_class: &mut resources::usb_class<'_>) {
-    use rtic::Mutex;
-    let report: KbHidReport = iter.collect();
-    if usb_class.lock(|k| k.device_mut().set_keyboard_report(report.clone())) {
-        while let Ok(0) = usb_class.lock(|k| k.write(report.as_bytes())) {}
-    }
-}
-
-fn usb_poll(usb_device: &mut UsbDevice, keyboard: &mut UsbClass) {
-    if usb_device.poll(&mut [keyboard]) {
+fn usb_poll(
+    usb_device: &mut UsbDevice,
+    keyboard: &mut KeyboardClass,
+    raw_hid: &mut RawClass,
+    via: &mut Via,
+    keymap: &mut BoardKeymap,
+    saver: &mut Saver<SettingsFlash>,
+) {
+    if usb_device.poll(&mut [keyboard, raw_hid]) {
         keyboard.poll();
+        raw_hid.poll();
+    }
+    if via.answer(raw_hid, keymap) {
+        // Saved from the tick once the edits settle
+        saver.changed();
     }
 }
@@ -5,8 +5,10 @@
 
 use cortex_m::asm::delay;
 use my_app as _;
+use my_app::hid::HidError;
 
 use embedded_hal::digital::v2::OutputPin;
+use my_app::power::RemoteWakeup;
 use rtic::app;
 use rtic::cyccnt::U32Ext;
 use stm32f3xx_hal::gpio::{gpioa, gpioc, Input, Output, PullUp, PushPull};
@@ -23,6 +25,9 @@ use usb_device::device::UsbVidPid;
 
 const PERIOD: u32 = 10_000_000;
 
+// Keycode of the `a` key
+const KEY_A: u8 = 0x04;
+
 // Generic keyboard from
 // https://github.com/obdev/v-usb/blob/master/usbdrv/USB-IDs-for-free.txt
 const PID: u16 = 0x27db;
@@ -40,6 +45,7 @@ const APP: () = {
         // output: gpioa::PA5<Output<PushPull>>,
         led: gpioc::PC13<Output<PushPull>>,
         timer: Timer<stm32f3xx_hal::stm32::TIM3>,
+        remote_wakeup: RemoteWakeup,
     }
 
     #[init(schedule = [blinker])]
@@ -102,6 +108,7 @@ const APP: () = {
             .manufacturer("ando")
             .product("nano")
             .serial_number(env!("CARGO_PKG_VERSION"))
+            .supports_remote_wakeup(true)
             // .device_class(3) // Not having this will make the thing qwork?
             .build();
 
@@ -132,6 +139,7 @@ const APP: () = {
             button,
             // output,
             timer,
+            remote_wakeup: RemoteWakeup::new(clocks.sysclk()),
         }
     }
 
@@ -178,8 +186,12 @@ const APP: () = {
         cx.schedule.blinker(cx.scheduled + PERIOD.cycles()).unwrap();
     }
 
-    #[task(binds=TIM3, priority=1, resources=[timer, button, usb_class])]
+    #[task(binds=TIM3, priority=1, resources=[timer, button, usb_class, usb_device, remote_wakeup])]
     fn tick(mut cx: tick::Context) {
+        static mut HELD: bool = false;
+        // The key state the host last received
+        static mut SENT: Option<bool> = None;
+
         cx.resources.timer.clear_update_interrupt_flag();
 
         let key_pressed = cx
@@ -187,19 +199,32 @@ const APP: () = {
             .button
             .is_low()
             .expect("Couldn't poll pressed keys!");
-        cx.resources
-            .usb_class
-            .lock(|k| {
-                // defmt::info!("writing from tick");
+        // A new press wakes a suspended host, the report goes out once it resumed the bus
+        if key_pressed && !*HELD {
+            let remote_wakeup = &cx.resources.remote_wakeup;
+            cx.resources
+                .usb_device
+                .lock(|usb_device| remote_wakeup.wake_host(usb_device));
+        }
+        *HELD = key_pressed;
+        cx.resources.usb_class.lock(|k| {
+            if *SENT != Some(key_pressed) {
                 // Type the character `a`
                 if key_pressed {
-                    k.write(&[0, 0, 4, 0, 0, 0, 0, 0])
+                    k.device_mut().press(KEY_A);
                 } else {
-                    // k.write(&[0, 0, 0, 0, 0, 0, 0, 0])
-                    Ok(0)
+                    k.device_mut().release(KEY_A);
                 }
-            })
-            .expect("Couldn't get access to USB_CLASS!");
+                match k.write_device_report(0) {
+                    Ok(_) => *SENT = Some(key_pressed),
+                    // The report queue is full, try again on the next tick
+                    Err(HidError::Busy) => {}
+                    Err(err) => defmt::error!("Couldn't send report: {:?}", err),
+                }
+            }
+            // Repeats the last report once the idle duration set by the host expires
+            k.tick();
+        });
     }
 
     extern "C" {
@@ -7,6 +7,7 @@ use cortex_m::asm::delay;
 use my_app as _;
 
 use embedded_hal::digital::v2::OutputPin;
+use my_app::power::RemoteWakeup;
 use rtic::app;
 use rtic::cyccnt::U32Ext;
 use stm32f3xx_hal::gpio::{gpioa, gpioc, Input, Output, PullUp, PushPull};
@@ -43,6 +44,7 @@ const APP: () = {
         // output: gpioa::PA5<Output<PushPull>>,
         led: gpioc::PC13<Output<PushPull>>,
         timer: Timer<stm32f3xx_hal::stm32::TIM3>,
+        remote_wakeup: RemoteWakeup,
     }
 
     #[init(schedule = [blinker])]
@@ -105,6 +107,7 @@ const APP: () = {
             .manufacturer("ando")
             .product("nano")
             .serial_number(env!("CARGO_PKG_VERSION"))
+            .supports_remote_wakeup(true)
             // .device_class(3) // Not having this will make the thing qwork?
             .build();
 
@@ -135,6 +138,7 @@ const APP: () = {
             button,
             // output,
             timer,
+            remote_wakeup: RemoteWakeup::new(clocks.sysclk()),
         }
     }
 
@@ -181,8 +185,10 @@ const APP: () = {
         cx.schedule.blinker(cx.scheduled + PERIOD.cycles()).unwrap();
     }
 
-    #[task(binds=TIM3, priority=1, resources=[timer, button, usb_class])]
+    #[task(binds=TIM3, priority=1, resources=[timer, button, usb_class, usb_device, remote_wakeup])]
     fn tick(mut cx: tick::Context) {
+        static mut HELD: bool = false;
+
         cx.resources.timer.clear_update_interrupt_flag();
 
         let key_pressed = cx
@@ -190,6 +196,14 @@ const APP: () = {
             .button
             .is_low()
             .expect("Couldn't poll pressed keys!");
+        // A new press wakes a suspended host, the report goes out once it resumed the bus
+        if key_pressed && !*HELD {
+            let remote_wakeup = &cx.resources.remote_wakeup;
+            cx.resources
+                .usb_device
+                .lock(|usb_device| remote_wakeup.wake_host(usb_device));
+        }
+        *HELD = key_pressed;
         cx.resources
             .usb_class
             .lock(|hid| {
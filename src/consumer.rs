@@ -0,0 +1,281 @@
+//! Consumer control, the media and launcher keys of a keyboard.
+//!
+//! The input report is an array of up to `MAX_PRESSED` usages of the Consumer page, 16 bits
+//! each, so any usage of the page can be sent, see `usage` for the common ones. The device
+//! either gets its own interface, or declares a report ID so it can share one, as with
+//! `Keyboard::with_consumer`.
+
+use crate::descriptor::{Collection, DescriptorBuilder, ItemFlags, SharedDescriptor, UsagePage};
+use crate::hid::{HidDevice, HidError, Protocol, ReportType, Subclass};
+
+/// Usages of the Consumer page, see chapter 15 of the HID Usage Tables.
+pub mod usage {
+    pub const CONSUMER_CONTROL: u16 = 0x01;
+
+    pub const BRIGHTNESS_INCREMENT: u16 = 0x6f;
+    pub const BRIGHTNESS_DECREMENT: u16 = 0x70;
+
+    pub const FAST_FORWARD: u16 = 0xb3;
+    pub const REWIND: u16 = 0xb4;
+    pub const SCAN_NEXT_TRACK: u16 = 0xb5;
+    pub const SCAN_PREVIOUS_TRACK: u16 = 0xb6;
+    pub const STOP: u16 = 0xb7;
+    pub const EJECT: u16 = 0xb8;
+    pub const PLAY_PAUSE: u16 = 0xcd;
+    pub const MUTE: u16 = 0xe2;
+    pub const VOLUME_INCREMENT: u16 = 0xe9;
+    pub const VOLUME_DECREMENT: u16 = 0xea;
+
+    // Application launchers
+    pub const AL_MEDIA_PLAYER: u16 = 0x183;
+    pub const AL_EMAIL: u16 = 0x18a;
+    pub const AL_CALCULATOR: u16 = 0x192;
+    pub const AL_FILE_BROWSER: u16 = 0x194;
+
+    // Application controls, mostly for browsers
+    pub const AC_SEARCH: u16 = 0x221;
+    pub const AC_HOME: u16 = 0x223;
+    pub const AC_BACK: u16 = 0x224;
+    pub const AC_FORWARD: u16 = 0x225;
+    pub const AC_STOP: u16 = 0x226;
+    pub const AC_REFRESH: u16 = 0x227;
+    pub const AC_BOOKMARKS: u16 = 0x22a;
+}
+
+/// Number of usages reported at once.
+pub const MAX_PRESSED: usize = 4;
+
+/// Highest usage the report can carry, past the last usage the Consumer page defines.
+const MAX_USAGE: u16 = 0xfff;
+
+const REPORT_SIZE: usize = MAX_PRESSED * 2;
+
+/// The consumer control collection, with a report ID unless `report_id` is 0.
+pub const fn consumer_collection(builder: DescriptorBuilder, report_id: u8) -> DescriptorBuilder {
+    builder
+        .usage_page(UsagePage::Consumer)
+        .usage(usage::CONSUMER_CONTROL)
+        .collection(Collection::Application)
+        .optional_report_id(report_id)
+        .logical_minimum(0)
+        .logical_maximum(MAX_USAGE as i32)
+        .usage_minimum(0)
+        .usage_maximum(MAX_USAGE)
+        .report_size(16)
+        .report_count(MAX_PRESSED as u32)
+        .input(ItemFlags::DATA_ARRAY)
+        .end_collection()
+}
+
+const CONSUMER: DescriptorBuilder = consumer_collection(DescriptorBuilder::new(), 0);
+pub const CONSUMER_DESC: &[u8] = &CONSUMER.finish::<{ CONSUMER.len() }>();
+
+/// Length of `CONSUMER_DESC` with the two byte Report ID item added.
+const CONSUMER_DESC_WITH_ID_LEN: usize = CONSUMER.len() + 2;
+
+/// `CONSUMER_DESC` declaring the reports `ID`.
+struct ConsumerDescWithId<const ID: u8>;
+
+impl<const ID: u8> ConsumerDescWithId<ID> {
+    const DESC: [u8; CONSUMER_DESC_WITH_ID_LEN] = {
+        assert!(ID != 0, "report ID 0 is reserved");
+        consumer_collection(DescriptorBuilder::new(), ID).finish::<CONSUMER_DESC_WITH_ID_LEN>()
+    };
+}
+
+pub struct ConsumerControl {
+    name: &'static str,
+    descriptor: SharedDescriptor,
+    usages: [u16; MAX_PRESSED],
+    report: [u8; REPORT_SIZE],
+}
+
+impl ConsumerControl {
+    /// Creates a device for an interface of its own, with reports without ID.
+    pub fn new() -> ConsumerControl {
+        ConsumerControl {
+            name: "Consumer control",
+            descriptor: SharedDescriptor::new(CONSUMER_DESC),
+            usages: [0; MAX_PRESSED],
+            report: [0; REPORT_SIZE],
+        }
+    }
+
+    /// Prefixes the reports with `ID`, which must not be 0. Write them with
+    /// `HidClass::write_report`.
+    pub fn with_report_id<const ID: u8>(mut self) -> ConsumerControl {
+        self.descriptor = SharedDescriptor::with_report_id(&ConsumerDescWithId::<ID>::DESC, ID);
+        self
+    }
+
+    pub fn with_name(mut self, name: &'static str) -> ConsumerControl {
+        self.name = name;
+        self
+    }
+
+    /// Returns the report ID, 0 when the reports have none.
+    pub fn report_id(&self) -> u8 {
+        self.descriptor.report_id()
+    }
+
+    /// Adds a usage to the report, returning whether the report changed. Usages beyond
+    /// `MAX_PRESSED` at once are ignored.
+    pub fn press(&mut self, usage: u16) -> bool {
+        if usage == 0 || usage > MAX_USAGE || self.usages.contains(&usage) {
+            return false;
+        }
+        match self.usages.iter_mut().find(|u| **u == 0) {
+            Some(free) => *free = usage,
+            None => return false,
+        }
+        self.refresh_report();
+        true
+    }
+
+    /// Removes a usage from the report, returning whether the report changed.
+    pub fn release(&mut self, usage: u16) -> bool {
+        match self.usages.iter().position(|&u| u == usage && u != 0) {
+            Some(i) => {
+                // Keep the remaining usages in the order they were pressed
+                self.usages.copy_within(i + 1.., i);
+                self.usages[MAX_PRESSED - 1] = 0;
+            }
+            None => return false,
+        }
+        self.refresh_report();
+        true
+    }
+
+    /// Releases all usages, returning whether the report changed.
+    pub fn clear(&mut self) -> bool {
+        if self.usages == [0; MAX_PRESSED] {
+            return false;
+        }
+        self.usages = [0; MAX_PRESSED];
+        self.refresh_report();
+        true
+    }
+
+    /// Returns the input report, without report ID.
+    pub fn report(&self) -> &[u8] {
+        &self.report
+    }
+
+    fn refresh_report(&mut self) {
+        for (bytes, usage) in self.report.chunks_mut(2).zip(self.usages.iter()) {
+            bytes.copy_from_slice(&usage.to_le_bytes());
+        }
+    }
+}
+
+impl Default for ConsumerControl {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl HidDevice for ConsumerControl {
+    fn subclass(&self) -> Subclass {
+        Subclass::None
+    }
+
+    fn protocol(&self) -> Protocol {
+        Protocol::None
+    }
+
+    fn report_descriptor(&self) -> &[u8] {
+        self.descriptor.bytes()
+    }
+
+    fn get_report(&mut self, report_type: ReportType, report_id: u8) -> Result<&[u8], HidError> {
+        self.descriptor.check_input(report_type, report_id)?;
+        Ok(&self.report)
+    }
+
+    fn input_report(&self, _report_id: u8) -> Result<&[u8], HidError> {
+        Ok(&self.report)
+    }
+
+    fn set_report(
+        &mut self,
+        _report_type: ReportType,
+        _report_id: u8,
+        _data: &[u8],
+    ) -> Result<(), HidError> {
+        Err(HidError::UnsupportedReportType)
+    }
+
+    fn report_len(&self, report_type: ReportType, _report_id: u8) -> Option<usize> {
+        match report_type {
+            ReportType::Input => Some(REPORT_SIZE),
+            _ => None,
+        }
+    }
+
+    fn report_ids(&self) -> &[u8] {
+        self.descriptor.report_ids()
+    }
+
+    fn interface_name(&self, _lang_id: u16) -> Option<&str> {
+        Some(self.name)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{usage, ConsumerControl, CONSUMER_DESC, REPORT_SIZE};
+    use crate::descriptor;
+    use crate::hid::{HidDevice, HidError, ReportType};
+
+    #[test]
+    fn descriptor_declares_usage_array() {
+        let layout = descriptor::parse(CONSUMER_DESC).unwrap();
+        assert!(!layout.uses_report_ids());
+        assert_eq!(layout.report_len(ReportType::Input, 0), Some(REPORT_SIZE));
+        assert_eq!(layout.report_len(ReportType::Output, 0), None);
+
+        let mut consumer = ConsumerControl::new();
+        assert!(descriptor::check_device(&mut consumer).is_ok());
+    }
+
+    #[test]
+    fn report_id_is_declared() {
+        let mut consumer = ConsumerControl::new().with_report_id::<3>();
+        assert_eq!(consumer.report_ids(), [3]);
+        let layout = descriptor::check_device(&mut consumer).unwrap();
+        assert_eq!(layout.report_ids().collect::<Vec<_>>(), [3]);
+        assert_eq!(layout.report_len(ReportType::Input, 3), Some(REPORT_SIZE));
+        assert_eq!(
+            consumer.get_report(ReportType::Input, 0),
+            Err(HidError::UnknownReportId)
+        );
+    }
+
+    #[test]
+    fn press_and_release_maintain_the_report() {
+        let mut consumer = ConsumerControl::new();
+        assert!(consumer.press(usage::PLAY_PAUSE));
+        assert!(consumer.press(usage::AC_HOME));
+        assert!(!consumer.press(usage::PLAY_PAUSE));
+        assert_eq!(consumer.report(), [0xcd, 0, 0x23, 0x02, 0, 0, 0, 0]);
+
+        assert!(consumer.release(usage::PLAY_PAUSE));
+        assert!(!consumer.release(usage::PLAY_PAUSE));
+        assert_eq!(consumer.report(), [0x23, 0x02, 0, 0, 0, 0, 0, 0]);
+
+        assert!(consumer.clear());
+        assert!(!consumer.clear());
+        assert_eq!(consumer.report(), [0; REPORT_SIZE]);
+    }
+
+    #[test]
+    fn extra_usages_are_ignored() {
+        let mut consumer = ConsumerControl::new();
+        for usage in 0xe9..0xe9 + 4 {
+            assert!(consumer.press(usage));
+        }
+        assert!(!consumer.press(usage::MUTE));
+        assert!(!consumer.press(0));
+        assert_eq!(consumer.report()[6..], [0xec, 0]);
+    }
+}
@@ -0,0 +1,850 @@
+//! Parser for HID report descriptors, see HID 1.11 section 6.2.2.
+//!
+//! `parse` walks the items of a descriptor, checks that collections are balanced and every
+//! report ends on a byte boundary, and returns the length of each report. `check_device`
+//! compares those lengths with the reports a `HidDevice` actually sends and accepts.
+//!
+//! `DescriptorBuilder` goes the other way and writes descriptors in const context, so a
+//! descriptor is defined with named items rather than hex, and unbalanced collections or
+//! unpadded reports fail to compile.
+
+use crate::hid::{HidDevice, HidError, ReportType};
+use heapless::Vec;
+
+/// Number of distinct report IDs a parsed descriptor may declare.
+pub const MAX_REPORTS: usize = 16;
+
+/// Depth of nested collections, and of the global item stack, the parser keeps track of.
+const MAX_DEPTH: usize = 8;
+
+const LONG_ITEM_PREFIX: u8 = 0xfe;
+
+#[derive(Debug, defmt::Format, Clone, Copy, PartialEq)]
+pub enum DescriptorError {
+    /// The descriptor ends in the middle of an item.
+    Truncated,
+    /// A long item, which no HID class defines.
+    LongItem,
+    /// An End Collection without a matching Collection.
+    UnbalancedCollection,
+    /// A Collection that is never closed.
+    UnclosedCollection,
+    /// Collections or Push items nest deeper than the parser follows.
+    TooDeep,
+    /// A Pop without a matching Push.
+    UnbalancedPop,
+    /// An Input, Output or Feature item before any Report Size or Report Count.
+    MissingReportSize,
+    /// Report ID 0 is reserved.
+    ReportIdZero,
+    /// The descriptor uses report IDs, but declares a report before the first one.
+    MissingReportId,
+    /// More report IDs than `MAX_REPORTS`.
+    TooManyReports,
+    /// A report is longer than 2^32 bits, from a Report Size or Report Count no device uses.
+    ReportTooLong,
+    /// A report doesn't end on a byte boundary, it is missing constant padding.
+    NotByteAligned {
+        report_type: ReportType,
+        report_id: u8,
+    },
+    /// A device sends or expects a report of another length than its descriptor declares.
+    LengthMismatch {
+        report_type: ReportType,
+        report_id: u8,
+        descriptor: usize,
+        device: usize,
+    },
+}
+
+#[derive(Debug, defmt::Format, Clone, Copy, PartialEq)]
+#[repr(u8)]
+pub enum ItemType {
+    Main = 0,
+    Global = 1,
+    Local = 2,
+    Reserved = 3,
+}
+
+/// One short item of a report descriptor.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Item<'a> {
+    pub item_type: ItemType,
+    pub tag: u8,
+    pub data: &'a [u8],
+}
+
+impl Item<'_> {
+    /// The item data as an unsigned little endian number.
+    pub fn value(&self) -> u32 {
+        self.data
+            .iter()
+            .rev()
+            .fold(0, |value, &byte| value << 8 | u32::from(byte))
+    }
+}
+
+/// Iterates over the items of a report descriptor.
+pub struct Items<'a> {
+    descriptor: &'a [u8],
+}
+
+impl<'a> Items<'a> {
+    pub fn new(descriptor: &'a [u8]) -> Items<'a> {
+        Items { descriptor }
+    }
+}
+
+impl<'a> Iterator for Items<'a> {
+    type Item = Result<Item<'a>, DescriptorError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let (&prefix, rest) = self.descriptor.split_first()?;
+        if prefix == LONG_ITEM_PREFIX {
+            self.descriptor = &[];
+            return Some(Err(DescriptorError::LongItem));
+        }
+        let len = match prefix & 0b11 {
+            3 => 4,
+            size => usize::from(size),
+        };
+        if rest.len() < len {
+            self.descriptor = &[];
+            return Some(Err(DescriptorError::Truncated));
+        }
+        let (data, rest) = rest.split_at(len);
+        self.descriptor = rest;
+        let item_type = match (prefix >> 2) & 0b11 {
+            0 => ItemType::Main,
+            1 => ItemType::Global,
+            2 => ItemType::Local,
+            _ => ItemType::Reserved,
+        };
+        Some(Ok(Item {
+            item_type,
+            tag: prefix >> 4,
+            data,
+        }))
+    }
+}
+
+// Main item tags
+const INPUT: u8 = 0x8;
+const OUTPUT: u8 = 0x9;
+const FEATURE: u8 = 0xb;
+const COLLECTION: u8 = 0xa;
+const END_COLLECTION: u8 = 0xc;
+
+// Global item tags
+const USAGE_PAGE: u8 = 0x0;
+const LOGICAL_MINIMUM: u8 = 0x1;
+const LOGICAL_MAXIMUM: u8 = 0x2;
+const REPORT_SIZE: u8 = 0x7;
+const REPORT_ID: u8 = 0x8;
+const REPORT_COUNT: u8 = 0x9;
+const PUSH: u8 = 0xa;
+const POP: u8 = 0xb;
+
+// Local item tags
+const USAGE: u8 = 0x0;
+const USAGE_MINIMUM: u8 = 0x1;
+const USAGE_MAXIMUM: u8 = 0x2;
+
+/// The global items that decide report lengths.
+#[derive(Clone, Copy, Default)]
+struct Globals {
+    report_size: Option<u32>,
+    report_count: Option<u32>,
+    report_id: u8,
+}
+
+/// Lengths in bits of the input, output and feature report with one report ID.
+#[derive(Clone, Copy)]
+struct Report {
+    id: u8,
+    bits: [u32; 3],
+}
+
+/// The reports a descriptor declares.
+pub struct ReportLayout {
+    reports: Vec<Report, MAX_REPORTS>,
+    uses_report_ids: bool,
+}
+
+fn type_index(report_type: ReportType) -> Option<usize> {
+    match report_type {
+        ReportType::Input => Some(0),
+        ReportType::Output => Some(1),
+        ReportType::Feature => Some(2),
+        ReportType::Reserved(_) => None,
+    }
+}
+
+const REPORT_TYPES: [ReportType; 3] = [ReportType::Input, ReportType::Output, ReportType::Feature];
+
+impl ReportLayout {
+    /// Length in bytes of a report, without the report ID prefix. `None` if the descriptor
+    /// declares no such report.
+    pub fn report_len(&self, report_type: ReportType, report_id: u8) -> Option<usize> {
+        let index = type_index(report_type)?;
+        self.reports
+            .iter()
+            .find(|report| report.id == report_id)
+            .map(|report| report.bits[index])
+            .filter(|&bits| bits > 0)
+            .map(|bits| (bits / 8) as usize)
+    }
+
+    /// The declared report IDs, or only 0 if the descriptor doesn't use report IDs.
+    pub fn report_ids(&self) -> impl Iterator<Item = u8> + '_ {
+        self.reports.iter().map(|report| report.id)
+    }
+
+    pub fn uses_report_ids(&self) -> bool {
+        self.uses_report_ids
+    }
+
+    fn add_bits(&mut self, report_id: u8, index: usize, bits: u32) -> Result<(), DescriptorError> {
+        let position = self
+            .reports
+            .iter()
+            .position(|report| report.id == report_id);
+        let report = match position {
+            Some(position) => &mut self.reports[position],
+            None => {
+                self.reports
+                    .push(Report {
+                        id: report_id,
+                        bits: [0; 3],
+                    })
+                    .map_err(|_| DescriptorError::TooManyReports)?;
+                self.reports.last_mut().unwrap()
+            }
+        };
+        report.bits[index] = report.bits[index]
+            .checked_add(bits)
+            .ok_or(DescriptorError::ReportTooLong)?;
+        Ok(())
+    }
+}
+
+/// Parses a report descriptor and returns the reports it declares.
+pub fn parse(descriptor: &[u8]) -> Result<ReportLayout, DescriptorError> {
+    let mut layout = ReportLayout {
+        reports: Vec::new(),
+        uses_report_ids: false,
+    };
+    let mut globals = Globals::default();
+    let mut stack: Vec<Globals, MAX_DEPTH> = Vec::new();
+    let mut depth = 0;
+    let mut declared_report = false;
+
+    for item in Items::new(descriptor) {
+        let item = item?;
+        match (item.item_type, item.tag) {
+            (ItemType::Main, INPUT) | (ItemType::Main, OUTPUT) | (ItemType::Main, FEATURE) => {
+                let index = match item.tag {
+                    INPUT => 0,
+                    OUTPUT => 1,
+                    _ => 2,
+                };
+                let (size, count) = match (globals.report_size, globals.report_count) {
+                    (Some(size), Some(count)) => (size, count),
+                    _ => return Err(DescriptorError::MissingReportSize),
+                };
+                let bits = size
+                    .checked_mul(count)
+                    .ok_or(DescriptorError::ReportTooLong)?;
+                layout.add_bits(globals.report_id, index, bits)?;
+                declared_report = true;
+            }
+            (ItemType::Main, COLLECTION) => {
+                depth += 1;
+                if depth > MAX_DEPTH {
+                    return Err(DescriptorError::TooDeep);
+                }
+            }
+            (ItemType::Main, END_COLLECTION) => {
+                if depth == 0 {
+                    return Err(DescriptorError::UnbalancedCollection);
+                }
+                depth -= 1;
+            }
+            (ItemType::Global, REPORT_SIZE) => globals.report_size = Some(item.value()),
+            (ItemType::Global, REPORT_COUNT) => globals.report_count = Some(item.value()),
+            (ItemType::Global, REPORT_ID) => {
+                let report_id = item.value() as u8;
+                if report_id == 0 {
+                    return Err(DescriptorError::ReportIdZero);
+                }
+                if declared_report && !layout.uses_report_ids {
+                    return Err(DescriptorError::MissingReportId);
+                }
+                layout.uses_report_ids = true;
+                globals.report_id = report_id;
+            }
+            (ItemType::Global, PUSH) => {
+                stack.push(globals).map_err(|_| DescriptorError::TooDeep)?
+            }
+            (ItemType::Global, POP) => {
+                globals = stack.pop().ok_or(DescriptorError::UnbalancedPop)?;
+            }
+            _ => {}
+        }
+    }
+    if depth != 0 {
+        return Err(DescriptorError::UnclosedCollection);
+    }
+
+    for report in &layout.reports {
+        for (&report_type, &bits) in REPORT_TYPES.iter().zip(report.bits.iter()) {
+            if bits % 8 != 0 {
+                return Err(DescriptorError::NotByteAligned {
+                    report_type,
+                    report_id: report.id,
+                });
+            }
+        }
+    }
+    Ok(layout)
+}
+
+/// Checks that the reports of a device have the lengths its report descriptor declares: those
+/// it returns from `get_report` and `get_feature_report`, and those it expects according to
+/// `report_len`.
+pub fn check_device<D: HidDevice>(device: &mut D) -> Result<ReportLayout, DescriptorError> {
+    let layout = parse(device.report_descriptor())?;
+    let mismatch = |report_type, report_id, descriptor, device| {
+        Err(DescriptorError::LengthMismatch {
+            report_type,
+            report_id,
+            descriptor,
+            device,
+        })
+    };
+
+    for report_id in layout.report_ids() {
+        for &report_type in REPORT_TYPES.iter() {
+            let declared = layout.report_len(report_type, report_id).unwrap_or(0);
+            if let Some(len) = device.report_len(report_type, report_id) {
+                if len != declared {
+                    return mismatch(report_type, report_id, declared, len);
+                }
+            }
+            let sent = match report_type {
+                ReportType::Input => device.get_report(report_type, report_id),
+                ReportType::Feature => device.get_feature_report(report_id),
+                _ => continue,
+            };
+            if let Ok(report) = sent {
+                if report.len() != declared {
+                    return mismatch(report_type, report_id, declared, report.len());
+                }
+            }
+        }
+    }
+    Ok(layout)
+}
+
+/// Longest descriptor `DescriptorBuilder` can write.
+pub const MAX_DESCRIPTOR_LEN: usize = 256;
+
+/// Usage pages from the HID Usage Tables.
+#[derive(Debug, defmt::Format, Clone, Copy, PartialEq)]
+pub enum UsagePage {
+    GenericDesktop,
+    Keyboard,
+    Leds,
+    Button,
+    Consumer,
+    /// A vendor-defined page, 0xFF00 to 0xFFFF.
+    Vendor(u16),
+}
+
+impl UsagePage {
+    const fn id(self) -> u16 {
+        match self {
+            UsagePage::GenericDesktop => 0x01,
+            UsagePage::Keyboard => 0x07,
+            UsagePage::Leds => 0x08,
+            UsagePage::Button => 0x09,
+            UsagePage::Consumer => 0x0c,
+            UsagePage::Vendor(id) => id,
+        }
+    }
+}
+
+/// Usages on the Generic Desktop page.
+pub mod generic_desktop {
+    pub const POINTER: u16 = 0x01;
+    pub const MOUSE: u16 = 0x02;
+    pub const JOYSTICK: u16 = 0x04;
+    pub const GAMEPAD: u16 = 0x05;
+    pub const KEYBOARD: u16 = 0x06;
+    pub const X: u16 = 0x30;
+    pub const Y: u16 = 0x31;
+    pub const Z: u16 = 0x32;
+    pub const RX: u16 = 0x33;
+    pub const RY: u16 = 0x34;
+    pub const RZ: u16 = 0x35;
+    pub const WHEEL: u16 = 0x38;
+    pub const HAT_SWITCH: u16 = 0x39;
+    pub const SYSTEM_CONTROL: u16 = 0x80;
+    pub const SYSTEM_POWER_DOWN: u16 = 0x81;
+    pub const SYSTEM_SLEEP: u16 = 0x82;
+    pub const SYSTEM_WAKE_UP: u16 = 0x83;
+}
+
+#[derive(Debug, defmt::Format, Clone, Copy, PartialEq)]
+#[repr(u8)]
+pub enum Collection {
+    Physical = 0x00,
+    Application = 0x01,
+    Logical = 0x02,
+}
+
+/// Data bits of an Input, Output or Feature item, see HID 1.11 section 6.2.2.5.
+#[derive(Debug, defmt::Format, Clone, Copy, PartialEq)]
+pub struct ItemFlags(u8);
+
+impl ItemFlags {
+    /// An array of selectors, like the keycodes of a boot keyboard.
+    pub const DATA_ARRAY: ItemFlags = ItemFlags(0x00);
+    /// One absolute value per usage, like modifier bits.
+    pub const DATA_VARIABLE: ItemFlags = ItemFlags(0x02);
+    /// One value per usage, relative to the previous report, like mouse motion.
+    pub const DATA_VARIABLE_RELATIVE: ItemFlags = ItemFlags(0x06);
+    /// One absolute value per usage, where values out of the logical range mean no input,
+    /// like a centred hat switch.
+    pub const DATA_VARIABLE_NULL: ItemFlags = ItemFlags(0x42);
+    /// Padding.
+    pub const CONSTANT: ItemFlags = ItemFlags(0x03);
+}
+
+/// Writes a report descriptor in const context. Chain the items into a `const`, then turn it
+/// into bytes with `finish::<{ BUILDER.len() }>()`, see `keyboard::KEYBOARD_DESC`. Mistakes
+/// such as an unclosed collection panic, which fails the build.
+///
+/// Items are written with the shortest encoding of their value, like the hand-written
+/// descriptors in this crate.
+#[derive(Clone, Copy)]
+pub struct DescriptorBuilder {
+    bytes: [u8; MAX_DESCRIPTOR_LEN],
+    len: usize,
+    depth: usize,
+    report_size: u32,
+    report_count: u32,
+    /// Bits of the input, output and feature report since the last Report ID.
+    bits: [u32; 3],
+}
+
+impl DescriptorBuilder {
+    pub const fn new() -> DescriptorBuilder {
+        DescriptorBuilder {
+            bytes: [0; MAX_DESCRIPTOR_LEN],
+            len: 0,
+            depth: 0,
+            report_size: 0,
+            report_count: 0,
+            bits: [0; 3],
+        }
+    }
+
+    /// Length of the descriptor written so far.
+    pub const fn len(&self) -> usize {
+        self.len
+    }
+
+    pub const fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Returns the descriptor, `N` has to be `len()`.
+    pub const fn finish<const N: usize>(self) -> [u8; N] {
+        assert!(self.depth == 0, "unclosed collection");
+        assert!(N == self.len, "descriptor length differs from N");
+        let mut bytes = [0; N];
+        let mut i = 0;
+        while i < N {
+            bytes[i] = self.bytes[i];
+            i += 1;
+        }
+        bytes
+    }
+
+    pub const fn usage_page(self, page: UsagePage) -> Self {
+        if let UsagePage::Vendor(id) = page {
+            assert!(id >= 0xff00, "vendor usage pages start at 0xFF00");
+        }
+        self.unsigned(ItemType::Global, USAGE_PAGE, page.id() as u32)
+    }
+
+    pub const fn usage(self, usage: u16) -> Self {
+        self.unsigned(ItemType::Local, USAGE, usage as u32)
+    }
+
+    pub const fn usage_minimum(self, usage: u16) -> Self {
+        self.unsigned(ItemType::Local, USAGE_MINIMUM, usage as u32)
+    }
+
+    pub const fn usage_maximum(self, usage: u16) -> Self {
+        self.unsigned(ItemType::Local, USAGE_MAXIMUM, usage as u32)
+    }
+
+    pub const fn logical_minimum(self, value: i32) -> Self {
+        self.signed(ItemType::Global, LOGICAL_MINIMUM, value)
+    }
+
+    pub const fn logical_maximum(self, value: i32) -> Self {
+        self.signed(ItemType::Global, LOGICAL_MAXIMUM, value)
+    }
+
+    /// Size in bits of each field of the following main items.
+    pub const fn report_size(mut self, bits: u32) -> Self {
+        assert!(bits > 0, "report size 0");
+        self.report_size = bits;
+        self.unsigned(ItemType::Global, REPORT_SIZE, bits)
+    }
+
+    /// Number of fields of the following main items.
+    pub const fn report_count(mut self, count: u32) -> Self {
+        self.report_count = count;
+        self.unsigned(ItemType::Global, REPORT_COUNT, count)
+    }
+
+    pub const fn report_id(mut self, report_id: u8) -> Self {
+        assert!(report_id != 0, "report ID 0 is reserved");
+        assert!(self.is_byte_aligned(), "previous report isn't byte aligned");
+        self.bits = [0; 3];
+        self.unsigned(ItemType::Global, REPORT_ID, report_id as u32)
+    }
+
+    /// Like `report_id`, but writes nothing for report ID 0, which stands for reports without
+    /// ID.
+    pub const fn optional_report_id(self, report_id: u8) -> Self {
+        if report_id == 0 {
+            self
+        } else {
+            self.report_id(report_id)
+        }
+    }
+
+    pub const fn collection(mut self, collection: Collection) -> Self {
+        self.depth += 1;
+        self.unsigned(ItemType::Main, COLLECTION, collection as u32)
+    }
+
+    pub const fn end_collection(mut self) -> Self {
+        assert!(self.depth > 0, "end of collection without a collection");
+        self.depth -= 1;
+        assert!(
+            self.depth > 0 || self.is_byte_aligned(),
+            "report isn't byte aligned"
+        );
+        self.item(ItemType::Main, END_COLLECTION, 0, 0)
+    }
+
+    pub const fn input(self, flags: ItemFlags) -> Self {
+        self.main(0, INPUT, flags)
+    }
+
+    pub const fn output(self, flags: ItemFlags) -> Self {
+        self.main(1, OUTPUT, flags)
+    }
+
+    pub const fn feature(self, flags: ItemFlags) -> Self {
+        self.main(2, FEATURE, flags)
+    }
+
+    /// Pads the input report to the next byte boundary with constant bits, if needed. Padding
+    /// leaves the report size at 1, set it again before the next main item.
+    pub const fn pad_input(self) -> Self {
+        self.pad(0, INPUT)
+    }
+
+    /// Pads the output report to the next byte boundary, like `pad_input`.
+    pub const fn pad_output(self) -> Self {
+        self.pad(1, OUTPUT)
+    }
+
+    const fn is_byte_aligned(&self) -> bool {
+        self.bits[0] % 8 == 0 && self.bits[1] % 8 == 0 && self.bits[2] % 8 == 0
+    }
+
+    const fn pad(self, index: usize, tag: u8) -> Self {
+        let padding = (8 - self.bits[index] % 8) % 8;
+        if padding == 0 {
+            return self;
+        }
+        self.report_count(padding)
+            .report_size(1)
+            .main(index, tag, ItemFlags::CONSTANT)
+    }
+
+    const fn main(mut self, index: usize, tag: u8, flags: ItemFlags) -> Self {
+        assert!(
+            self.report_size > 0,
+            "main item before report size and count"
+        );
+        self.bits[index] += self.report_size * self.report_count;
+        self.item(ItemType::Main, tag, flags.0 as u32, 1)
+    }
+
+    const fn unsigned(self, item_type: ItemType, tag: u8, value: u32) -> Self {
+        let size = if value <= 0xff {
+            1
+        } else if value <= 0xffff {
+            2
+        } else {
+            4
+        };
+        self.item(item_type, tag, value, size)
+    }
+
+    const fn signed(self, item_type: ItemType, tag: u8, value: i32) -> Self {
+        let size = if value >= -0x80 && value <= 0x7f {
+            1
+        } else if value >= -0x8000 && value <= 0x7fff {
+            2
+        } else {
+            4
+        };
+        self.item(item_type, tag, value as u32, size)
+    }
+
+    const fn item(mut self, item_type: ItemType, tag: u8, value: u32, size: usize) -> Self {
+        assert!(
+            self.len + 1 + size <= MAX_DESCRIPTOR_LEN,
+            "descriptor longer than MAX_DESCRIPTOR_LEN"
+        );
+        let size_code = if size == 4 { 3 } else { size as u8 };
+        self.bytes[self.len] = tag << 4 | (item_type as u8) << 2 | size_code;
+        let mut i = 0;
+        while i < size {
+            self.bytes[self.len + 1 + i] = (value >> (8 * i)) as u8;
+            i += 1;
+        }
+        self.len += 1 + size;
+        self
+    }
+}
+
+impl Default for DescriptorBuilder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// The report descriptor of a device with a single input report, which either gets an interface
+/// of its own, or declares a report ID so it can share one. Devices build the descriptor with
+/// the Report ID item in a constant, so the builder checks still run at compile time.
+pub struct SharedDescriptor {
+    bytes: &'static [u8],
+    /// 0 when the reports have no ID.
+    report_id: u8,
+}
+
+impl SharedDescriptor {
+    /// A descriptor for reports without ID.
+    pub const fn new(bytes: &'static [u8]) -> Self {
+        SharedDescriptor {
+            bytes,
+            report_id: 0,
+        }
+    }
+
+    /// A descriptor `bytes` declaring the reports `report_id`.
+    pub const fn with_report_id(bytes: &'static [u8], report_id: u8) -> Self {
+        SharedDescriptor { bytes, report_id }
+    }
+
+    pub fn report_id(&self) -> u8 {
+        self.report_id
+    }
+
+    pub fn bytes(&self) -> &[u8] {
+        self.bytes
+    }
+
+    /// The report IDs to return from `HidDevice::report_ids`.
+    pub fn report_ids(&self) -> &[u8] {
+        if self.report_id == 0 {
+            &[]
+        } else {
+            core::slice::from_ref(&self.report_id)
+        }
+    }
+
+    /// Checks that a `HidDevice::get_report` request is for the input report.
+    pub fn check_input(&self, report_type: ReportType, report_id: u8) -> Result<(), HidError> {
+        if report_type != ReportType::Input {
+            return Err(HidError::UnsupportedReportType);
+        }
+        if report_id != self.report_id {
+            return Err(HidError::UnknownReportId);
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        parse, Collection, DescriptorBuilder, DescriptorError, ItemFlags, ItemType, Items,
+        UsagePage,
+    };
+    use crate::hid::ReportType;
+
+    #[test]
+    fn splits_items() {
+        let descriptor = [0x05, 0x01, 0x26, 0xff, 0x00, 0xc0];
+        let items: std::vec::Vec<_> = Items::new(&descriptor).map(Result::unwrap).collect();
+        assert_eq!(items.len(), 3);
+        assert_eq!(items[0].item_type, ItemType::Global);
+        assert_eq!(items[1].value(), 255);
+        assert_eq!(items[2].item_type, ItemType::Main);
+        assert!(items[2].data.is_empty());
+    }
+
+    #[test]
+    fn sums_reports_per_id() {
+        #[rustfmt::skip]
+        let descriptor = [
+            0x85, 0x01,       // Report ID (1)
+            0x75, 0x08,       // Report Size (8)
+            0x95, 0x02,       // Report Count (2)
+            0x81, 0x02,       // Input
+            0xb1, 0x02,       // Feature
+            0x85, 0x02,       // Report ID (2)
+            0x95, 0x04,       // Report Count (4)
+            0x81, 0x02,       // Input
+        ];
+        let layout = parse(&descriptor).unwrap();
+        assert!(layout.uses_report_ids());
+        assert_eq!(layout.report_len(ReportType::Input, 1), Some(2));
+        assert_eq!(layout.report_len(ReportType::Feature, 1), Some(2));
+        assert_eq!(layout.report_len(ReportType::Output, 1), None);
+        assert_eq!(layout.report_len(ReportType::Input, 2), Some(4));
+    }
+
+    #[test]
+    fn push_and_pop_restore_globals() {
+        #[rustfmt::skip]
+        let descriptor = [
+            0x75, 0x08, 0x95, 0x01, // Report Size (8), Report Count (1)
+            0xa4,                   // Push
+            0x95, 0x04,             // Report Count (4)
+            0x81, 0x02,             // Input
+            0xb4,                   // Pop
+            0x81, 0x02,             // Input
+        ];
+        let layout = parse(&descriptor).unwrap();
+        assert_eq!(layout.report_len(ReportType::Input, 0), Some(5));
+    }
+
+    #[test]
+    fn rejects_malformed_descriptors() {
+        assert_eq!(parse(&[0x26, 0xff]).err(), Some(DescriptorError::Truncated));
+        assert_eq!(
+            parse(&[0xa1, 0x01]).err(),
+            Some(DescriptorError::UnclosedCollection)
+        );
+        assert_eq!(
+            parse(&[0xc0]).err(),
+            Some(DescriptorError::UnbalancedCollection)
+        );
+        assert_eq!(
+            parse(&[0x81, 0x02]).err(),
+            Some(DescriptorError::MissingReportSize)
+        );
+        assert_eq!(
+            parse(&[0x85, 0x00]).err(),
+            Some(DescriptorError::ReportIdZero)
+        );
+        assert_eq!(
+            parse(&[0x75, 0x08, 0x95, 0x01, 0x81, 0x02, 0x85, 0x01]).err(),
+            Some(DescriptorError::MissingReportId)
+        );
+    }
+
+    #[test]
+    fn rejects_oversized_reports() {
+        #[rustfmt::skip]
+        let descriptor = [
+            0x77, 0xff, 0xff, 0xff, 0xff, // Report Size (4294967295)
+            0x95, 0x02,                   // Report Count (2)
+            0x81, 0x02,                   // Input
+        ];
+        assert_eq!(
+            parse(&descriptor).err(),
+            Some(DescriptorError::ReportTooLong)
+        );
+
+        // Each item fits, their sum doesn't
+        #[rustfmt::skip]
+        let descriptor = [
+            0x75, 0x08,                   // Report Size (8)
+            0x97, 0x00, 0x00, 0x00, 0x10, // Report Count (268435456)
+            0x81, 0x02,                   // Input
+            0x81, 0x02,                   // Input
+        ];
+        assert_eq!(
+            parse(&descriptor).err(),
+            Some(DescriptorError::ReportTooLong)
+        );
+    }
+
+    #[test]
+    fn builder_uses_shortest_encoding() {
+        const BUILDER: DescriptorBuilder = DescriptorBuilder::new()
+            .usage_page(UsagePage::Vendor(0xff00))
+            .usage_maximum(0xe7)
+            .logical_minimum(-127)
+            .logical_maximum(255)
+            .report_count(164);
+        #[rustfmt::skip]
+        assert_eq!(
+            BUILDER.finish::<{ BUILDER.len() }>(),
+            [
+                0x06, 0x00, 0xff, // Usage Page (Vendor Defined 0xFF00)
+                0x29, 0xe7,       // Usage Maximum (0xE7)
+                0x15, 0x81,       // Logical Minimum (-127)
+                0x26, 0xff, 0x00, // Logical Maximum (255)
+                0x95, 0xa4,       // Report Count (164)
+            ]
+        );
+    }
+
+    #[test]
+    fn builder_pads_reports() {
+        const BUILDER: DescriptorBuilder = DescriptorBuilder::new()
+            .collection(Collection::Application)
+            .report_size(1)
+            .report_count(5)
+            .input(ItemFlags::DATA_VARIABLE)
+            .pad_input()
+            .end_collection();
+        let descriptor = BUILDER.finish::<{ BUILDER.len() }>();
+        let layout = parse(&descriptor).unwrap();
+        assert_eq!(layout.report_len(ReportType::Input, 0), Some(1));
+    }
+
+    #[test]
+    #[should_panic(expected = "unclosed collection")]
+    fn builder_rejects_unclosed_collection() {
+        DescriptorBuilder::new()
+            .collection(Collection::Application)
+            .finish::<2>();
+    }
+
+    #[test]
+    fn rejects_missing_padding() {
+        assert_eq!(
+            parse(&[0x75, 0x01, 0x95, 0x05, 0x91, 0x02]).err(),
+            Some(DescriptorError::NotByteAligned {
+                report_type: ReportType::Output,
+                report_id: 0,
+            })
+        );
+    }
+}
@@ -0,0 +1,168 @@
+//! The flash page `memory.x` keeps out of the program for settings, see `storage` for what
+//! goes in it.
+//!
+//! Erasing and programming stall every flash access, code fetches included, so interrupts are
+//! held off for up to 40 ms per erase. Save after edits settle rather than on every change, see
+//! `storage::Saver`.
+
+use crate::storage::Store;
+use core::sync::atomic::{AtomicBool, Ordering};
+use stm32f3xx_hal::pac;
+
+/// Start of the SETTINGS region in `memory.x`, the last 2K page of the 256K flash.
+const SETTINGS_ADDRESS: usize = 0x0803_f800;
+pub const PAGE_SIZE: usize = 2048;
+
+const KEY1: u32 = 0x4567_0123;
+const KEY2: u32 = 0xcdef_89ab;
+
+// FLASH_SR and FLASH_CR bits, see RM0316 section 4.5
+const SR_BSY: u32 = 1 << 0;
+const SR_PGERR: u32 = 1 << 2;
+const SR_WRPRTERR: u32 = 1 << 4;
+const SR_EOP: u32 = 1 << 5;
+const CR_PG: u32 = 1 << 0;
+const CR_PER: u32 = 1 << 1;
+const CR_STRT: u32 = 1 << 6;
+const CR_LOCK: u32 = 1 << 7;
+
+#[derive(Debug, defmt::Format, Clone, Copy, PartialEq)]
+pub enum FlashError {
+    /// The data is longer than the page.
+    TooLong,
+    /// The page is write protected.
+    Protected,
+    /// A half-word wasn't erased before programming it.
+    NotErased,
+    /// The page doesn't read back what was written.
+    Verify,
+}
+
+static TAKEN: AtomicBool = AtomicBool::new(false);
+
+/// The settings page.
+pub struct SettingsFlash {
+    // Only one exists, see `take`
+    _private: (),
+}
+
+impl SettingsFlash {
+    /// Returns the settings page the first time it is called, `None` after that.
+    ///
+    /// The HAL consumes `FLASH` in `constrain` but only touches its access control register,
+    /// this uses the others.
+    pub fn take() -> Option<SettingsFlash> {
+        if TAKEN.swap(true, Ordering::Relaxed) {
+            None
+        } else {
+            Some(SettingsFlash { _private: () })
+        }
+    }
+
+    /// Returns the page, all 0xff when erased.
+    pub fn read(&self) -> &[u8] {
+        // NOTE(unsafe) the page is always mapped, and only changes through `&mut self`
+        unsafe { core::slice::from_raw_parts(SETTINGS_ADDRESS as *const u8, PAGE_SIZE) }
+    }
+
+    /// Erases the page.
+    pub fn erase(&mut self) -> Result<(), FlashError> {
+        let flash = unlock();
+        // NOTE(unsafe) the control, address and status registers take raw bits, see the consts
+        flash.cr.modify(|r, w| unsafe { w.bits(r.bits() | CR_PER) });
+        // NOTE(unsafe) any address in the page selects it
+        flash
+            .ar
+            .write(|w| unsafe { w.bits(SETTINGS_ADDRESS as u32) });
+        flash
+            .cr
+            .modify(|r, w| unsafe { w.bits(r.bits() | CR_STRT) });
+        let result = wait(flash);
+        flash
+            .cr
+            .modify(|r, w| unsafe { w.bits(r.bits() & !CR_PER) });
+        lock(flash);
+        result
+    }
+
+    /// Erases the page and writes `data` at its start. Odd lengths are padded with 0xff.
+    pub fn write(&mut self, data: &[u8]) -> Result<(), FlashError> {
+        if data.len() > PAGE_SIZE {
+            return Err(FlashError::TooLong);
+        }
+        self.erase()?;
+
+        let flash = unlock();
+        flash.cr.modify(|r, w| unsafe { w.bits(r.bits() | CR_PG) });
+        let mut result = Ok(());
+        for (i, chunk) in data.chunks(2).enumerate() {
+            let half_word = u16::from_le_bytes([chunk[0], *chunk.get(1).unwrap_or(&0xff)]);
+            let address = (SETTINGS_ADDRESS + i * 2) as *mut u16;
+            // NOTE(unsafe) the address is half-word aligned and within the settings page, which
+            // the program never occupies
+            unsafe { core::ptr::write_volatile(address, half_word) };
+            result = wait(flash);
+            if result.is_ok() && unsafe { core::ptr::read_volatile(address) } != half_word {
+                result = Err(FlashError::Verify);
+            }
+            if result.is_err() {
+                break;
+            }
+        }
+        flash.cr.modify(|r, w| unsafe { w.bits(r.bits() & !CR_PG) });
+        lock(flash);
+        result
+    }
+}
+
+impl Store for SettingsFlash {
+    type Error = FlashError;
+
+    fn read(&self) -> &[u8] {
+        SettingsFlash::read(self)
+    }
+
+    fn write(&mut self, data: &[u8]) -> Result<(), FlashError> {
+        SettingsFlash::write(self, data)
+    }
+
+    fn erase(&mut self) -> Result<(), FlashError> {
+        SettingsFlash::erase(self)
+    }
+}
+
+fn unlock() -> &'static pac::flash::RegisterBlock {
+    // NOTE(unsafe) the HAL never touches these registers, and `SettingsFlash` is the only
+    // other user
+    let flash = unsafe { &*pac::FLASH::ptr() };
+    while flash.sr.read().bits() & SR_BSY != 0 {}
+    if flash.cr.read().bits() & CR_LOCK != 0 {
+        // NOTE(unsafe) the key sequence, any other write locks the controller until reset
+        flash.keyr.write(|w| unsafe { w.bits(KEY1) });
+        flash.keyr.write(|w| unsafe { w.bits(KEY2) });
+    }
+    flash
+}
+
+fn lock(flash: &pac::flash::RegisterBlock) {
+    flash
+        .cr
+        .modify(|r, w| unsafe { w.bits(r.bits() | CR_LOCK) });
+}
+
+/// Waits for the current operation, then clears and checks its status flags.
+fn wait(flash: &pac::flash::RegisterBlock) -> Result<(), FlashError> {
+    while flash.sr.read().bits() & SR_BSY != 0 {}
+    let status = flash.sr.read().bits();
+    // NOTE(unsafe) the status flags are cleared by writing 1
+    flash
+        .sr
+        .write(|w| unsafe { w.bits(SR_EOP | SR_PGERR | SR_WRPRTERR) });
+    if status & SR_WRPRTERR != 0 {
+        Err(FlashError::Protected)
+    } else if status & SR_PGERR != 0 {
+        Err(FlashError::NotErased)
+    } else {
+        Ok(())
+    }
+}
@@ -0,0 +1,318 @@
+//! A gamepad with 32 buttons, a hat switch and six axes.
+//!
+//! Arcade sticks drive the hat switch with four direction keys through `GamepadKey`, so their
+//! buttons and stick can be bound from a keyberon layout like any other key. Opposite
+//! directions held together cancel out.
+
+use crate::descriptor::{generic_desktop, Collection, DescriptorBuilder, ItemFlags, UsagePage};
+use crate::hid::{HidDevice, HidError, Protocol, ReportType, Subclass};
+
+pub const NUM_BUTTONS: u8 = 32;
+
+const GAMEPAD: DescriptorBuilder = DescriptorBuilder::new()
+    .usage_page(UsagePage::GenericDesktop)
+    .usage(generic_desktop::GAMEPAD)
+    .collection(Collection::Application)
+    // Buttons
+    .usage_page(UsagePage::Button)
+    .usage_minimum(1)
+    .usage_maximum(NUM_BUTTONS as u16)
+    .logical_minimum(0)
+    .logical_maximum(1)
+    .report_size(1)
+    .report_count(NUM_BUTTONS as u32)
+    .input(ItemFlags::DATA_VARIABLE)
+    // Hat switch, clockwise from up in eighths of a turn
+    .usage_page(UsagePage::GenericDesktop)
+    .usage(generic_desktop::HAT_SWITCH)
+    .logical_maximum(7)
+    .report_size(4)
+    .report_count(1)
+    .input(ItemFlags::DATA_VARIABLE_NULL)
+    .pad_input()
+    // Axes
+    .usage_minimum(generic_desktop::X)
+    .usage_maximum(generic_desktop::RZ)
+    .logical_minimum(-127)
+    .logical_maximum(127)
+    .report_size(8)
+    .report_count(NUM_AXES as u32)
+    .input(ItemFlags::DATA_VARIABLE)
+    .end_collection();
+pub const GAMEPAD_DESC: &[u8] = &GAMEPAD.finish::<{ GAMEPAD.len() }>();
+
+const NUM_AXES: usize = 6;
+
+/// Size of the input report: buttons, hat switch and axes. Longer than the default packet
+/// size, build the `HidClass` with a max packet size of 16 to send it in a single packet.
+pub const GAMEPAD_REPORT_SIZE: usize = 4 + 1 + NUM_AXES;
+
+/// The axes in report order.
+#[derive(Debug, defmt::Format, Clone, Copy, PartialEq)]
+pub enum Axis {
+    X,
+    Y,
+    Z,
+    Rx,
+    Ry,
+    Rz,
+}
+
+/// Keys a layout can bind.
+#[derive(Debug, defmt::Format, Clone, Copy, PartialEq)]
+pub enum GamepadKey {
+    /// Buttons 0 to 31, reported as buttons 1 to 32.
+    Button(u8),
+    Up,
+    Down,
+    Left,
+    Right,
+}
+
+pub struct Gamepad {
+    name: &'static str,
+    buttons: u32,
+    /// Held direction keys, bit 0 to 3: up, down, left and right.
+    directions: u8,
+    axes: [i8; NUM_AXES],
+    report: [u8; GAMEPAD_REPORT_SIZE],
+}
+
+impl Gamepad {
+    pub fn new() -> Gamepad {
+        let mut gamepad = Gamepad {
+            name: "Gamepad",
+            buttons: 0,
+            directions: 0,
+            axes: [0; NUM_AXES],
+            report: [0; GAMEPAD_REPORT_SIZE],
+        };
+        gamepad.refresh_report();
+        gamepad
+    }
+
+    pub fn with_name(mut self, name: &'static str) -> Gamepad {
+        self.name = name;
+        self
+    }
+
+    /// Presses a button or direction, returning whether the report changed. Buttons past
+    /// `NUM_BUTTONS` are ignored.
+    pub fn press(&mut self, key: GamepadKey) -> bool {
+        match key {
+            GamepadKey::Button(button) if button < NUM_BUTTONS => self.buttons |= 1 << button,
+            GamepadKey::Button(_) => return false,
+            _ => self.directions |= direction_bit(key),
+        }
+        self.refresh_report()
+    }
+
+    /// Releases a button or direction, returning whether the report changed.
+    pub fn release(&mut self, key: GamepadKey) -> bool {
+        match key {
+            GamepadKey::Button(button) if button < NUM_BUTTONS => self.buttons &= !(1 << button),
+            GamepadKey::Button(_) => return false,
+            _ => self.directions &= !direction_bit(key),
+        }
+        self.refresh_report()
+    }
+
+    /// Sets an axis, returning whether the report changed. -128 reads as -127.
+    pub fn set_axis(&mut self, axis: Axis, value: i8) -> bool {
+        self.axes[axis as usize] = value.max(-127);
+        self.refresh_report()
+    }
+
+    /// Releases all buttons and directions and centres the axes, returning whether the
+    /// report changed.
+    pub fn clear(&mut self) -> bool {
+        self.buttons = 0;
+        self.directions = 0;
+        self.axes = [0; NUM_AXES];
+        self.refresh_report()
+    }
+
+    /// Returns the hat switch position, 0 for up through 7 for up-left, or 8 when centred.
+    pub fn hat(&self) -> u8 {
+        let held = |key| self.directions & direction_bit(key) != 0;
+        let vertical = i8::from(held(GamepadKey::Down)) - i8::from(held(GamepadKey::Up));
+        let horizontal = i8::from(held(GamepadKey::Right)) - i8::from(held(GamepadKey::Left));
+        match (vertical, horizontal) {
+            (-1, 0) => 0,
+            (-1, 1) => 1,
+            (0, 1) => 2,
+            (1, 1) => 3,
+            (1, 0) => 4,
+            (1, -1) => 5,
+            (0, -1) => 6,
+            (-1, -1) => 7,
+            _ => 8,
+        }
+    }
+
+    pub fn report(&self) -> &[u8] {
+        &self.report
+    }
+
+    fn refresh_report(&mut self) -> bool {
+        let mut report = [0; GAMEPAD_REPORT_SIZE];
+        report[..4].copy_from_slice(&self.buttons.to_le_bytes());
+        report[4] = self.hat();
+        for (byte, &axis) in report[5..].iter_mut().zip(self.axes.iter()) {
+            *byte = axis as u8;
+        }
+        let changed = report != self.report;
+        self.report = report;
+        changed
+    }
+}
+
+impl Default for Gamepad {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+fn direction_bit(key: GamepadKey) -> u8 {
+    match key {
+        GamepadKey::Up => 1 << 0,
+        GamepadKey::Down => 1 << 1,
+        GamepadKey::Left => 1 << 2,
+        GamepadKey::Right => 1 << 3,
+        GamepadKey::Button(_) => 0,
+    }
+}
+
+impl HidDevice for Gamepad {
+    fn subclass(&self) -> Subclass {
+        Subclass::None
+    }
+
+    fn protocol(&self) -> Protocol {
+        Protocol::None
+    }
+
+    fn report_descriptor(&self) -> &[u8] {
+        GAMEPAD_DESC
+    }
+
+    fn get_report(&mut self, report_type: ReportType, _report_id: u8) -> Result<&[u8], HidError> {
+        if report_type != ReportType::Input {
+            return Err(HidError::UnsupportedReportType);
+        }
+        Ok(&self.report)
+    }
+
+    fn input_report(&self, _report_id: u8) -> Result<&[u8], HidError> {
+        Ok(self.report())
+    }
+
+    fn set_report(
+        &mut self,
+        _report_type: ReportType,
+        _report_id: u8,
+        _data: &[u8],
+    ) -> Result<(), HidError> {
+        Err(HidError::UnsupportedReportType)
+    }
+
+    fn report_len(&self, report_type: ReportType, _report_id: u8) -> Option<usize> {
+        match report_type {
+            ReportType::Input => Some(GAMEPAD_REPORT_SIZE),
+            _ => None,
+        }
+    }
+
+    fn interface_name(&self, _lang_id: u16) -> Option<&str> {
+        Some(self.name)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Axis, Gamepad, GamepadKey, GAMEPAD_DESC, GAMEPAD_REPORT_SIZE};
+    use crate::descriptor;
+    use crate::hid::ReportType;
+
+    #[rustfmt::skip]
+    const GAMEPAD_DESC_BYTES: &[u8] = &[
+        0x05, 0x01,       // USAGE_PAGE (Generic Desktop)
+        0x09, 0x05,       // USAGE (Game Pad)
+        0xa1, 0x01,       // COLLECTION (Application)
+        0x05, 0x09,       //   USAGE_PAGE (Button)
+        0x19, 0x01,       //   USAGE_MINIMUM (Button 1)
+        0x29, 0x20,       //   USAGE_MAXIMUM (Button 32)
+        0x15, 0x00,       //   LOGICAL_MINIMUM (0)
+        0x25, 0x01,       //   LOGICAL_MAXIMUM (1)
+        0x75, 0x01,       //   REPORT_SIZE (1)
+        0x95, 0x20,       //   REPORT_COUNT (32)
+        0x81, 0x02,       //   INPUT (Data,Var,Abs)
+        0x05, 0x01,       //   USAGE_PAGE (Generic Desktop)
+        0x09, 0x39,       //   USAGE (Hat switch)
+        0x25, 0x07,       //   LOGICAL_MAXIMUM (7)
+        0x75, 0x04,       //   REPORT_SIZE (4)
+        0x95, 0x01,       //   REPORT_COUNT (1)
+        0x81, 0x42,       //   INPUT (Data,Var,Abs,Null)
+        0x95, 0x04,       //   REPORT_COUNT (4)
+        0x75, 0x01,       //   REPORT_SIZE (1)
+        0x81, 0x03,       //   INPUT (Cnst,Var,Abs)
+        0x19, 0x30,       //   USAGE_MINIMUM (X)
+        0x29, 0x35,       //   USAGE_MAXIMUM (Rz)
+        0x15, 0x81,       //   LOGICAL_MINIMUM (-127)
+        0x25, 0x7f,       //   LOGICAL_MAXIMUM (127)
+        0x75, 0x08,       //   REPORT_SIZE (8)
+        0x95, 0x06,       //   REPORT_COUNT (6)
+        0x81, 0x02,       //   INPUT (Data,Var,Abs)
+        0xc0,             // END_COLLECTION
+    ];
+
+    #[test]
+    fn descriptor_matches_report() {
+        assert_eq!(GAMEPAD_DESC, GAMEPAD_DESC_BYTES);
+        let mut gamepad = Gamepad::new();
+        let layout = descriptor::check_device(&mut gamepad).unwrap();
+        assert_eq!(
+            layout.report_len(ReportType::Input, 0),
+            Some(GAMEPAD_REPORT_SIZE)
+        );
+    }
+
+    #[test]
+    fn buttons_and_axes_fill_the_report() {
+        let mut gamepad = Gamepad::new();
+        // Centred hat
+        assert_eq!(gamepad.report(), [0, 0, 0, 0, 8, 0, 0, 0, 0, 0, 0]);
+
+        assert!(gamepad.press(GamepadKey::Button(0)));
+        assert!(gamepad.press(GamepadKey::Button(31)));
+        assert!(!gamepad.press(GamepadKey::Button(31)));
+        assert!(!gamepad.press(GamepadKey::Button(32)));
+        assert!(gamepad.set_axis(Axis::Y, -128));
+        assert!(gamepad.set_axis(Axis::Rz, 100));
+        assert_eq!(gamepad.report(), [1, 0, 0, 0x80, 8, 0, 0x81, 0, 0, 0, 100]);
+
+        assert!(gamepad.release(GamepadKey::Button(0)));
+        assert!(gamepad.clear());
+        assert!(!gamepad.clear());
+        assert_eq!(gamepad.report()[..5], [0, 0, 0, 0, 8]);
+    }
+
+    #[test]
+    fn directions_drive_the_hat() {
+        let mut gamepad = Gamepad::new();
+        assert!(gamepad.press(GamepadKey::Up));
+        assert_eq!(gamepad.hat(), 0);
+        assert!(gamepad.press(GamepadKey::Left));
+        assert_eq!(gamepad.hat(), 7);
+        assert_eq!(gamepad.report()[4], 7);
+        assert!(gamepad.press(GamepadKey::Down));
+        // Up and down cancel out
+        assert_eq!(gamepad.hat(), 6);
+        assert!(gamepad.release(GamepadKey::Up));
+        assert_eq!(gamepad.hat(), 5);
+        assert!(gamepad.press(GamepadKey::Right));
+        assert_eq!(gamepad.hat(), 4);
+        assert!(gamepad.release(GamepadKey::Down));
+        assert_eq!(gamepad.hat(), 8);
+    }
+}
@@ -1,17 +1,45 @@
 // Copyright 2019 Robin Krahl <robin.krahl@ireas.org>, Guillaume Pinot <texitoi@texitoi.eu>
 // SPDX-License-Identifier: Apache-2.0 OR MIT
 
+use heapless::{Deque, Vec};
 use usb_device::bus::{InterfaceNumber, StringIndex, UsbBus, UsbBusAllocator};
 use usb_device::class::{ControlIn, ControlOut, UsbClass};
 use usb_device::control;
 use usb_device::control::{Recipient, RequestType};
-use usb_device::descriptor::DescriptorWriter;
-use usb_device::endpoint::{EndpointAddress, EndpointIn};
+use usb_device::descriptor::{lang_id, DescriptorWriter};
+use usb_device::endpoint::{EndpointAddress, EndpointIn, EndpointOut};
 use usb_device::UsbError;
 
 const SPECIFICATION_RELEASE: u16 = 0x111;
 const INTERFACE_CLASS_HID: u8 = 0x03;
 
+/// Largest input report (including a report ID prefix) that can be written.
+pub const MAX_REPORT_LEN: usize = 64;
+
+/// Number of input reports `HidClass` holds back while the interrupt IN endpoint is busy.
+pub const REPORT_QUEUE_LEN: usize = 8;
+
+/// Number of report strings a single interface can declare.
+pub const MAX_REPORT_STRINGS: usize = 4;
+
+/// Largest wMaxPacketSize of a full speed interrupt endpoint.
+pub const MAX_PACKET_SIZE: u16 = 64;
+
+const DEFAULT_MAX_PACKET_SIZE: u16 = 8;
+const DEFAULT_POLL_INTERVAL_MS: u8 = 10;
+
+/// Number of report IDs a single interface can declare.
+pub const MAX_REPORT_IDS: usize = 4;
+
+/// GET_IDLE/SET_IDLE durations are expressed in units of 4 ms.
+const IDLE_UNIT_MS: u16 = 4;
+
+/// Default idle duration for keyboards (500 ms), as recommended by HID 1.11 section 7.2.4.
+const KEYBOARD_DEFAULT_IDLE: u8 = 125;
+
+/// An input report, including the report ID prefix when the device uses report IDs.
+pub type Report = Vec<u8, MAX_REPORT_LEN>;
+
 #[derive(Clone, Copy, Debug, PartialEq)]
 #[repr(u8)]
 pub enum Subclass {
@@ -27,6 +55,58 @@ pub enum Protocol {
     Mouse = 0x02,
 }
 
+/// Errors of `HidDevice` implementations and `HidClass`.
+///
+/// An error returned while handling a control request stalls that request: usb-device offers
+/// no way to NAK the data stage of a control transfer. `Busy` from `HidClass::write`
+/// corresponds to the interrupt IN endpoint NAKing the host, the report should be retried
+/// once the queue drains.
+#[derive(Debug, defmt::Format, Clone, Copy, PartialEq)]
+pub enum HidError {
+    /// The report can't be taken right now, retry later.
+    Busy,
+    /// The device has no report of the requested type, e.g. a feature report.
+    UnsupportedReportType,
+    /// The report ID isn't declared by the device.
+    UnknownReportId,
+    /// The report is longer or shorter than the device expects.
+    BadLength,
+    /// A value in the report is out of range.
+    BadValue,
+    /// The request isn't supported by the device or interface.
+    Unsupported,
+    /// The USB peripheral failed the transfer.
+    Bus,
+}
+
+impl From<UsbError> for HidError {
+    fn from(err: UsbError) -> Self {
+        match err {
+            UsbError::WouldBlock => HidError::Busy,
+            UsbError::BufferOverflow => HidError::BadLength,
+            _ => HidError::Bus,
+        }
+    }
+}
+
+/// Report protocol selected by the host with SET_PROTOCOL, see HID 1.11 section 7.2.6.
+#[derive(Debug, defmt::Format, Clone, Copy, PartialEq)]
+#[repr(u8)]
+pub enum ReportProtocol {
+    Boot = 0x00,
+    Report = 0x01,
+}
+
+impl ReportProtocol {
+    fn new(u: u8) -> Option<ReportProtocol> {
+        match u {
+            0x00 => Some(ReportProtocol::Boot),
+            0x01 => Some(ReportProtocol::Report),
+            _ => None,
+        }
+    }
+}
+
 #[derive(Debug, Clone, Copy)]
 #[repr(u8)]
 pub enum DescriptorType {
@@ -86,55 +166,567 @@ pub trait HidDevice {
 
     fn report_descriptor(&self) -> &[u8];
 
-    fn set_report(&mut self, report_type: ReportType, report_id: u8, data: &[u8])
-        -> Result<(), ()>;
+    /// Handles an input or output report sent by the host. Feature reports go to
+    /// `set_feature_report` instead.
+    fn set_report(
+        &mut self,
+        report_type: ReportType,
+        report_id: u8,
+        data: &[u8],
+    ) -> Result<(), HidError>;
+
+    /// Returns an input or output report requested by the host. Feature reports are read with
+    /// `get_feature_report` instead.
+    fn get_report(&mut self, report_type: ReportType, report_id: u8) -> Result<&[u8], HidError>;
+
+    /// Returns the input report `HidClass::write_device_report` sends over the interrupt IN
+    /// endpoint. Unlike `get_report` it includes relative values, which the host must only
+    /// receive once. Devices that leave writing reports to the application keep the default.
+    fn input_report(&self, _report_id: u8) -> Result<&[u8], HidError> {
+        Err(HidError::UnsupportedReportType)
+    }
+
+    /// Handles a feature report written by the host, typically a device setting. Devices
+    /// without feature reports keep the default, which rejects them.
+    fn set_feature_report(&mut self, _report_id: u8, _data: &[u8]) -> Result<(), HidError> {
+        Err(HidError::UnsupportedReportType)
+    }
+
+    /// Returns a feature report requested by the host.
+    fn get_feature_report(&mut self, _report_id: u8) -> Result<&[u8], HidError> {
+        Err(HidError::UnsupportedReportType)
+    }
 
-    fn get_report(&mut self, report_type: ReportType, report_id: u8) -> Result<&[u8], ()>;
+    /// Length of a report, without report ID prefix, if it is known.
+    ///
+    /// Used to reassemble output reports that the host splits over several packets of the
+    /// interrupt OUT endpoint. When `None` (the default) every packet is taken to be a
+    /// complete report.
+    fn report_len(&self, _report_type: ReportType, _report_id: u8) -> Option<usize> {
+        None
+    }
+
+    /// Report IDs declared in the report descriptor, at most `MAX_REPORT_IDS` of them.
+    ///
+    /// Empty (the default) when reports are not prefixed with an ID; `set_report` and
+    /// `get_report` are then only called with report ID 0. Otherwise `HidClass` only routes
+    /// the declared IDs to the device, and adds or strips the ID prefix, so the data passed
+    /// to and returned from the device never includes it. Boot devices can drop their IDs in
+    /// boot protocol, which has none.
+    fn report_ids(&self) -> &[u8] {
+        &[]
+    }
+
+    /// Name of the interface in the language `lang_id`, served as its iInterface string.
+    ///
+    /// Whether the interface has a name is decided once, for US English, when the `HidClass`
+    /// is built. For other languages `None` falls back to the US English name.
+    fn interface_name(&self, _lang_id: u16) -> Option<&str> {
+        None
+    }
+
+    /// Number of strings referenced from the report descriptor, at most `MAX_REPORT_STRINGS`.
+    ///
+    /// `HidClass` allocates consecutive string indices for them, see
+    /// `HidClass::report_string_index`.
+    fn report_string_count(&self) -> usize {
+        0
+    }
+
+    /// Report string number `index` in the language `lang_id`, with the same fallback to US
+    /// English as `interface_name`.
+    fn report_string(&self, _index: usize, _lang_id: u16) -> Option<&str> {
+        None
+    }
+
+    /// Whether input reports carry relative values, like mouse motion. `HidClass` then sends
+    /// every report written, even if it equals the previous one, and never repeats one when
+    /// the idle duration expires.
+    fn relative_reports(&self) -> bool {
+        false
+    }
+
+    /// Called whenever the host switches between boot and report protocol, and with
+    /// `ReportProtocol::Report` on bus reset. Only boot interfaces can be switched.
+    fn set_protocol(&mut self, _protocol: ReportProtocol) {}
+}
+
+/// Idle state of one input report: the duration requested by the host, in units of 4 ms,
+/// and the last report sent so it can be repeated once that duration expires.
+#[derive(Clone, Copy)]
+struct IdleReport {
+    report_id: u8,
+    duration: u8,
+    /// Milliseconds since the report last went out, advanced by `HidClass::tick`.
+    elapsed_ms: u16,
+    data: [u8; MAX_REPORT_LEN],
+    len: usize,
+}
+
+impl IdleReport {
+    const fn new(report_id: u8, duration: u8) -> IdleReport {
+        IdleReport {
+            report_id,
+            duration,
+            elapsed_ms: 0,
+            data: [0; MAX_REPORT_LEN],
+            len: 0,
+        }
+    }
+
+    fn is_due(&self) -> bool {
+        // An idle duration of zero means "only report on change".
+        self.len > 0
+            && self.duration > 0
+            && self.elapsed_ms >= u16::from(self.duration) * IDLE_UNIT_MS
+    }
 }
 
 pub struct HidClass<'a, B: UsbBus, D: HidDevice> {
     device: D,
     interface: InterfaceNumber,
     endpoint_interrupt_in: EndpointIn<'a, B>,
+    endpoint_interrupt_out: Option<EndpointOut<'a, B>>,
+    interface_string: Option<StringIndex>,
+    report_strings: [Option<StringIndex>; MAX_REPORT_STRINGS],
     expect_interrupt_in_complete: bool,
+    /// Report being sent over the interrupt IN endpoint, and how many bytes of it were
+    /// already written.
+    in_flight: Option<(Report, usize)>,
+    /// Packets of an output report received so far on the interrupt OUT endpoint.
+    out_report: Report,
+    protocol: ReportProtocol,
+    /// Idle duration last set for all report IDs at once.
+    idle_default: u8,
+    /// One entry per report ID declared by the device, or a single one for report ID 0.
+    idle_reports: [IdleReport; MAX_REPORT_IDS],
+    idle_report_count: usize,
+    /// Reports waiting for the interrupt IN endpoint, oldest first.
+    queue: Deque<Report, REPORT_QUEUE_LEN>,
+    /// The most recent report accepted by `write`, used to coalesce duplicates.
+    last_written: Report,
+    dropped_reports: u32,
 }
 
-impl<B: UsbBus, D: HidDevice> HidClass<'_, B, D> {
-    pub fn new(device: D, alloc: &UsbBusAllocator<B>) -> HidClass<'_, B, D> {
-        HidClass {
-            device,
+/// Configures the endpoints of a `HidClass` before allocating them.
+pub struct HidClassBuilder<'a, B: UsbBus, D: HidDevice> {
+    device: D,
+    alloc: &'a UsbBusAllocator<B>,
+    max_packet_size: u16,
+    poll_interval_ms: u8,
+    interrupt_out: bool,
+}
+
+impl<'a, B: UsbBus, D: HidDevice> HidClassBuilder<'a, B, D> {
+    /// Sets wMaxPacketSize of the interrupt endpoints, 8 bytes by default.
+    ///
+    /// Reports longer than this are split over several packets.
+    pub fn max_packet_size(mut self, max_packet_size: u16) -> Self {
+        assert!(max_packet_size > 0 && max_packet_size <= MAX_PACKET_SIZE);
+        self.max_packet_size = max_packet_size;
+        self
+    }
+
+    /// Sets bInterval of the interrupt endpoints, 10 ms by default.
+    ///
+    /// At full speed this is the polling period in milliseconds, 1 for 1000 Hz reporting.
+    pub fn poll_interval(mut self, poll_interval_ms: u8) -> Self {
+        assert!(poll_interval_ms > 0);
+        self.poll_interval_ms = poll_interval_ms;
+        self
+    }
+
+    /// Also allocates an interrupt OUT endpoint, over which the host can send output reports
+    /// instead of using SET_REPORT on the control pipe.
+    pub fn interrupt_out(mut self, interrupt_out: bool) -> Self {
+        self.interrupt_out = interrupt_out;
+        self
+    }
+
+    pub fn build(self) -> HidClass<'a, B, D> {
+        let report_ids = self.device.report_ids();
+        assert!(report_ids.len() <= MAX_REPORT_IDS && !report_ids.contains(&0));
+        let report_string_count = self.device.report_string_count();
+        assert!(report_string_count <= MAX_REPORT_STRINGS);
+
+        let alloc = self.alloc;
+        let interface_string = self
+            .device
+            .interface_name(lang_id::ENGLISH_US)
+            .map(|_| alloc.string());
+        let mut report_strings = [None; MAX_REPORT_STRINGS];
+        for index in report_strings[..report_string_count].iter_mut() {
+            *index = Some(alloc.string());
+        }
+
+        let mut class = HidClass {
+            device: self.device,
             interface: alloc.interface(),
-            endpoint_interrupt_in: alloc.interrupt(8, 10),
+            endpoint_interrupt_in: alloc.interrupt(self.max_packet_size, self.poll_interval_ms),
+            endpoint_interrupt_out: if self.interrupt_out {
+                Some(alloc.interrupt(self.max_packet_size, self.poll_interval_ms))
+            } else {
+                None
+            },
+            interface_string,
+            report_strings,
             expect_interrupt_in_complete: false,
+            in_flight: None,
+            out_report: Report::new(),
+            protocol: ReportProtocol::Report,
+            idle_default: 0,
+            idle_reports: [IdleReport::new(0, 0); MAX_REPORT_IDS],
+            idle_report_count: 0,
+            queue: Deque::new(),
+            last_written: Report::new(),
+            dropped_reports: 0,
+        };
+        class.reset_idle();
+        class
+    }
+}
+
+impl<'a, B: UsbBus, D: HidDevice> HidClass<'a, B, D> {
+    /// Creates a class with 8 byte interrupt IN packets polled every 10 ms.
+    pub fn new(device: D, alloc: &UsbBusAllocator<B>) -> HidClass<'_, B, D> {
+        HidClass::builder(device, alloc).build()
+    }
+
+    /// Like `new`, but also allocates an interrupt OUT endpoint over which the host can send
+    /// output reports instead of using SET_REPORT on the control pipe.
+    pub fn new_with_out_endpoint(device: D, alloc: &UsbBusAllocator<B>) -> HidClass<'_, B, D> {
+        HidClass::builder(device, alloc).interrupt_out(true).build()
+    }
+
+    pub fn builder(device: D, alloc: &'a UsbBusAllocator<B>) -> HidClassBuilder<'a, B, D> {
+        HidClassBuilder {
+            device,
+            alloc,
+            max_packet_size: DEFAULT_MAX_PACKET_SIZE,
+            poll_interval_ms: DEFAULT_POLL_INTERVAL_MS,
+            interrupt_out: false,
         }
     }
 
-    pub fn write(&mut self, data: &[u8]) -> Result<usize, ()> {
-        if self.expect_interrupt_in_complete {
-            return Ok(0);
+    pub fn device(&self) -> &D {
+        &self.device
+    }
+
+    pub fn device_mut(&mut self) -> &mut D {
+        &mut self.device
+    }
+
+    /// Returns the protocol currently selected by the host.
+    pub fn protocol(&self) -> ReportProtocol {
+        self.protocol
+    }
+
+    /// Returns how many reports `write` had to discard because the queue was full.
+    pub fn dropped_reports(&self) -> u32 {
+        self.dropped_reports
+    }
+
+    /// Queues an input report for the interrupt IN endpoint.
+    ///
+    /// Reports are sent in order as the host polls the endpoint. A report identical to the
+    /// previously written one is coalesced with it, unless the device has relative reports.
+    /// Fails with `HidError::Busy`, counting the report as dropped, if `REPORT_QUEUE_LEN`
+    /// reports are already waiting.
+    pub fn write(&mut self, data: &[u8]) -> Result<usize, HidError> {
+        let report = Report::from_slice(data).map_err(|()| HidError::BadLength)?;
+        if report == self.last_written && !self.device.relative_reports() {
+            return Ok(data.len());
+        }
+
+        if self.queue.push_back(report.clone()).is_err() {
+            self.dropped_reports = self.dropped_reports.wrapping_add(1);
+            defmt::warn!("report queue full, {=u32} dropped", self.dropped_reports);
+            return Err(HidError::Busy);
         }
+        self.last_written = report;
+
+        self.flush()?;
+        Ok(data.len())
+    }
 
-        if data.len() >= 8 {
-            self.expect_interrupt_in_complete = true;
+    /// Writes the next packet of the report in flight, taking the oldest queued report if
+    /// there is none, unless the endpoint hasn't completed the previous packet yet.
+    ///
+    /// Reports ending on a packet boundary aren't followed by a zero length packet: hosts size
+    /// interrupt transfers after the report descriptor.
+    fn flush(&mut self) -> Result<(), HidError> {
+        if self.expect_interrupt_in_complete {
+            return Ok(());
         }
+        if self.in_flight.is_none() {
+            let report = match self.queue.pop_front() {
+                Some(report) => report,
+                None => return Ok(()),
+            };
+            self.remember_report(&report);
+            self.in_flight = Some((report, 0));
+        }
+        let (report, written) = match &mut self.in_flight {
+            Some(in_flight) => in_flight,
+            None => return Ok(()),
+        };
+
+        let max_packet_size = usize::from(self.endpoint_interrupt_in.max_packet_size());
+        let end = report.len().min(*written + max_packet_size);
 
         defmt::info!("writing");
 
-        match self.endpoint_interrupt_in.write(data) {
-            Ok(count) => Ok(count),
-            Err(UsbError::WouldBlock) => Ok(0),
-            Err(_) => Err(()),
+        match self.endpoint_interrupt_in.write(&report[*written..end]) {
+            Ok(count) => {
+                self.expect_interrupt_in_complete = true;
+                *written += count;
+                if *written >= report.len() {
+                    self.in_flight = None;
+                }
+                Ok(())
+            }
+            // Retried once the endpoint completes its current transfer.
+            Err(UsbError::WouldBlock) => Ok(()),
+            Err(err) => {
+                self.in_flight = None;
+                Err(err.into())
+            }
+        }
+    }
+
+    /// Writes an input report, prefixed with `report_id`.
+    ///
+    /// `report_id` must be one of the IDs declared by `HidDevice::report_ids`.
+    pub fn write_report(&mut self, report_id: u8, data: &[u8]) -> Result<usize, HidError> {
+        if report_id == 0 || !self.is_known_report_id(report_id) {
+            return Err(HidError::UnknownReportId);
+        }
+        if data.len() >= MAX_REPORT_LEN {
+            return Err(HidError::BadLength);
+        }
+
+        let mut report = [0; MAX_REPORT_LEN];
+        report[0] = report_id;
+        report[1..=data.len()].copy_from_slice(data);
+        self.write(&report[..=data.len()])
+    }
+
+    /// Writes the current input report of the device, see `HidDevice::input_report`. Like
+    /// `write_report` unless `report_id` is 0, for devices without report IDs.
+    pub fn write_device_report(&mut self, report_id: u8) -> Result<usize, HidError> {
+        if !self.is_known_report_id(report_id) {
+            return Err(HidError::UnknownReportId);
+        }
+        let report = self.device.input_report(report_id)?;
+        let report = Report::from_slice(report).map_err(|()| HidError::BadLength)?;
+        if report_id == 0 {
+            self.write(&report)
+        } else {
+            self.write_report(report_id, &report)
+        }
+    }
+
+    /// Advances the idle timers by one millisecond.
+    ///
+    /// Must be called at 1 kHz. Once the idle duration set by the host has passed without a
+    /// new report being written for a report ID, the last input report with that ID is sent
+    /// again, except for devices with relative reports.
+    pub fn tick(&mut self) {
+        for report in self.idle_reports[..self.idle_report_count].iter_mut() {
+            report.elapsed_ms = report.elapsed_ms.saturating_add(1);
+        }
+        // Queued reports take precedence over repeating old ones.
+        if self.flush().is_err()
+            || self.expect_interrupt_in_complete
+            || self.in_flight.is_some()
+            || !self.queue.is_empty()
+            || self.device.relative_reports()
+        {
+            return;
+        }
+
+        let idle_reports = &mut self.idle_reports[..self.idle_report_count];
+        if let Some(report) = idle_reports.iter_mut().find(|report| report.is_due()) {
+            report.elapsed_ms = 0;
+            if let Ok(data) = Report::from_slice(&report.data[..report.len]) {
+                self.in_flight = Some((data, 0));
+                self.flush().ok();
+            }
+        }
+    }
+
+    pub fn interface(&self) -> InterfaceNumber {
+        self.interface
+    }
+
+    /// Returns the string index allocated for report string number `index`, to be used in
+    /// String Index items of the report descriptor.
+    pub fn report_string_index(&self, index: usize) -> Option<StringIndex> {
+        self.report_strings.get(index).copied().flatten()
+    }
+
+    /// Checks that an interface request is addressed to this interface, which matters once
+    /// several `HidClass`es share a device.
+    fn is_own_interface(&self, req: &control::Request) -> bool {
+        req.recipient == Recipient::Interface && req.index == u16::from(u8::from(self.interface))
+    }
+
+    fn uses_report_ids(&self) -> bool {
+        !self.device.report_ids().is_empty()
+    }
+
+    /// Checks that `report_id` addresses a report of the device: one of the declared IDs, or
+    /// zero when the device doesn't use report IDs.
+    fn is_known_report_id(&self, report_id: u8) -> bool {
+        if self.uses_report_ids() {
+            self.device.report_ids().contains(&report_id)
+        } else {
+            report_id == 0
         }
     }
 
+    fn idle_report(&mut self, report_id: u8) -> Option<&mut IdleReport> {
+        self.idle_reports[..self.idle_report_count]
+            .iter_mut()
+            .find(|report| report.report_id == report_id)
+    }
+
+    fn remember_report(&mut self, data: &[u8]) {
+        let report_id = match data.first() {
+            Some(&report_id) if self.uses_report_ids() => report_id,
+            _ => 0,
+        };
+        if let Some(report) = self.idle_report(report_id) {
+            let len = data.len().min(MAX_REPORT_LEN);
+            report.data[..len].copy_from_slice(&data[..len]);
+            report.len = len;
+            report.elapsed_ms = 0;
+        }
+    }
+
+    fn reset_idle(&mut self) {
+        self.idle_default = default_idle(&self.device);
+        let report_ids = self.device.report_ids();
+        if report_ids.is_empty() {
+            self.idle_reports[0] = IdleReport::new(0, self.idle_default);
+            self.idle_report_count = 1;
+        } else {
+            for (report, &report_id) in self.idle_reports.iter_mut().zip(report_ids) {
+                *report = IdleReport::new(report_id, self.idle_default);
+            }
+            self.idle_report_count = report_ids.len();
+        }
+    }
+
+    /// Returns the idle duration for `report_id` in units of 4 ms.
+    fn idle_duration(&mut self, report_id: u8) -> Option<u8> {
+        if report_id == 0 {
+            return Some(self.idle_default);
+        }
+        self.idle_report(report_id).map(|report| report.duration)
+    }
+
+    fn set_idle_duration(&mut self, report_id: u8, duration: u8) -> Result<(), HidError> {
+        if report_id == 0 {
+            // Report ID 0 sets the idle rate of all input reports.
+            self.idle_default = duration;
+            for report in self.idle_reports[..self.idle_report_count].iter_mut() {
+                report.duration = duration;
+                report.elapsed_ms = 0;
+            }
+            return Ok(());
+        }
+
+        let report = self
+            .idle_report(report_id)
+            .ok_or(HidError::UnknownReportId)?;
+        report.duration = duration;
+        report.elapsed_ms = 0;
+        Ok(())
+    }
+
+    fn get_idle(&mut self, xfer: ControlIn<B>) {
+        let [_, report_id] = xfer.request().value.to_be_bytes();
+        match self.idle_duration(report_id) {
+            Some(duration) => {
+                defmt::info!("get idle {=u8}: {=u8}", report_id, duration);
+                xfer.accept_with(&[duration]).ok()
+            }
+            None => {
+                defmt::warn!("get idle: {:?}", HidError::UnknownReportId);
+                xfer.reject().ok()
+            }
+        };
+    }
+
+    fn set_idle(&mut self, xfer: ControlOut<B>) {
+        let [duration, report_id] = xfer.request().value.to_be_bytes();
+        defmt::info!("set idle {=u8}: {=u8}", report_id, duration);
+        match self.set_idle_duration(report_id, duration) {
+            Ok(()) => xfer.accept().ok(),
+            Err(err) => {
+                defmt::warn!("set idle: {:?}", err);
+                xfer.reject().ok()
+            }
+        };
+    }
+
+    fn get_protocol(&mut self, xfer: ControlIn<B>) {
+        xfer.accept_with(&[self.protocol as u8]).ok();
+    }
+
+    fn set_protocol(&mut self, xfer: ControlOut<B>) {
+        let protocol = ReportProtocol::new(xfer.request().value as u8);
+        match protocol {
+            Some(protocol) if self.device.subclass() == Subclass::BootInterface => {
+                defmt::info!("set protocol {:?}", protocol);
+                self.protocol = protocol;
+                self.device.set_protocol(protocol);
+                // The device may declare other report IDs in the new protocol
+                self.reset_idle();
+                xfer.accept().ok()
+            }
+            _ => {
+                defmt::warn!("set protocol: {:?}", HidError::Unsupported);
+                xfer.reject().ok()
+            }
+        };
+    }
+
     fn get_report(&mut self, xfer: ControlIn<B>) {
         defmt::info!("get reoprt");
         let req = xfer.request();
         let [report_type, report_id] = req.value.to_be_bytes();
         let report_type = ReportType::from(report_type);
-        match self.device.get_report(report_type, report_id) {
-            Ok(data) => xfer.accept_with(data).ok(),
-            Err(()) => xfer.reject().ok(),
+        if !self.is_known_report_id(report_id) {
+            defmt::warn!("report {=u8}: {:?}", report_id, HidError::UnknownReportId);
+            xfer.reject().ok();
+            return;
+        }
+
+        let prefix_len = usize::from(self.uses_report_ids());
+        let report = match report_type {
+            ReportType::Feature => self.device.get_feature_report(report_id),
+            _ => self.device.get_report(report_type, report_id),
+        };
+        match report {
+            Ok(data) => xfer
+                .accept(|buf| {
+                    let len = prefix_len + data.len();
+                    if len > buf.len() {
+                        return Err(UsbError::BufferOverflow);
+                    }
+                    if prefix_len > 0 {
+                        buf[0] = report_id;
+                    }
+                    buf[prefix_len..len].copy_from_slice(data);
+                    Ok(len)
+                })
+                .ok(),
+            Err(err) => {
+                defmt::warn!("get report {=u8}: {:?}", report_id, err);
+                xfer.reject().ok()
+            }
         };
     }
 
@@ -143,18 +735,50 @@ impl<B: UsbBus, D: HidDevice> HidClass<'_, B, D> {
         let req = xfer.request();
         let [report_type, report_id] = req.value.to_be_bytes();
         let report_type = ReportType::from(report_type);
-        match self.device.set_report(report_type, report_id, xfer.data()) {
+        if !self.is_known_report_id(report_id) {
+            defmt::warn!("report {=u8}: {:?}", report_id, HidError::UnknownReportId);
+            xfer.reject().ok();
+            return;
+        }
+
+        // Hosts send the report ID as the first data byte when the device uses report IDs.
+        let data = match xfer.data() {
+            [prefix, data @ ..] if self.uses_report_ids() && *prefix == report_id => data,
+            _ if self.uses_report_ids() => {
+                defmt::warn!("report {=u8}: data without its report ID", report_id);
+                xfer.reject().ok();
+                return;
+            }
+            data => data,
+        };
+        let result = match report_type {
+            ReportType::Feature => self.device.set_feature_report(report_id, data),
+            _ => self.device.set_report(report_type, report_id, data),
+        };
+        match result {
             Ok(()) => xfer.accept().ok(),
-            Err(()) => xfer.reject().ok(),
+            Err(err) => {
+                defmt::warn!("set report {=u8}: {:?}", report_id, err);
+                xfer.reject().ok()
+            }
         };
     }
 }
 
 impl<B: UsbBus, D: HidDevice> UsbClass<B> for HidClass<'_, B, D> {
-    fn poll(&mut self) {}
+    fn poll(&mut self) {
+        self.flush().ok();
+    }
 
     fn reset(&mut self) {
         self.expect_interrupt_in_complete = false;
+        self.in_flight = None;
+        self.out_report.clear();
+        self.queue.clear();
+        self.last_written.clear();
+        self.protocol = ReportProtocol::Report;
+        self.device.set_protocol(ReportProtocol::Report);
+        self.reset_idle();
     }
 
     fn get_configuration_descriptors(
@@ -162,11 +786,13 @@ impl<B: UsbBus, D: HidDevice> UsbClass<B> for HidClass<'_, B, D> {
         writer: &mut DescriptorWriter,
     ) -> usb_device::Result<()> {
         defmt::info!("get_configuration_descriptors");
-        writer.interface(
+        writer.interface_alt(
             self.interface,
+            0,
             INTERFACE_CLASS_HID,
             self.device.subclass() as u8,
             self.device.protocol() as u8,
+            self.interface_string,
         )?;
 
         let report_descriptor = self.device.report_descriptor();
@@ -190,24 +816,90 @@ impl<B: UsbBus, D: HidDevice> UsbClass<B> for HidClass<'_, B, D> {
         )?;
 
         writer.endpoint(&self.endpoint_interrupt_in)?;
+        if let Some(endpoint) = &self.endpoint_interrupt_out {
+            writer.endpoint(endpoint)?;
+        }
 
         Ok(())
     }
 
-    fn get_string(&self, _index: StringIndex, _lang_id: u16) -> Option<&str> {
-        None
+    fn get_string(&self, index: StringIndex, lang_id: u16) -> Option<&str> {
+        if Some(index) == self.interface_string {
+            return self
+                .device
+                .interface_name(lang_id)
+                .or_else(|| self.device.interface_name(lang_id::ENGLISH_US));
+        }
+
+        let report_string = self
+            .report_strings
+            .iter()
+            .position(|&report_index| report_index == Some(index))?;
+        self.device
+            .report_string(report_string, lang_id)
+            .or_else(|| {
+                self.device
+                    .report_string(report_string, lang_id::ENGLISH_US)
+            })
     }
 
     fn endpoint_in_complete(&mut self, addr: EndpointAddress) {
         if addr == self.endpoint_interrupt_in.address() {
             self.expect_interrupt_in_complete = false;
+            self.flush().ok();
         }
     }
 
-    fn endpoint_out(&mut self, _addr: EndpointAddress) {}
+    fn endpoint_out(&mut self, addr: EndpointAddress) {
+        let endpoint = match &self.endpoint_interrupt_out {
+            Some(endpoint) if endpoint.address() == addr => endpoint,
+            _ => return,
+        };
+
+        let mut packet = [0; MAX_PACKET_SIZE as usize];
+        let len = match endpoint.read(&mut packet) {
+            Ok(len) => len,
+            Err(_) => return,
+        };
+        let short_packet = len < usize::from(endpoint.max_packet_size());
+        if self.out_report.extend_from_slice(&packet[..len]).is_err() {
+            defmt::warn!("output report too long");
+            self.out_report.clear();
+            return;
+        }
+
+        // Output reports on the interrupt pipe always carry their report ID, if any.
+        let report = self.out_report.clone();
+        let (report_id, data) = match &report[..] {
+            [report_id, data @ ..] if self.uses_report_ids() => (*report_id, data),
+            data => (0, data),
+        };
+        if !self.is_known_report_id(report_id) {
+            defmt::warn!("output report with unknown ID {=u8}", report_id);
+            self.out_report.clear();
+            return;
+        }
+
+        // A report longer than a packet continues until its expected length or a short packet.
+        let complete = match self.device.report_len(ReportType::Output, report_id) {
+            Some(expected_len) => short_packet || data.len() >= expected_len,
+            None => true,
+        };
+        if !complete {
+            return;
+        }
+        self.out_report.clear();
+
+        if let Err(err) = self.device.set_report(ReportType::Output, report_id, data) {
+            defmt::warn!("output report {=u8}: {:?}", report_id, err);
+        }
+    }
 
     fn control_in(&mut self, xfer: ControlIn<B>) {
         let req = xfer.request();
+        if !self.is_own_interface(req) {
+            return;
+        }
         match (req.request_type, req.recipient) {
             (RequestType::Standard, Recipient::Interface) => {
                 if req.request == control::Request::GET_DESCRIPTOR {
@@ -218,26 +910,297 @@ impl<B: UsbBus, D: HidDevice> UsbClass<B> for HidClass<'_, B, D> {
                     }
                 }
             }
-            (RequestType::Class, Recipient::Interface) => {
-                if let Some(request) = Request::new(req.request) {
-                    if request == Request::GetReport {
-                        self.get_report(xfer);
-                    }
-                }
-            }
+            (RequestType::Class, Recipient::Interface) => match Request::new(req.request) {
+                Some(Request::GetReport) => self.get_report(xfer),
+                Some(Request::GetIdle) => self.get_idle(xfer),
+                Some(Request::GetProtocol) => self.get_protocol(xfer),
+                _ => (),
+            },
             _ => {}
         }
     }
 
     fn control_out(&mut self, xfer: ControlOut<B>) {
         let req = xfer.request();
-        if req.request_type == RequestType::Class && req.recipient == Recipient::Interface {
+        if req.request_type == RequestType::Class && self.is_own_interface(req) {
             if let Some(request) = Request::new(req.request) {
                 match request {
                     Request::SetReport => self.set_report(xfer),
+                    Request::SetIdle => self.set_idle(xfer),
+                    Request::SetProtocol => self.set_protocol(xfer),
                     _ => (),
                 }
             }
         }
     }
 }
+
+fn default_idle<D: HidDevice>(device: &D) -> u8 {
+    match device.protocol() {
+        Protocol::Keyboard => KEYBOARD_DEFAULT_IDLE,
+        _ => 0,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{HidClass, HidDevice, HidError, Protocol, ReportProtocol, ReportType, Subclass};
+    use crate::descriptor;
+    use crate::keyboard::{Keyboard, KEYBOARD_DESC, KEYBOARD_REPORT_ID};
+    use crate::mock_bus::{self, Host, MockBus, Stalled};
+    use usb_device::device::{UsbDevice, UsbDeviceState};
+
+    const GET_CONFIGURATION_DESCRIPTOR: [u8; 8] = [0x80, 0x06, 0x00, 0x02, 0, 0, 0xff, 0];
+    const GET_REPORT_DESCRIPTOR: [u8; 8] = [0x81, 0x06, 0x00, 0x22, 0, 0, 0xff, 0];
+    const GET_INPUT_REPORT: [u8; 8] = [0xa1, 0x01, 0x00, 0x01, 0, 0, 8, 0];
+    const GET_FEATURE_REPORT: [u8; 8] = [0xa1, 0x01, 0x00, 0x03, 0, 0, 8, 0];
+    const SET_OUTPUT_REPORT: [u8; 8] = [0x21, 0x09, 0x00, 0x02, 0, 0, 1, 0];
+    const SET_BOOT_PROTOCOL: [u8; 8] = [0x21, 0x0b, 0, 0, 0, 0, 0, 0];
+
+    type KeyboardClass = HidClass<'static, MockBus, Keyboard>;
+
+    fn keyboard() -> (Host, UsbDevice<'static, MockBus>, KeyboardClass) {
+        mock_bus::enumerated(|alloc| HidClass::new(Keyboard::new(), alloc))
+    }
+
+    #[test]
+    fn enumerates() {
+        let (host, usb, _) = keyboard();
+        assert_eq!(usb.state(), UsbDeviceState::Configured);
+        assert_eq!(host.address(), 1);
+    }
+
+    #[test]
+    fn describes_boot_keyboard_interface() {
+        let (host, mut usb, mut class) = keyboard();
+        let config = host
+            .control_in(&mut usb, &mut [&mut class], GET_CONFIGURATION_DESCRIPTOR)
+            .unwrap();
+
+        // Interface 0 with one endpoint, HID class, boot subclass, keyboard protocol
+        assert_eq!(config[9..17], [9, 0x04, 0, 0, 1, 0x03, 0x01, 0x01]);
+        let len = (KEYBOARD_DESC.len() as u16).to_le_bytes();
+        assert_eq!(
+            config[18..27],
+            [9, 0x21, 0x11, 0x01, 0, 1, 0x22, len[0], len[1]]
+        );
+        // Interrupt IN endpoint 1, 8 bytes every 10 ms
+        assert_eq!(config[27..34], [7, 0x05, 0x81, 0x03, 8, 0, 10]);
+    }
+
+    #[test]
+    fn returns_report_descriptor() {
+        let (host, mut usb, mut class) = keyboard();
+        let descriptor = host
+            .control_in(&mut usb, &mut [&mut class], GET_REPORT_DESCRIPTOR)
+            .unwrap();
+        assert_eq!(descriptor, KEYBOARD_DESC);
+    }
+
+    #[test]
+    fn get_report_returns_input_report() {
+        let (host, mut usb, mut class) = keyboard();
+        class.device_mut().update(0x02, [0x04, 0, 0, 0, 0, 0]);
+        let report = host
+            .control_in(&mut usb, &mut [&mut class], GET_INPUT_REPORT)
+            .unwrap();
+        assert_eq!(report, [0x02, 0x04, 0, 0, 0, 0, 0]);
+    }
+
+    #[test]
+    fn get_report_routes_feature_reports() {
+        let (host, mut usb, mut class) = keyboard();
+        let settings = host
+            .control_in(&mut usb, &mut [&mut class], GET_FEATURE_REPORT)
+            .unwrap();
+        assert_eq!(settings, class.device_mut().get_feature_report(0).unwrap());
+    }
+
+    #[test]
+    fn set_report_checks_output_report_length() {
+        let (host, mut usb, mut class) = keyboard();
+        assert_eq!(
+            host.control_out(&mut usb, &mut [&mut class], SET_OUTPUT_REPORT, &[0x02]),
+            Ok(())
+        );
+
+        let mut too_long = SET_OUTPUT_REPORT;
+        too_long[6] = 2;
+        assert_eq!(
+            host.control_out(&mut usb, &mut [&mut class], too_long, &[0x02, 0]),
+            Err(Stalled)
+        );
+    }
+
+    /// Takes one byte output reports with report ID 2.
+    struct Output {
+        data: Option<u8>,
+    }
+
+    impl HidDevice for Output {
+        fn subclass(&self) -> Subclass {
+            Subclass::None
+        }
+
+        fn protocol(&self) -> Protocol {
+            Protocol::None
+        }
+
+        fn report_descriptor(&self) -> &[u8] {
+            &[]
+        }
+
+        fn get_report(&mut self, _: ReportType, _: u8) -> Result<&[u8], HidError> {
+            Err(HidError::UnsupportedReportType)
+        }
+
+        fn set_report(&mut self, _: ReportType, _: u8, data: &[u8]) -> Result<(), HidError> {
+            match data {
+                [byte] => self.data = Some(*byte),
+                _ => return Err(HidError::BadLength),
+            }
+            Ok(())
+        }
+
+        fn report_ids(&self) -> &[u8] {
+            &[2]
+        }
+    }
+
+    #[test]
+    fn set_report_strips_the_report_id() {
+        let (host, mut usb, mut class) =
+            mock_bus::enumerated(|alloc| HidClass::new(Output { data: None }, alloc));
+
+        let set_report = [0x21, 0x09, 0x02, 0x02, 0, 0, 2, 0];
+        assert_eq!(
+            host.control_out(&mut usb, &mut [&mut class], set_report, &[2, 0x55]),
+            Ok(())
+        );
+        assert_eq!(class.device().data, Some(0x55));
+
+        // The data must start with the report ID of the request
+        assert_eq!(
+            host.control_out(&mut usb, &mut [&mut class], set_report, &[3, 0x66]),
+            Err(Stalled)
+        );
+        assert_eq!(class.device().data, Some(0x55));
+    }
+
+    #[test]
+    fn ignores_requests_for_other_interfaces() {
+        let (host, mut usb, mut class) = keyboard();
+        let mut other_interface = GET_INPUT_REPORT;
+        other_interface[4] = 1;
+        assert_eq!(
+            host.control_in(&mut usb, &mut [&mut class], other_interface),
+            Err(Stalled)
+        );
+    }
+
+    #[test]
+    fn set_protocol_switches_to_boot_reports() {
+        let (host, mut usb, mut class) = keyboard();
+        host.control_out(&mut usb, &mut [&mut class], SET_BOOT_PROTOCOL, &[])
+            .unwrap();
+        assert_eq!(class.protocol(), ReportProtocol::Boot);
+        assert_eq!(class.device().report().len(), 8);
+    }
+
+    #[test]
+    fn keyboard_shares_its_interface_with_consumer_control() {
+        let (host, mut usb, mut class) = mock_bus::enumerated(|alloc| {
+            HidClass::builder(Keyboard::new().with_consumer::<2>(), alloc)
+                .max_packet_size(16)
+                .build()
+        });
+        let descriptor = host
+            .control_in(&mut usb, &mut [&mut class], GET_REPORT_DESCRIPTOR)
+            .unwrap();
+        assert_eq!(descriptor, class.device().report_descriptor());
+        let layout = descriptor::parse(&descriptor).unwrap();
+        assert_eq!(
+            layout.report_ids().collect::<Vec<_>>(),
+            [KEYBOARD_REPORT_ID, 2]
+        );
+
+        class.device_mut().press(0x04);
+        let report_id = class.device().report_id();
+        let mut report = [0; 7];
+        report.copy_from_slice(class.device().report());
+        assert_eq!(class.write_report(report_id, &report), Ok(8));
+        assert_eq!(host.receive(1).unwrap(), [1, 0, 0x04, 0, 0, 0, 0, 0]);
+
+        class.device_mut().consumer_mut().unwrap().press(0xe2);
+        let mut report = [0; 8];
+        report.copy_from_slice(class.device_mut().consumer_mut().unwrap().report());
+        assert_eq!(class.write_report(2, &report), Ok(9));
+        host.poll(&mut usb, &mut [&mut class]);
+        assert_eq!(host.receive(1).unwrap(), [2, 0xe2, 0, 0, 0, 0, 0, 0, 0]);
+
+        let mut get_consumer_report = GET_INPUT_REPORT;
+        get_consumer_report[2] = 2;
+        get_consumer_report[6] = 9;
+        let consumer_report = host
+            .control_in(&mut usb, &mut [&mut class], get_consumer_report)
+            .unwrap();
+        assert_eq!(consumer_report, [2, 0xe2, 0, 0, 0, 0, 0, 0, 0]);
+
+        let mut set_leds = SET_OUTPUT_REPORT;
+        set_leds[2] = KEYBOARD_REPORT_ID;
+        set_leds[6] = 2;
+        assert_eq!(
+            host.control_out(&mut usb, &mut [&mut class], set_leds, &[1, 0x02]),
+            Ok(())
+        );
+        assert!(class.device().led_state().caps_lock());
+
+        // Boot protocol reports go without ID
+        host.control_out(&mut usb, &mut [&mut class], SET_BOOT_PROTOCOL, &[])
+            .unwrap();
+        assert_eq!(class.device().report_id(), 0);
+        let mut report = [0; 8];
+        report.copy_from_slice(class.device().report());
+        assert_eq!(class.write(&report), Ok(8));
+        host.poll(&mut usb, &mut [&mut class]);
+        assert_eq!(host.receive(1).unwrap(), [0, 0, 0x04, 0, 0, 0, 0, 0]);
+    }
+
+    #[test]
+    fn writes_the_device_report() {
+        let (host, _usb, mut class) = keyboard();
+        class.device_mut().press(0x04);
+        assert_eq!(class.write_device_report(0), Ok(7));
+        assert_eq!(host.receive(1).unwrap(), [0, 0x04, 0, 0, 0, 0, 0]);
+        assert_eq!(class.write_device_report(1), Err(HidError::UnknownReportId));
+    }
+
+    #[test]
+    fn queues_reports_while_endpoint_is_busy() {
+        let (host, mut usb, mut class) = keyboard();
+        let pressed = [0, 0x04, 0, 0, 0, 0, 0];
+        let released = [0; 7];
+        assert_eq!(class.write(&pressed), Ok(7));
+        assert_eq!(class.write(&released), Ok(7));
+
+        assert_eq!(host.receive(1).unwrap(), pressed);
+        assert_eq!(host.receive(1), None);
+        host.poll(&mut usb, &mut [&mut class]);
+        assert_eq!(host.receive(1).unwrap(), released);
+    }
+
+    #[test]
+    fn splits_reports_longer_than_a_packet() {
+        let (host, mut usb, mut class) = mock_bus::enumerated(|alloc| {
+            HidClass::builder(Keyboard::new(), alloc)
+                .max_packet_size(4)
+                .build()
+        });
+
+        let report = [0, 0x04, 0x05, 0x06, 0, 0, 0];
+        class.write(&report).unwrap();
+        assert_eq!(host.receive(1).unwrap(), report[..4]);
+        host.poll(&mut usb, &mut [&mut class]);
+        assert_eq!(host.receive(1).unwrap(), report[4..]);
+    }
+}
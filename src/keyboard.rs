@@ -1,4 +1,7 @@
-use crate::hid::{HidDevice, Protocol, ReportType, Subclass};
+use crate::consumer::{consumer_collection, ConsumerControl};
+use crate::descriptor::{generic_desktop, Collection, DescriptorBuilder, ItemFlags, UsagePage};
+use crate::hid::{HidDevice, HidError, Protocol, ReportProtocol, ReportType, Subclass};
+use crate::leds::{LedSink, LedState};
 use usbd_hid::descriptor::generator_prelude::*;
 
 /// KeyboardReport describes a report and its companion descriptor that can be
@@ -61,7 +64,55 @@ const REPORT_DESCRIPTOR: &[u8] = &[
     0xC0,             // End Collection
 ];
 
-// Note: not actually used. Soarer's NRKO descriptor is *actually* sent.
+/// Report protocol layout of `KeyboardReport`, plus a vendor-defined feature report carrying
+/// the `KeyboardSettings`. With a report ID unless `report_id` is 0.
+const fn keyboard_collection(builder: DescriptorBuilder, report_id: u8) -> DescriptorBuilder {
+    builder
+        .usage_page(UsagePage::GenericDesktop)
+        .usage(generic_desktop::KEYBOARD)
+        .collection(Collection::Application)
+        .optional_report_id(report_id)
+        // Modifiers
+        .usage_page(UsagePage::Keyboard)
+        .usage_minimum(0xe0)
+        .usage_maximum(0xe7)
+        .logical_minimum(0)
+        .logical_maximum(1)
+        .report_size(1)
+        .report_count(8)
+        .input(ItemFlags::DATA_VARIABLE)
+        // LEDs
+        .usage_page(UsagePage::Leds)
+        .usage_minimum(0x01)
+        .usage_maximum(0x05)
+        .report_count(5)
+        .output(ItemFlags::DATA_VARIABLE)
+        .report_count(3)
+        .output(ItemFlags::CONSTANT)
+        // Keycodes
+        .usage_page(UsagePage::Keyboard)
+        .usage_minimum(0x00)
+        .usage_maximum(ARRAY_LAST_KEYCODE as u16)
+        .logical_maximum(ARRAY_LAST_KEYCODE as i32)
+        .report_size(8)
+        .report_count(6)
+        .input(ItemFlags::DATA_ARRAY)
+        // Debounce time, active layer and LED mode
+        .usage_page(UsagePage::Vendor(0xff00))
+        .usage(0x01)
+        .usage(0x02)
+        .usage(0x03)
+        .logical_maximum(0xff)
+        .report_count(3)
+        .feature(ItemFlags::DATA_VARIABLE)
+        .end_collection()
+}
+
+const KEYBOARD: DescriptorBuilder = keyboard_collection(DescriptorBuilder::new(), 0);
+pub const KEYBOARD_DESC: &[u8] = &KEYBOARD.finish::<{ KEYBOARD.len() }>();
+
+// Layout of the fixed 8-byte report hosts expect in boot protocol, see HID 1.11 appendix B.1.
+// Never sent to the host: boot protocol hosts don't parse report descriptors.
 #[allow(dead_code)]
 #[rustfmt::skip]
 pub const BOOT_DESC: &[u8] = &[
@@ -99,105 +150,562 @@ pub const BOOT_DESC: &[u8] = &[
     0xc0              // END_COLLECTION
 ];
 
+/// Last keycode of the six-key array, Keyboard Application. The boot keyboard stops there too.
+const ARRAY_LAST_KEYCODE: u8 = 0x65;
+
 const USB_FIRST_KEY_BIT: u8 = 1;
 const USB_LAST_KEY_BIT: u8 = 0xA4;
 const USB_NUM_KEY_BITS: u8 = USB_LAST_KEY_BIT - USB_FIRST_KEY_BIT + 1;
 const USB_NUM_KEY_BIT_BYTES: u8 = (USB_NUM_KEY_BITS + 7) / 8;
-const USB_NUM_PADDING_KEY_BITS: u8 = 8 * USB_NUM_KEY_BIT_BYTES - USB_NUM_KEY_BITS;
 
 // Main key bitfield + 1 byte for modifiers + 1 media byte + 6 boot desc bytes
 pub const USB_REPORT_SIZE: u8 = USB_NUM_KEY_BIT_BYTES + 8;
 
-// Report descriptor by Soarer on geekhack
-#[rustfmt::skip]
-pub const SOARER_DESC: &[u8] = &[
-    0x05, 0x01,          // Usage Page (Generic Desktop),
-    0x09, 0x06,          // Usage (Keyboard),
-    0xA1, 0x01,          // Collection (Application),
-
-    // modifier byte
-    0x75, 0x01,          //   Report Size (1),
-    0x95, 0x08,          //   Report Count (8),
-    0x05, 0x07,          //   Usage Page (Key Codes),
-    0x19, 0xE0,          //   Usage Minimum (224),
-    0x29, 0xE7,          //   Usage Maximum (231),
-    0x15, 0x00,          //   Logical Minimum (0),
-    0x25, 0x01,          //   Logical Maximum (1),
-    0x81, 0x02,          //   Input (Data, Variable, Absolute), ;Modifier byte
-    // 0xC0,                 // End Collection
-
-    // // Media controls (constant in boot desc)
-    // 0x05, 0x0C,        // Usage Page (Consumer)
-    // 0x09, 0x01,        // Usage (Consumer Control)
-    // 0xA1, 0x01,        // Collection (Application)
-    0x05, 0x0C,        //   Usage Page (Consumer)
-    0x15, 0x00,        //   Logical Minimum (0)
-    0x25, 0x01,        //   Logical Maximum (1)
-    0x75, 0x01,        //   Report Size (1)
-    0x95, 0x08,        //   Report Count (8)
-    0x09, 0xB5,        //   Usage (Scan Next Track)
-    0x09, 0xB6,        //   Usage (Scan Previous Track)
-    0x09, 0xB7,        //   Usage (Stop)
-    0x09, 0xB8,        //   Usage (Eject)
-    0x09, 0xCD,        //   Usage (Play/Pause)
-    0x09, 0xE2,        //   Usage (Mute)
-    0x09, 0xE9,        //   Usage (Volume Increment)
-    0x09, 0xEA,        //   Usage (Volume Decrement)
-    0x81, 0x02,        //   Input (Data,Var,Abs,No Wrap,Linear,Preferred State,No Null Position)
-    // 0xC0,                 // End Collection
-
-    // 0x05, 0x01,          // Usage Page (Generic Desktop),
-    // 0x09, 0x06,          // Usage (Keyboard),
-    // 0xA1, 0x01,          // Collection (Application),
-
-    // LEDs
-    0x95, 0x05,          //   Report Count (5),
-    0x75, 0x01,          //   Report Size (1),
-    0x05, 0x08,          //   Usage Page (LEDs),
-    0x19, 0x01,          //   Usage Minimum (1),
-    0x29, 0x05,          //   Usage Maximum (5),
-    0x91, 0x02,          //   Output (Data, Variable, Absolute), ;LED report
-
-    // LED padding
-    0x95, 0x01,          //   Report Count (1),
-    0x75, 0x03,          //   Report Size (3),
-    0x91, 0x03,          //   Output (Constant),                 ;LED report padding
-
-    // Boot Desc bytes
-    0x95, 0x06,                     //   Report Count (6),
-    0x75, 0x08,						//   Report Size (8),
-    0x81, 0x03,						//   Input (Constant),                 ;Padding
-
-    // Keys
-    0x75, 0x01,					//   Report Size (1),
-    0x95, USB_NUM_KEY_BITS,		//   Report Count (),
-    0x05, 0x07,					//   Usage Page (Key Codes),
-    0x19, USB_FIRST_KEY_BIT,    //   Usage Minimum (),
-    0x29, USB_LAST_KEY_BIT,     //   Usage Maximum (),
-    0x15, 0x00,					//   Logical Minimum (0),
-    0x25, 0x01,					//   Logical Maximum (1),
-    0x81, 0x02,					//   Input (Data, Variable, Absolute), ;keys bit array
-
-    // Might not be needed if USB_NUM_PADDING_KEY_BITS is 0, check the math
-    0x95, USB_NUM_PADDING_KEY_BITS, //   Report Count (4),
-    0x75, 0x01,						//   Report Size (1),
-    0x81, 0x03,						//   Input (Constant),                 ;Padding
-
-    0xC0,                 // End Collection
-];
+/// Report descriptor by Soarer on geekhack, up to the end of its collection. With a report ID
+/// unless `report_id` is 0.
+const fn soarer_reports(builder: DescriptorBuilder, report_id: u8) -> DescriptorBuilder {
+    builder
+        .usage_page(UsagePage::GenericDesktop)
+        .usage(generic_desktop::KEYBOARD)
+        .collection(Collection::Application)
+        .optional_report_id(report_id)
+        // Modifier byte
+        .report_size(1)
+        .report_count(8)
+        .usage_page(UsagePage::Keyboard)
+        .usage_minimum(0xe0)
+        .usage_maximum(0xe7)
+        .logical_minimum(0)
+        .logical_maximum(1)
+        .input(ItemFlags::DATA_VARIABLE)
+        // Media controls (constant in boot desc)
+        .usage_page(UsagePage::Consumer)
+        .logical_minimum(0)
+        .logical_maximum(1)
+        .report_size(1)
+        .report_count(8)
+        .usage(0xb5) // Scan Next Track
+        .usage(0xb6) // Scan Previous Track
+        .usage(0xb7) // Stop
+        .usage(0xb8) // Eject
+        .usage(0xcd) // Play/Pause
+        .usage(0xe2) // Mute
+        .usage(0xe9) // Volume Increment
+        .usage(0xea) // Volume Decrement
+        .input(ItemFlags::DATA_VARIABLE)
+        // LEDs
+        .report_count(5)
+        .report_size(1)
+        .usage_page(UsagePage::Leds)
+        .usage_minimum(1)
+        .usage_maximum(5)
+        .output(ItemFlags::DATA_VARIABLE)
+        .report_count(1)
+        .report_size(3)
+        .output(ItemFlags::CONSTANT)
+        // Boot desc bytes
+        .report_count(6)
+        .report_size(8)
+        .input(ItemFlags::CONSTANT)
+        // Keys bit array
+        .report_size(1)
+        .report_count(USB_NUM_KEY_BITS as u32)
+        .usage_page(UsagePage::Keyboard)
+        .usage_minimum(USB_FIRST_KEY_BIT as u16)
+        .usage_maximum(USB_LAST_KEY_BIT as u16)
+        .logical_minimum(0)
+        .logical_maximum(1)
+        .input(ItemFlags::DATA_VARIABLE)
+        .pad_input()
+}
+
+const SOARER: DescriptorBuilder = soarer_reports(DescriptorBuilder::new(), 0).end_collection();
+pub const SOARER_DESC: &[u8] = &SOARER.finish::<{ SOARER.len() }>();
+
+/// The NKRO layout of `SOARER_DESC`, plus the `KeyboardSettings` feature report.
+const fn nkro_collection(builder: DescriptorBuilder, report_id: u8) -> DescriptorBuilder {
+    soarer_reports(builder, report_id)
+        .usage_page(UsagePage::Vendor(0xff00))
+        .usage(0x01)
+        .usage(0x02)
+        .usage(0x03)
+        .logical_maximum(0xff)
+        .report_size(8)
+        .report_count(3)
+        .feature(ItemFlags::DATA_VARIABLE)
+        .end_collection()
+}
+
+const NKRO: DescriptorBuilder = nkro_collection(DescriptorBuilder::new(), 0);
+pub const NKRO_DESC: &[u8] = &NKRO.finish::<{ NKRO.len() }>();
+
+/// Report ID of the keyboard reports once a consumer control shares the interface, see
+/// `Keyboard::with_consumer`.
+pub const KEYBOARD_REPORT_ID: u8 = 1;
+
+// Descriptor lengths with a consumer control, which don't depend on its report ID
+const KEYBOARD_WITH_CONSUMER_LEN: usize = consumer_collection(
+    keyboard_collection(DescriptorBuilder::new(), KEYBOARD_REPORT_ID),
+    KEYBOARD_REPORT_ID + 1,
+)
+.len();
+const NKRO_WITH_CONSUMER_LEN: usize = consumer_collection(
+    nkro_collection(DescriptorBuilder::new(), KEYBOARD_REPORT_ID),
+    KEYBOARD_REPORT_ID + 1,
+)
+.len();
+
+/// The report descriptors with a consumer control of reports `ID`, see `Keyboard::with_consumer`.
+struct WithConsumer<const ID: u8>;
+
+impl<const ID: u8> WithConsumer<ID> {
+    const KEYBOARD_DESC: [u8; KEYBOARD_WITH_CONSUMER_LEN] = {
+        assert!(ID != KEYBOARD_REPORT_ID, "report ID taken by the keyboard");
+        consumer_collection(
+            keyboard_collection(DescriptorBuilder::new(), KEYBOARD_REPORT_ID),
+            ID,
+        )
+        .finish::<KEYBOARD_WITH_CONSUMER_LEN>()
+    };
+    const NKRO_DESC: [u8; NKRO_WITH_CONSUMER_LEN] = {
+        assert!(ID != KEYBOARD_REPORT_ID, "report ID taken by the keyboard");
+        consumer_collection(
+            nkro_collection(DescriptorBuilder::new(), KEYBOARD_REPORT_ID),
+            ID,
+        )
+        .finish::<NKRO_WITH_CONSUMER_LEN>()
+    };
+}
+
+/// Size of an input report in boot protocol: modifiers, a reserved byte and six keycodes.
+const BOOT_REPORT_SIZE: usize = 8;
+
+/// Size of an input report in report protocol: modifiers and six keycodes, as laid out by
+/// `KEYBOARD_DESC`.
+const REPORT_REPORT_SIZE: usize = 7;
+
+/// Size of an input report in NKRO mode, as laid out by `NKRO_DESC`.
+const NKRO_REPORT_SIZE: usize = USB_REPORT_SIZE as usize;
+
+/// Keycodes of the modifier keys, left control to right GUI, reported as the bits of the
+/// modifier byte rather than as keys.
+const MODIFIER_KEYCODES: core::ops::RangeInclusive<u8> = 0xe0..=0xe7;
+
+/// Keycode reported in every array slot when more keys are pressed than the report holds.
+const ERROR_ROLL_OVER: u8 = 0x01;
+
+/// Size of the settings feature report: debounce time, active layer and LED mode.
+const SETTINGS_REPORT_SIZE: usize = 3;
+
+/// Longest debounce time the host may configure.
+pub const MAX_DEBOUNCE_MS: u8 = 50;
+
+/// What the keyboard LEDs show.
+#[derive(Debug, defmt::Format, Clone, Copy, PartialEq)]
+pub enum LedMode {
+    /// LEDs stay off.
+    Off = 0,
+    /// LEDs follow the lock state reported by the host.
+    Indicators = 1,
+    /// LEDs stay on.
+    On = 2,
+}
+
+impl LedMode {
+    fn new(value: u8) -> Option<LedMode> {
+        match value {
+            0 => Some(LedMode::Off),
+            1 => Some(LedMode::Indicators),
+            2 => Some(LedMode::On),
+            _ => None,
+        }
+    }
+}
+
+/// How many keys the keyboard reports at once.
+#[derive(Debug, defmt::Format, Clone, Copy, PartialEq)]
+pub enum KeyboardMode {
+    /// Up to six keys, in the boot keyboard layout of `KEYBOARD_DESC`.
+    SixKey,
+    /// Any number of keys, in the bitfield of `NKRO_DESC`. The first eight bytes stay a valid
+    /// boot report, for hosts that never select a protocol.
+    Nkro,
+}
+
+/// Device settings the host reads and writes through the feature report.
+#[derive(Debug, defmt::Format, Clone, Copy, PartialEq)]
+pub struct KeyboardSettings {
+    pub debounce_ms: u8,
+    pub layer: u8,
+    pub led_mode: LedMode,
+}
+
+impl KeyboardSettings {
+    pub const fn new() -> KeyboardSettings {
+        KeyboardSettings {
+            debounce_ms: 5,
+            layer: 0,
+            led_mode: LedMode::Indicators,
+        }
+    }
+
+    fn to_bytes(self) -> [u8; SETTINGS_REPORT_SIZE] {
+        [self.debounce_ms, self.layer, self.led_mode as u8]
+    }
+
+    fn from_bytes(data: &[u8], layer_count: u8) -> Result<KeyboardSettings, HidError> {
+        if data.len() != SETTINGS_REPORT_SIZE {
+            return Err(HidError::BadLength);
+        }
+        if data[0] > MAX_DEBOUNCE_MS || data[1] >= layer_count {
+            return Err(HidError::BadValue);
+        }
+        let led_mode = LedMode::new(data[2]).ok_or(HidError::BadValue)?;
+        Ok(KeyboardSettings {
+            debounce_ms: data[0],
+            layer: data[1],
+            led_mode,
+        })
+    }
+}
+
+impl Default for KeyboardSettings {
+    fn default() -> Self {
+        Self::new()
+    }
+}
 
-pub struct Keyboard {
-    report: [u8; 8],
+/// A keyboard, showing the LEDs the host sets on `L`.
+pub struct Keyboard<L = ()> {
+    name: &'static str,
+    mode: KeyboardMode,
+    protocol: ReportProtocol,
+    modifier: u8,
+    media: u8,
+    /// Bitmap of the pressed keycodes.
+    keys: [u8; 32],
+    report: [u8; NKRO_REPORT_SIZE],
+    report_len: usize,
+    settings: KeyboardSettings,
+    settings_report: [u8; SETTINGS_REPORT_SIZE],
+    settings_changed: bool,
+    /// Number of layers the host may select as the active layer.
+    layer_count: u8,
+    leds: L,
+    led_state: LedState,
+    /// Consumer control sharing the interface, with report ID 0 when there is none.
+    consumer: ConsumerControl,
+    /// Keyboard and consumer control report IDs, used in report protocol with a consumer.
+    report_ids: [u8; 2],
+    /// Report descriptors with a consumer control, in six-key and NKRO mode.
+    consumer_descriptors: [&'static [u8]; 2],
 }
 impl Keyboard {
     pub fn new() -> Keyboard {
-        Keyboard { report: [0; 8] }
+        Keyboard {
+            name: "Keyboard",
+            mode: KeyboardMode::SixKey,
+            protocol: ReportProtocol::Report,
+            modifier: 0,
+            media: 0,
+            keys: [0; 32],
+            report: [0; NKRO_REPORT_SIZE],
+            report_len: REPORT_REPORT_SIZE,
+            settings: KeyboardSettings::new(),
+            settings_report: [0; SETTINGS_REPORT_SIZE],
+            settings_changed: false,
+            layer_count: 1,
+            leds: (),
+            led_state: LedState::default(),
+            consumer: ConsumerControl::new(),
+            report_ids: [0; 2],
+            consumer_descriptors: [&[]; 2],
+        }
+    }
+}
+
+impl Default for Keyboard {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<L: LedSink> Keyboard<L> {
+    /// Shows the LEDs the host sets on `leds`.
+    pub fn with_leds<M: LedSink>(self, leds: M) -> Keyboard<M> {
+        Keyboard {
+            name: self.name,
+            mode: self.mode,
+            protocol: self.protocol,
+            modifier: self.modifier,
+            media: self.media,
+            keys: self.keys,
+            report: self.report,
+            report_len: self.report_len,
+            settings: self.settings,
+            settings_report: self.settings_report,
+            settings_changed: self.settings_changed,
+            layer_count: self.layer_count,
+            leds,
+            led_state: self.led_state,
+            consumer: self.consumer,
+            report_ids: self.report_ids,
+            consumer_descriptors: self.consumer_descriptors,
+        }
+    }
+
+    /// Returns the LED state the host last wrote, all off until it writes one.
+    pub fn led_state(&self) -> LedState {
+        self.led_state
+    }
+
+    /// Shows the host LED state on the LEDs, as the LED mode of the settings allows.
+    fn show_leds(&mut self) {
+        let leds = match self.settings.led_mode {
+            LedMode::Off => LedState::default(),
+            LedMode::Indicators => self.led_state,
+            LedMode::On => LedState::from_bits(0xff),
+        };
+        self.leds.set_leds(leds);
+    }
+
+    /// Gives access to the LEDs, for instance to turn them off while the bus is suspended.
+    pub fn leds_mut(&mut self) -> &mut L {
+        &mut self.leds
+    }
+
+    /// Sets the interface name shown by the host, "Keyboard" by default.
+    pub fn with_name(mut self, name: &'static str) -> Self {
+        self.name = name;
+        self
+    }
+
+    /// Selects six-key rollover or NKRO, six-key by default. NKRO reports are 29 bytes long,
+    /// build the `HidClass` with a max packet size of 32 to send them in a single packet.
+    pub fn with_mode(mut self, mode: KeyboardMode) -> Self {
+        self.mode = mode;
+        self.refresh_report();
+        self
+    }
+
+    /// Adds a consumer control with reports `ID` to the interface, for keyboards that can't
+    /// spare an interface for it. `ID` must be neither 0 nor `KEYBOARD_REPORT_ID`.
+    ///
+    /// In report protocol the keyboard reports then carry `KEYBOARD_REPORT_ID`, see
+    /// `report_id`. Boot protocol has no report IDs and no consumer control. Consumer reports
+    /// with their ID are 9 bytes long, build the `HidClass` with a max packet size of 16 to send
+    /// them in a single packet.
+    pub fn with_consumer<const ID: u8>(mut self) -> Self {
+        self.consumer = ConsumerControl::new().with_report_id::<ID>();
+        self.report_ids = [KEYBOARD_REPORT_ID, ID];
+        self.consumer_descriptors = [
+            &WithConsumer::<ID>::KEYBOARD_DESC,
+            &WithConsumer::<ID>::NKRO_DESC,
+        ];
+        self
+    }
+
+    /// Returns the consumer control added by `with_consumer`. Write its reports with
+    /// `HidClass::write_report`, unless the host selected boot protocol.
+    pub fn consumer_mut(&mut self) -> Option<&mut ConsumerControl> {
+        if self.has_consumer() {
+            Some(&mut self.consumer)
+        } else {
+            None
+        }
+    }
+
+    /// Returns the ID of the keyboard reports, 0 when they have none. Write them with
+    /// `HidClass::write_report` otherwise.
+    pub fn report_id(&self) -> u8 {
+        if self.report_ids().is_empty() {
+            0
+        } else {
+            KEYBOARD_REPORT_ID
+        }
+    }
+
+    fn has_consumer(&self) -> bool {
+        self.consumer.report_id() != 0
+    }
+
+    /// Returns whether `report_id` addresses the reports of the consumer control.
+    fn is_consumer_report(&self, report_id: u8) -> bool {
+        self.has_consumer() && self.consumer.report_id() == report_id
+    }
+
+    pub fn mode(&self) -> KeyboardMode {
+        self.mode
+    }
+
+    /// Sets the settings reported to the host until it writes new ones.
+    pub fn with_settings(mut self, settings: KeyboardSettings) -> Self {
+        self.settings = settings;
+        self
+    }
+
+    /// Sets how many layers the keymap has, 1 by default. The host can't select another active
+    /// layer than those.
+    pub fn with_layer_count(mut self, layer_count: u8) -> Self {
+        assert!(layer_count > 0, "a keymap has at least one layer");
+        self.layer_count = layer_count;
+        self
+    }
+
+    pub fn settings(&self) -> KeyboardSettings {
+        self.settings
+    }
+
+    /// Returns whether the host wrote new settings since the last call.
+    pub fn take_settings_changed(&mut self) -> bool {
+        core::mem::replace(&mut self.settings_changed, false)
+    }
+
+    /// Replaces the pressed modifiers and keys, returning whether the report changed.
+    pub fn update(&mut self, modifier: u8, keycodes: [u8; 6]) -> bool {
+        self.update_keys(modifier, &keycodes)
+    }
+
+    /// Replaces the pressed modifiers and any number of keys, returning whether the report
+    /// changed. Keycode 0 and keycodes past the descriptor are ignored, and modifier keycodes
+    /// are added to `modifier`. In six-key mode, more than six keys report ErrorRollOver.
+    pub fn update_keys(&mut self, mut modifier: u8, keycodes: &[u8]) -> bool {
+        let mut keys = [0; 32];
+        for &keycode in keycodes.iter() {
+            if MODIFIER_KEYCODES.contains(&keycode) {
+                modifier |= modifier_bit(keycode);
+            } else if self.reports_key(keycode) {
+                keys[usize::from(keycode / 8)] |= 1 << (keycode % 8);
+            }
+        }
+        if self.modifier == modifier && self.keys == keys {
+            return false;
+        }
+        self.modifier = modifier;
+        self.keys = keys;
+        self.refresh_report()
+    }
+
+    /// Adds a key to the report, returning whether the report changed. Modifier keycodes set
+    /// their bit in the modifier byte, keycodes past the descriptor are ignored.
+    pub fn press(&mut self, keycode: u8) -> bool {
+        self.set_pressed(keycode, true)
+    }
+
+    /// Removes a key from the report, returning whether the report changed.
+    pub fn release(&mut self, keycode: u8) -> bool {
+        self.set_pressed(keycode, false)
+    }
+
+    /// Replaces the modifier byte, returning whether the report changed.
+    pub fn set_modifiers(&mut self, modifier: u8) -> bool {
+        if self.modifier == modifier {
+            return false;
+        }
+        self.modifier = modifier;
+        self.refresh_report()
+    }
+
+    /// Releases all keys and modifiers, returning whether the report changed.
+    pub fn clear(&mut self) -> bool {
+        self.update_keys(0, &[])
+    }
+
+    fn set_pressed(&mut self, keycode: u8, pressed: bool) -> bool {
+        let (byte, bit) = if MODIFIER_KEYCODES.contains(&keycode) {
+            (&mut self.modifier, modifier_bit(keycode))
+        } else if self.reports_key(keycode) {
+            (&mut self.keys[usize::from(keycode / 8)], 1 << (keycode % 8))
+        } else {
+            return false;
+        };
+        let old = *byte;
+        if pressed {
+            *byte |= bit;
+        } else {
+            *byte &= !bit;
+        }
+        if *byte == old {
+            return false;
+        }
+        self.refresh_report()
+    }
+
+    /// Returns whether the descriptor of the current mode declares a key.
+    fn reports_key(&self, keycode: u8) -> bool {
+        let last = match self.mode {
+            KeyboardMode::SixKey => ARRAY_LAST_KEYCODE,
+            KeyboardMode::Nkro => USB_LAST_KEY_BIT,
+        };
+        keycode != 0 && keycode <= last
+    }
+
+    /// Sets the media byte of `SOARER_DESC`, bit 0 to 7: next track, previous track, stop,
+    /// eject, play/pause, mute, volume up and volume down. Only reported in NKRO mode.
+    pub fn set_media(&mut self, media: u8) -> bool {
+        if self.media == media {
+            return false;
+        }
+        self.media = media;
+        self.refresh_report()
+    }
+
+    /// Returns the input report in the layout of the protocol selected by the host.
+    pub fn report(&self) -> &[u8] {
+        &self.report[..self.report_len]
+    }
+
+    /// Rebuilds the report from the key state, returning whether it changed. It doesn't when
+    /// keys change during roll over, or outside of the report of the current mode.
+    fn refresh_report(&mut self) -> bool {
+        let old = (self.report, self.report_len);
+        self.report = [0; NKRO_REPORT_SIZE];
+        self.report[0] = self.modifier;
+
+        // The keycode array follows the modifiers in the six-key report, the reserved byte in the
+        // boot report and the media byte in the NKRO report
+        let (array_start, report_len) = match (self.protocol, self.mode) {
+            (ReportProtocol::Boot, _) => (2, BOOT_REPORT_SIZE),
+            (ReportProtocol::Report, KeyboardMode::SixKey) => (1, REPORT_REPORT_SIZE),
+            (ReportProtocol::Report, KeyboardMode::Nkro) => (2, NKRO_REPORT_SIZE),
+        };
+        self.report_len = report_len;
+        let keys = self.keys;
+        let array = &mut self.report[array_start..array_start + 6];
+        let pressed = (1..=ARRAY_LAST_KEYCODE).filter(|&keycode| is_pressed(&keys, keycode));
+        for (i, keycode) in pressed.enumerate() {
+            if i == array.len() {
+                array.iter_mut().for_each(|b| *b = ERROR_ROLL_OVER);
+                break;
+            }
+            array[i] = keycode;
+        }
+
+        if report_len == NKRO_REPORT_SIZE {
+            self.report[1] = self.media;
+            let bitfield = &mut self.report[BOOT_REPORT_SIZE..];
+            for keycode in USB_FIRST_KEY_BIT..=USB_LAST_KEY_BIT {
+                if is_pressed(&keys, keycode) {
+                    let bit = usize::from(keycode - USB_FIRST_KEY_BIT);
+                    bitfield[bit / 8] |= 1 << (bit % 8);
+                }
+            }
+        }
+        (self.report, self.report_len) != old
     }
 }
 
-impl HidDevice for Keyboard {
+fn modifier_bit(keycode: u8) -> u8 {
+    1 << (keycode - MODIFIER_KEYCODES.start())
+}
+
+fn is_pressed(keys: &[u8; 32], keycode: u8) -> bool {
+    keys[usize::from(keycode / 8)] & 1 << (keycode % 8) != 0
+}
+
+impl<L: LedSink> HidDevice for Keyboard<L> {
     fn subclass(&self) -> Subclass {
-        Subclass::None
+        Subclass::BootInterface
     }
 
     fn protocol(&self) -> Protocol {
@@ -205,28 +713,547 @@ impl HidDevice for Keyboard {
     }
 
     fn report_descriptor(&self) -> &[u8] {
-        KeyboardReport::desc()
-        // BOOT_DESC
-        // SOARER_DESC
+        match (self.has_consumer(), self.mode) {
+            (true, KeyboardMode::SixKey) => self.consumer_descriptors[0],
+            (true, KeyboardMode::Nkro) => self.consumer_descriptors[1],
+            (false, KeyboardMode::SixKey) => KEYBOARD_DESC,
+            (false, KeyboardMode::Nkro) => NKRO_DESC,
+        }
     }
 
-    fn get_report(&mut self, report_type: ReportType, _report_id: u8) -> Result<&[u8], ()> {
+    fn get_report(&mut self, report_type: ReportType, report_id: u8) -> Result<&[u8], HidError> {
+        if self.is_consumer_report(report_id) {
+            return self.consumer.get_report(report_type, report_id);
+        }
         match report_type {
-            ReportType::Input => Ok(&self.report),
-            _ => Err(()),
+            ReportType::Input => Ok(self.report()),
+            _ => Err(HidError::UnsupportedReportType),
         }
     }
 
+    fn input_report(&self, report_id: u8) -> Result<&[u8], HidError> {
+        if self.is_consumer_report(report_id) {
+            return self.consumer.input_report(report_id);
+        }
+        Ok(self.report())
+    }
+
     fn set_report(
         &mut self,
         report_type: ReportType,
         report_id: u8,
         data: &[u8],
-    ) -> Result<(), ()> {
-        if report_type == ReportType::Output && report_id == 0 && data.len() == 1 {
-            defmt::info!("report {:?}, data {:?}", report_type, data);
-            return Ok(());
+    ) -> Result<(), HidError> {
+        if self.is_consumer_report(report_id) {
+            return self.consumer.set_report(report_type, report_id, data);
+        }
+        if report_type != ReportType::Output {
+            return Err(HidError::UnsupportedReportType);
+        }
+        if report_id != self.report_id() {
+            return Err(HidError::UnknownReportId);
+        }
+        if data.len() != 1 {
+            return Err(HidError::BadLength);
+        }
+        self.led_state = LedState::from_bits(data[0]);
+        self.show_leds();
+        Ok(())
+    }
+
+    fn get_feature_report(&mut self, report_id: u8) -> Result<&[u8], HidError> {
+        if self.is_consumer_report(report_id) {
+            return self.consumer.get_feature_report(report_id);
+        }
+        if report_id != self.report_id() {
+            return Err(HidError::UnknownReportId);
+        }
+        self.settings_report = self.settings.to_bytes();
+        Ok(&self.settings_report)
+    }
+
+    fn set_feature_report(&mut self, report_id: u8, data: &[u8]) -> Result<(), HidError> {
+        if self.is_consumer_report(report_id) {
+            return self.consumer.set_feature_report(report_id, data);
+        }
+        if report_id != self.report_id() {
+            return Err(HidError::UnknownReportId);
+        }
+        let settings = KeyboardSettings::from_bytes(data, self.layer_count)?;
+        defmt::info!("settings {:?}", settings);
+        if settings != self.settings {
+            let led_mode_changed = settings.led_mode != self.settings.led_mode;
+            self.settings = settings;
+            self.settings_changed = true;
+            if led_mode_changed {
+                self.show_leds();
+            }
+        }
+        Ok(())
+    }
+
+    fn report_len(&self, report_type: ReportType, report_id: u8) -> Option<usize> {
+        if self.is_consumer_report(report_id) {
+            return self.consumer.report_len(report_type, report_id);
         }
-        Err(())
+        match report_type {
+            ReportType::Input => Some(self.report().len()),
+            ReportType::Output => Some(1),
+            ReportType::Feature => Some(SETTINGS_REPORT_SIZE),
+            _ => None,
+        }
+    }
+
+    fn report_ids(&self) -> &[u8] {
+        match (self.has_consumer(), self.protocol) {
+            (true, ReportProtocol::Report) => &self.report_ids,
+            _ => &[],
+        }
+    }
+
+    fn interface_name(&self, _lang_id: u16) -> Option<&str> {
+        Some(self.name)
+    }
+
+    fn set_protocol(&mut self, protocol: ReportProtocol) {
+        self.protocol = protocol;
+        self.refresh_report();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        Keyboard, KeyboardMode, KeyboardReport, KeyboardSettings, LedMode, BOOT_DESC,
+        KEYBOARD_DESC, KEYBOARD_REPORT_ID, NKRO_DESC, REPORT_DESCRIPTOR, SOARER_DESC,
+        USB_FIRST_KEY_BIT, USB_LAST_KEY_BIT, USB_NUM_KEY_BITS, USB_REPORT_SIZE,
+    };
+    use crate::descriptor;
+    use crate::hid::{HidDevice, HidError, ReportProtocol, ReportType};
+    use crate::leds::{LedSink, LedState};
+    use usbd_hid::descriptor::SerializedDescriptor;
+
+    fn report_lens(descriptor: &[u8]) -> (Option<usize>, Option<usize>, Option<usize>) {
+        let layout = descriptor::parse(descriptor).unwrap();
+        assert!(!layout.uses_report_ids());
+        (
+            layout.report_len(ReportType::Input, 0),
+            layout.report_len(ReportType::Output, 0),
+            layout.report_len(ReportType::Feature, 0),
+        )
+    }
+
+    #[test]
+    fn descriptors_declare_their_reports() {
+        assert_eq!(report_lens(KEYBOARD_DESC), (Some(7), Some(1), Some(3)));
+        assert_eq!(
+            report_lens(KeyboardReport::desc()),
+            (Some(7), Some(1), None)
+        );
+        assert_eq!(report_lens(REPORT_DESCRIPTOR), (Some(8), Some(1), None));
+        assert_eq!(report_lens(BOOT_DESC), (Some(8), Some(1), None));
+        assert_eq!(
+            report_lens(SOARER_DESC),
+            (Some(usize::from(USB_REPORT_SIZE)), Some(1), None)
+        );
+        assert_eq!(
+            report_lens(NKRO_DESC),
+            (Some(usize::from(USB_REPORT_SIZE)), Some(1), Some(3))
+        );
+    }
+
+    #[rustfmt::skip]
+    const KEYBOARD_DESC_BYTES: &[u8] = &[
+        0x05, 0x01,       // Usage Page (Generic Desktop Ctrls)
+        0x09, 0x06,       // Usage (Keyboard)
+        0xA1, 0x01,       // Collection (Application)
+        0x05, 0x07,       //   Usage Page (Kbrd/Keypad)
+        0x19, 0xE0,       //   Usage Minimum (0xE0)
+        0x29, 0xE7,       //   Usage Maximum (0xE7)
+        0x15, 0x00,       //   Logical Minimum (0)
+        0x25, 0x01,       //   Logical Maximum (1)
+        0x75, 0x01,       //   Report Size (1)
+        0x95, 0x08,       //   Report Count (8)
+        0x81, 0x02,       //   Input (Data,Var,Abs)
+        0x05, 0x08,       //   Usage Page (LEDs)
+        0x19, 0x01,       //   Usage Minimum (Num Lock)
+        0x29, 0x05,       //   Usage Maximum (Kana)
+        0x95, 0x05,       //   Report Count (5)
+        0x91, 0x02,       //   Output (Data,Var,Abs)
+        0x95, 0x03,       //   Report Count (3)
+        0x91, 0x03,       //   Output (Const,Var,Abs)
+        0x05, 0x07,       //   Usage Page (Kbrd/Keypad)
+        0x19, 0x00,       //   Usage Minimum (0x00)
+        0x29, 0x65,       //   Usage Maximum (0x65)
+        0x25, 0x65,       //   Logical Maximum (101)
+        0x75, 0x08,       //   Report Size (8)
+        0x95, 0x06,       //   Report Count (6)
+        0x81, 0x00,       //   Input (Data,Array,Abs)
+        0x06, 0x00, 0xFF, //   Usage Page (Vendor Defined 0xFF00)
+        0x09, 0x01,       //   Usage (Debounce time)
+        0x09, 0x02,       //   Usage (Active layer)
+        0x09, 0x03,       //   Usage (LED mode)
+        0x26, 0xFF, 0x00, //   Logical Maximum (255)
+        0x95, 0x03,       //   Report Count (3)
+        0xB1, 0x02,       //   Feature (Data,Var,Abs)
+        0xC0,             // End Collection
+    ];
+
+    #[rustfmt::skip]
+    const SOARER_DESC_BYTES: &[u8] = &[
+        0x05, 0x01,              // Usage Page (Generic Desktop),
+        0x09, 0x06,              // Usage (Keyboard),
+        0xA1, 0x01,              // Collection (Application),
+
+        // modifier byte
+        0x75, 0x01,              //   Report Size (1),
+        0x95, 0x08,              //   Report Count (8),
+        0x05, 0x07,              //   Usage Page (Key Codes),
+        0x19, 0xE0,              //   Usage Minimum (224),
+        0x29, 0xE7,              //   Usage Maximum (231),
+        0x15, 0x00,              //   Logical Minimum (0),
+        0x25, 0x01,              //   Logical Maximum (1),
+        0x81, 0x02,              //   Input (Data, Variable, Absolute), ;Modifier byte
+        // 0xC0,                 // End Collection
+
+        // // Media controls (constant in boot desc)
+        // 0x05, 0x0C,        // Usage Page (Consumer)
+        // 0x09, 0x01,        // Usage (Consumer Control)
+        // 0xA1, 0x01,        // Collection (Application)
+        0x05, 0x0C,              //   Usage Page (Consumer)
+        0x15, 0x00,              //   Logical Minimum (0)
+        0x25, 0x01,              //   Logical Maximum (1)
+        0x75, 0x01,              //   Report Size (1)
+        0x95, 0x08,              //   Report Count (8)
+        0x09, 0xB5,              //   Usage (Scan Next Track)
+        0x09, 0xB6,              //   Usage (Scan Previous Track)
+        0x09, 0xB7,              //   Usage (Stop)
+        0x09, 0xB8,              //   Usage (Eject)
+        0x09, 0xCD,              //   Usage (Play/Pause)
+        0x09, 0xE2,              //   Usage (Mute)
+        0x09, 0xE9,              //   Usage (Volume Increment)
+        0x09, 0xEA,              //   Usage (Volume Decrement)
+        0x81, 0x02,              //   Input (Data,Var,Abs,No Wrap,Linear,Preferred State,No Null Position)
+        // 0xC0,                 // End Collection
+
+        // 0x05, 0x01,          // Usage Page (Generic Desktop),
+        // 0x09, 0x06,          // Usage (Keyboard),
+        // 0xA1, 0x01,          // Collection (Application),
+
+        // LEDs
+        0x95, 0x05,              //   Report Count (5),
+        0x75, 0x01,              //   Report Size (1),
+        0x05, 0x08,              //   Usage Page (LEDs),
+        0x19, 0x01,              //   Usage Minimum (1),
+        0x29, 0x05,              //   Usage Maximum (5),
+        0x91, 0x02,              //   Output (Data, Variable, Absolute), ;LED report
+
+        // LED padding
+        0x95, 0x01,              //   Report Count (1),
+        0x75, 0x03,              //   Report Size (3),
+        0x91, 0x03,              //   Output (Constant),                 ;LED report padding
+
+        // Boot Desc bytes
+        0x95, 0x06,              //   Report Count (6),
+        0x75, 0x08,              //   Report Size (8),
+        0x81, 0x03,              //   Input (Constant),                 ;Padding
+
+        // Keys
+        0x75, 0x01,              //   Report Size (1),
+        0x95, USB_NUM_KEY_BITS,  //   Report Count (),
+        0x05, 0x07,              //   Usage Page (Key Codes),
+        0x19, USB_FIRST_KEY_BIT, //   Usage Minimum (),
+        0x29, USB_LAST_KEY_BIT,  //   Usage Maximum (),
+        0x15, 0x00,              //   Logical Minimum (0),
+        0x25, 0x01,              //   Logical Maximum (1),
+        0x81, 0x02,              //   Input (Data, Variable, Absolute), ;keys bit array
+
+        0x95, 0x04,              //   Report Count (4),
+        0x75, 0x01,              //   Report Size (1),
+        0x81, 0x03,              //   Input (Constant),                 ;Padding
+
+        0xC0,                    // End Collection
+    ];
+
+    #[test]
+    fn built_descriptors_match_hand_written_bytes() {
+        assert_eq!(KEYBOARD_DESC, KEYBOARD_DESC_BYTES);
+        assert_eq!(SOARER_DESC, SOARER_DESC_BYTES);
+    }
+
+    #[test]
+    fn reports_match_descriptor() {
+        let mut keyboard = Keyboard::new();
+        assert!(descriptor::check_device(&mut keyboard).is_ok());
+        let mut keyboard = Keyboard::new().with_mode(KeyboardMode::Nkro);
+        assert!(descriptor::check_device(&mut keyboard).is_ok());
+    }
+
+    #[test]
+    fn six_key_mode_reports_roll_over() {
+        let mut keyboard = Keyboard::new();
+        keyboard.update_keys(0, &[0x04, 0x05, 0x06, 0x07, 0x08, 0x09, 0x0a]);
+        assert_eq!(keyboard.report(), [0, 1, 1, 1, 1, 1, 1]);
+    }
+
+    #[test]
+    fn nkro_mode_reports_every_key() {
+        let mut keyboard = Keyboard::new().with_mode(KeyboardMode::Nkro);
+        keyboard.update_keys(0x02, &[0x04, 0x05, 0x06, 0x07, 0x08, 0x09, 0x0a, 0xa4]);
+        keyboard.set_media(0x20);
+
+        let report = keyboard.report();
+        assert_eq!(report.len(), usize::from(USB_REPORT_SIZE));
+        // Modifiers, media, then the boot keycodes overflowing into ErrorRollOver
+        assert_eq!(report[..8], [0x02, 0x20, 1, 1, 1, 1, 1, 1]);
+        // Keycode 0x04 is bit 3 of the bitfield, 0xa4 its last bit
+        assert_eq!(report[8], 0b1111_1000);
+        assert_eq!(report[9], 0b0000_0011);
+        assert_eq!(report[28], 0b0000_1000);
+    }
+
+    #[test]
+    fn nkro_mode_sends_boot_reports_in_boot_protocol() {
+        let mut keyboard = Keyboard::new().with_mode(KeyboardMode::Nkro);
+        keyboard.update_keys(0x02, &[0x05, 0x04]);
+        keyboard.set_media(0x20);
+        keyboard.set_protocol(ReportProtocol::Boot);
+        assert_eq!(keyboard.report(), [0x02, 0, 0x04, 0x05, 0, 0, 0, 0]);
+    }
+
+    #[test]
+    fn press_and_release_maintain_the_report() {
+        let mut keyboard = Keyboard::new();
+        assert!(keyboard.press(0x05));
+        assert!(keyboard.press(0x04));
+        assert!(!keyboard.press(0x04));
+        assert_eq!(keyboard.report(), [0, 0x04, 0x05, 0, 0, 0, 0]);
+
+        // Left shift and right GUI
+        assert!(keyboard.press(0xe1));
+        assert!(keyboard.press(0xe7));
+        assert_eq!(keyboard.report(), [0x82, 0x04, 0x05, 0, 0, 0, 0]);
+        assert!(keyboard.release(0xe7));
+        assert!(!keyboard.set_modifiers(0x02));
+
+        assert!(keyboard.release(0x04));
+        assert!(!keyboard.release(0x04));
+        assert!(!keyboard.press(0));
+        assert_eq!(keyboard.report(), [0x02, 0x05, 0, 0, 0, 0, 0]);
+
+        assert!(keyboard.clear());
+        assert!(!keyboard.clear());
+        assert_eq!(keyboard.report(), [0; 7]);
+    }
+
+    #[test]
+    fn keys_past_the_descriptor_are_ignored() {
+        let mut keyboard = Keyboard::new();
+        assert!(!keyboard.press(0x66));
+        assert!(!keyboard.update_keys(0, &[0x66, 0xa4]));
+        assert_eq!(keyboard.report(), [0; 7]);
+
+        // NKRO reports them in the bitfield only, the array is that of the boot keyboard
+        let mut keyboard = Keyboard::new().with_mode(KeyboardMode::Nkro);
+        assert!(keyboard.press(0xa4));
+        assert!(!keyboard.press(0xa5));
+        assert_eq!(keyboard.report()[..8], [0; 8]);
+        assert_eq!(keyboard.report()[28], 0b0000_1000);
+    }
+
+    #[test]
+    fn seventh_key_rolls_over_until_released() {
+        let mut keyboard = Keyboard::new();
+        for keycode in 0x04..0x0a {
+            keyboard.press(keycode);
+        }
+        assert_eq!(keyboard.report(), [0, 0x04, 0x05, 0x06, 0x07, 0x08, 0x09]);
+        assert!(keyboard.press(0x0a));
+        assert_eq!(keyboard.report(), [0, 1, 1, 1, 1, 1, 1]);
+        // Keys changing during roll over leave the report as it is
+        assert!(!keyboard.press(0x0b));
+        assert!(!keyboard.release(0x0b));
+        // Modifiers are still reported during roll over
+        assert!(keyboard.press(0xe0));
+        assert_eq!(keyboard.report(), [0x01, 1, 1, 1, 1, 1, 1]);
+        assert!(keyboard.release(0x06));
+        assert_eq!(
+            keyboard.report(),
+            [0x01, 0x04, 0x05, 0x07, 0x08, 0x09, 0x0a]
+        );
+    }
+
+    #[test]
+    fn update_reports_changes_only() {
+        let mut keyboard = Keyboard::new();
+        assert!(keyboard.update(0, [0x04, 0, 0, 0, 0, 0]));
+        assert!(!keyboard.update(0, [0x04, 0, 0, 0, 0, 0]));
+        assert!(keyboard.update(0x02, [0x04, 0, 0, 0, 0, 0]));
+    }
+
+    #[test]
+    fn report_layout_follows_protocol() {
+        let mut keyboard = Keyboard::new();
+        keyboard.update(0x02, [0x04, 0x05, 0, 0, 0, 0]);
+        assert_eq!(keyboard.report(), [0x02, 0x04, 0x05, 0, 0, 0, 0]);
+
+        keyboard.set_protocol(ReportProtocol::Boot);
+        assert_eq!(keyboard.report(), [0x02, 0, 0x04, 0x05, 0, 0, 0, 0]);
+        assert_eq!(keyboard.report_len(ReportType::Input, 0), Some(8));
+    }
+
+    #[test]
+    fn consumer_shares_the_interface() {
+        for &mode in [KeyboardMode::SixKey, KeyboardMode::Nkro].iter() {
+            let mut keyboard = Keyboard::new().with_consumer::<3>().with_mode(mode);
+            assert_eq!(keyboard.report_ids(), [KEYBOARD_REPORT_ID, 3]);
+            assert_eq!(keyboard.report_id(), KEYBOARD_REPORT_ID);
+            let layout = descriptor::check_device(&mut keyboard).unwrap();
+            assert_eq!(
+                layout.report_ids().collect::<Vec<_>>(),
+                [KEYBOARD_REPORT_ID, 3]
+            );
+            let len = keyboard.report().len();
+            assert_eq!(layout.report_len(ReportType::Input, 1), Some(len));
+            assert_eq!(layout.report_len(ReportType::Output, 1), Some(1));
+            assert_eq!(layout.report_len(ReportType::Input, 3), Some(8));
+        }
+
+        let mut keyboard = Keyboard::new().with_consumer::<3>();
+        assert!(keyboard.consumer_mut().unwrap().press(0xcd));
+        assert_eq!(
+            keyboard.get_report(ReportType::Input, 3),
+            Ok(&[0xcd, 0, 0, 0, 0, 0, 0, 0][..])
+        );
+        assert_eq!(
+            keyboard.set_report(ReportType::Output, 3, &[0x02]),
+            Err(HidError::UnsupportedReportType)
+        );
+        assert_eq!(
+            keyboard.set_report(ReportType::Output, 0, &[0x02]),
+            Err(HidError::UnknownReportId)
+        );
+        assert_eq!(keyboard.set_report(ReportType::Output, 1, &[0x02]), Ok(()));
+        assert!(keyboard.led_state().caps_lock());
+
+        // Boot protocol has no report IDs
+        keyboard.set_protocol(ReportProtocol::Boot);
+        assert!(keyboard.report_ids().is_empty());
+        assert_eq!(keyboard.report_id(), 0);
+        assert_eq!(keyboard.set_report(ReportType::Output, 0, &[0]), Ok(()));
+    }
+
+    #[test]
+    fn output_report_is_one_byte() {
+        let mut keyboard = Keyboard::new();
+        assert_eq!(keyboard.set_report(ReportType::Output, 0, &[0x02]), Ok(()));
+        assert_eq!(
+            keyboard.set_report(ReportType::Output, 0, &[0x02, 0]),
+            Err(HidError::BadLength)
+        );
+        assert_eq!(
+            keyboard.set_report(ReportType::Input, 0, &[0x02]),
+            Err(HidError::UnsupportedReportType)
+        );
+    }
+
+    #[test]
+    fn output_report_sets_leds() {
+        struct Sink(Option<LedState>);
+        impl LedSink for Sink {
+            fn set_leds(&mut self, leds: LedState) {
+                self.0 = Some(leds);
+            }
+        }
+
+        let mut keyboard = Keyboard::new().with_leds(Sink(None));
+        assert_eq!(keyboard.led_state(), LedState::default());
+        assert_eq!(keyboard.leds_mut().0, None);
+
+        let report = LedState::NUM_LOCK | LedState::SCROLL_LOCK | LedState::COMPOSE;
+        assert_eq!(
+            keyboard.set_report(ReportType::Output, 0, &[report]),
+            Ok(())
+        );
+        assert!(keyboard.led_state().num_lock());
+        assert!(!keyboard.led_state().caps_lock());
+        assert_eq!(keyboard.leds_mut().0, Some(LedState::from_bits(report)));
+
+        // A rejected report leaves the LEDs alone
+        let _ = keyboard.set_report(ReportType::Output, 0, &[0, 0]);
+        assert_eq!(keyboard.led_state().bits(), report);
+    }
+
+    #[test]
+    fn led_mode_overrides_host_leds() {
+        struct Sink(Option<LedState>);
+        impl LedSink for Sink {
+            fn set_leds(&mut self, leds: LedState) {
+                self.0 = Some(leds);
+            }
+        }
+
+        let mut keyboard = Keyboard::new().with_leds(Sink(None));
+        let caps_lock = LedState::from_bits(LedState::CAPS_LOCK);
+        assert_eq!(keyboard.set_feature_report(0, &[5, 0, 0]), Ok(()));
+        assert_eq!(keyboard.leds_mut().0, Some(LedState::default()));
+        assert_eq!(
+            keyboard.set_report(ReportType::Output, 0, &[caps_lock.bits()]),
+            Ok(())
+        );
+        assert_eq!(keyboard.leds_mut().0, Some(LedState::default()));
+        // The host state is still tracked, to show once the mode allows it
+        assert_eq!(keyboard.led_state(), caps_lock);
+
+        assert_eq!(keyboard.set_feature_report(0, &[5, 0, 2]), Ok(()));
+        assert_eq!(keyboard.leds_mut().0, Some(LedState::from_bits(0xff)));
+        assert_eq!(keyboard.set_report(ReportType::Output, 0, &[0]), Ok(()));
+        assert_eq!(keyboard.leds_mut().0, Some(LedState::from_bits(0xff)));
+
+        assert_eq!(
+            keyboard.set_report(ReportType::Output, 0, &[caps_lock.bits()]),
+            Ok(())
+        );
+        assert_eq!(keyboard.set_feature_report(0, &[5, 0, 1]), Ok(()));
+        assert_eq!(keyboard.leds_mut().0, Some(caps_lock));
+    }
+
+    #[test]
+    fn feature_report_round_trips_settings() {
+        let mut keyboard = Keyboard::new().with_layer_count(2);
+        assert_eq!(keyboard.set_feature_report(0, &[10, 1, 2]), Ok(()));
+        assert!(keyboard.take_settings_changed());
+        assert!(!keyboard.take_settings_changed());
+        assert_eq!(
+            keyboard.settings(),
+            KeyboardSettings {
+                debounce_ms: 10,
+                layer: 1,
+                led_mode: LedMode::On,
+            }
+        );
+        assert_eq!(keyboard.get_feature_report(0), Ok(&[10, 1, 2][..]));
+    }
+
+    #[test]
+    fn feature_report_rejects_bad_settings() {
+        let mut keyboard = Keyboard::new().with_layer_count(2);
+        assert_eq!(
+            keyboard.set_feature_report(0, &[100, 0, 1]),
+            Err(HidError::BadValue)
+        );
+        assert_eq!(
+            keyboard.set_feature_report(0, &[5, 2, 1]),
+            Err(HidError::BadValue)
+        );
+        assert_eq!(
+            keyboard.set_feature_report(0, &[5, 0, 3]),
+            Err(HidError::BadValue)
+        );
+        assert_eq!(
+            keyboard.set_feature_report(0, &[5, 0]),
+            Err(HidError::BadLength)
+        );
+        assert_eq!(keyboard.settings(), KeyboardSettings::new());
     }
 }
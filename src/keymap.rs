@@ -0,0 +1,916 @@
+//! The keymap VIA edits: a copy of the compiled keyberon layers in RAM.
+//!
+//! `Keymap` runs a keyberon `Layout` over the copy, so hold-tap, chords and the other actions
+//! keep working while VIA remaps keys. VIA reads and writes QMK keycodes, see `keycode`, which
+//! are only translated to and from actions at that boundary. Compiled actions without a keycode
+//! read as `keycode::COMPILED`, writing it back restores them. Keycodes without an action, like
+//! modified keys or tap-hold, are rejected, as are those of devices the board lacks.
+//!
+//! The macros VIA edits are only stored.
+
+use crate::consumer::usage;
+use crate::gamepad::{GamepadKey, NUM_BUTTONS};
+use crate::mouse::{MouseButton, MouseKey};
+use crate::system::SystemKey;
+use core::ptr::NonNull;
+use heapless::Vec;
+use keyberon::action::Action;
+use keyberon::key_code::KeyCode;
+use keyberon::layout::{self, Event, Layers, Layout};
+
+/// QMK keycodes, in the numbering of VIA protocol version 9.
+pub mod keycode {
+    pub const NO: u16 = 0x0000;
+    /// Falls through to the default layer.
+    pub const TRANSPARENT: u16 = 0x0001;
+
+    /// First and last keycodes of the keyboard page, in their HID Usage ID.
+    pub const BASIC_FIRST: u16 = 0x0004;
+    pub const BASIC_LAST: u16 = 0x00e7;
+
+    pub const SYSTEM_POWER: u16 = 0x00a5;
+    pub const SYSTEM_SLEEP: u16 = 0x00a6;
+    pub const SYSTEM_WAKE: u16 = 0x00a7;
+    /// First of the consumer keys, from mute to brightness down.
+    pub const AUDIO_MUTE: u16 = 0x00a8;
+    pub const BRIGHTNESS_DOWN: u16 = 0x00be;
+
+    pub const MS_UP: u16 = 0x00f0;
+    pub const MS_DOWN: u16 = 0x00f1;
+    pub const MS_LEFT: u16 = 0x00f2;
+    pub const MS_RIGHT: u16 = 0x00f3;
+    /// Buttons 1 to 5 follow.
+    pub const MS_BTN1: u16 = 0x00f4;
+    pub const MS_WH_UP: u16 = 0x00f9;
+    pub const MS_WH_DOWN: u16 = 0x00fa;
+    pub const MS_WH_LEFT: u16 = 0x00fb;
+    pub const MS_WH_RIGHT: u16 = 0x00fc;
+
+    const MOMENTARY: u16 = 0x5100;
+    const DEFAULT_LAYER: u16 = 0x5200;
+
+    /// Activates `layer` while held, `MO(layer)` in QMK.
+    pub const fn momentary(layer: u8) -> u16 {
+        MOMENTARY | layer as u16 & 0x1f
+    }
+
+    /// Makes `layer` the default layer, `DF(layer)` in QMK.
+    pub const fn default_layer(layer: u8) -> u16 {
+        DEFAULT_LAYER | layer as u16 & 0x1f
+    }
+
+    /// Restarts the firmware on release.
+    pub const RESET: u16 = 0x5c00;
+
+    /// First keycode left to the keyboard, `USER00` in VIA definitions. The gamepad buttons
+    /// take `USER00` to `USER31` and its directions up, down, left and right the next four.
+    pub const USER00: u16 = 0x5f80;
+
+    /// A compiled action without keycode, like a hold-tap. Setting it restores that action.
+    pub const COMPILED: u16 = 0x5fff;
+}
+
+const CONSUMER_USAGES: [u16; (keycode::BRIGHTNESS_DOWN - keycode::AUDIO_MUTE + 1) as usize] = [
+    usage::MUTE,
+    usage::VOLUME_INCREMENT,
+    usage::VOLUME_DECREMENT,
+    usage::SCAN_NEXT_TRACK,
+    usage::SCAN_PREVIOUS_TRACK,
+    usage::STOP,
+    usage::PLAY_PAUSE,
+    usage::AL_MEDIA_PLAYER,
+    usage::EJECT,
+    usage::AL_EMAIL,
+    usage::AL_CALCULATOR,
+    usage::AL_FILE_BROWSER,
+    usage::AC_SEARCH,
+    usage::AC_HOME,
+    usage::AC_BACK,
+    usage::AC_FORWARD,
+    usage::AC_STOP,
+    usage::AC_REFRESH,
+    usage::AC_BOOKMARKS,
+    usage::FAST_FORWARD,
+    usage::REWIND,
+    usage::BRIGHTNESS_INCREMENT,
+    usage::BRIGHTNESS_DECREMENT,
+];
+
+const MOUSE_KEYS: [MouseKey; (keycode::MS_WH_RIGHT - keycode::MS_UP + 1) as usize] = [
+    MouseKey::Up,
+    MouseKey::Down,
+    MouseKey::Left,
+    MouseKey::Right,
+    MouseKey::Button(MouseButton::Left),
+    MouseKey::Button(MouseButton::Right),
+    MouseKey::Button(MouseButton::Middle),
+    MouseKey::Button(MouseButton::Back),
+    MouseKey::Button(MouseButton::Forward),
+    MouseKey::WheelUp,
+    MouseKey::WheelDown,
+    MouseKey::WheelLeft,
+    MouseKey::WheelRight,
+];
+
+/// The keys of the Keyboard page up to ExSel, by usage ID from `keycode::BASIC_FIRST`.
+const KEY_CODES: [KeyCode; KeyCode::ExSel as usize - keycode::BASIC_FIRST as usize + 1] = [
+    KeyCode::A,
+    KeyCode::B,
+    KeyCode::C,
+    KeyCode::D,
+    KeyCode::E,
+    KeyCode::F,
+    KeyCode::G,
+    KeyCode::H,
+    KeyCode::I,
+    KeyCode::J,
+    KeyCode::K,
+    KeyCode::L,
+    KeyCode::M,
+    KeyCode::N,
+    KeyCode::O,
+    KeyCode::P,
+    KeyCode::Q,
+    KeyCode::R,
+    KeyCode::S,
+    KeyCode::T,
+    KeyCode::U,
+    KeyCode::V,
+    KeyCode::W,
+    KeyCode::X,
+    KeyCode::Y,
+    KeyCode::Z,
+    KeyCode::Kb1,
+    KeyCode::Kb2,
+    KeyCode::Kb3,
+    KeyCode::Kb4,
+    KeyCode::Kb5,
+    KeyCode::Kb6,
+    KeyCode::Kb7,
+    KeyCode::Kb8,
+    KeyCode::Kb9,
+    KeyCode::Kb0,
+    KeyCode::Enter,
+    KeyCode::Escape,
+    KeyCode::BSpace,
+    KeyCode::Tab,
+    KeyCode::Space,
+    KeyCode::Minus,
+    KeyCode::Equal,
+    KeyCode::LBracket,
+    KeyCode::RBracket,
+    KeyCode::Bslash,
+    KeyCode::NonUsHash,
+    KeyCode::SColon,
+    KeyCode::Quote,
+    KeyCode::Grave,
+    KeyCode::Comma,
+    KeyCode::Dot,
+    KeyCode::Slash,
+    KeyCode::CapsLock,
+    KeyCode::F1,
+    KeyCode::F2,
+    KeyCode::F3,
+    KeyCode::F4,
+    KeyCode::F5,
+    KeyCode::F6,
+    KeyCode::F7,
+    KeyCode::F8,
+    KeyCode::F9,
+    KeyCode::F10,
+    KeyCode::F11,
+    KeyCode::F12,
+    KeyCode::PScreen,
+    KeyCode::ScrollLock,
+    KeyCode::Pause,
+    KeyCode::Insert,
+    KeyCode::Home,
+    KeyCode::PgUp,
+    KeyCode::Delete,
+    KeyCode::End,
+    KeyCode::PgDown,
+    KeyCode::Right,
+    KeyCode::Left,
+    KeyCode::Down,
+    KeyCode::Up,
+    KeyCode::NumLock,
+    KeyCode::KpSlash,
+    KeyCode::KpAsterisk,
+    KeyCode::KpMinus,
+    KeyCode::KpPlus,
+    KeyCode::KpEnter,
+    KeyCode::Kp1,
+    KeyCode::Kp2,
+    KeyCode::Kp3,
+    KeyCode::Kp4,
+    KeyCode::Kp5,
+    KeyCode::Kp6,
+    KeyCode::Kp7,
+    KeyCode::Kp8,
+    KeyCode::Kp9,
+    KeyCode::Kp0,
+    KeyCode::KpDot,
+    KeyCode::NonUsBslash,
+    KeyCode::Application,
+    KeyCode::Power,
+    KeyCode::KpEqual,
+    KeyCode::F13,
+    KeyCode::F14,
+    KeyCode::F15,
+    KeyCode::F16,
+    KeyCode::F17,
+    KeyCode::F18,
+    KeyCode::F19,
+    KeyCode::F20,
+    KeyCode::F21,
+    KeyCode::F22,
+    KeyCode::F23,
+    KeyCode::F24,
+    KeyCode::Execute,
+    KeyCode::Help,
+    KeyCode::Menu,
+    KeyCode::Select,
+    KeyCode::Stop,
+    KeyCode::Again,
+    KeyCode::Undo,
+    KeyCode::Cut,
+    KeyCode::Copy,
+    KeyCode::Paste,
+    KeyCode::Find,
+    KeyCode::Mute,
+    KeyCode::VolUp,
+    KeyCode::VolDown,
+    KeyCode::LockingCapsLock,
+    KeyCode::LockingNumLock,
+    KeyCode::LockingScrollLock,
+    KeyCode::KpComma,
+    KeyCode::KpEqualSign,
+    KeyCode::Intl1,
+    KeyCode::Intl2,
+    KeyCode::Intl3,
+    KeyCode::Intl4,
+    KeyCode::Intl5,
+    KeyCode::Intl6,
+    KeyCode::Intl7,
+    KeyCode::Intl8,
+    KeyCode::Intl9,
+    KeyCode::Lang1,
+    KeyCode::Lang2,
+    KeyCode::Lang3,
+    KeyCode::Lang4,
+    KeyCode::Lang5,
+    KeyCode::Lang6,
+    KeyCode::Lang7,
+    KeyCode::Lang8,
+    KeyCode::Lang9,
+    KeyCode::AltErase,
+    KeyCode::SysReq,
+    KeyCode::Cancel,
+    KeyCode::Clear,
+    KeyCode::Prior,
+    KeyCode::Return,
+    KeyCode::Separator,
+    KeyCode::Out,
+    KeyCode::Oper,
+    KeyCode::ClearAgain,
+    KeyCode::CrSel,
+    KeyCode::ExSel,
+];
+
+/// The modifier keys, by usage ID from `KeyCode::LCtrl`.
+const MODIFIERS: [KeyCode; 8] = [
+    KeyCode::LCtrl,
+    KeyCode::LShift,
+    KeyCode::LAlt,
+    KeyCode::LGui,
+    KeyCode::RCtrl,
+    KeyCode::RShift,
+    KeyCode::RAlt,
+    KeyCode::RGui,
+];
+
+const GAMEPAD_DIRECTIONS: [GamepadKey; 4] = [
+    GamepadKey::Up,
+    GamepadKey::Down,
+    GamepadKey::Left,
+    GamepadKey::Right,
+];
+
+/// Layout actions beyond the keyboard keys.
+#[derive(Debug, defmt::Format, Clone, Copy, PartialEq)]
+pub enum CustomAction {
+    /// Restarts the firmware on release.
+    Reset,
+    /// A usage of the Consumer page, see `consumer::usage`.
+    Consumer(u16),
+    System(SystemKey),
+    Mouse(MouseKey),
+    Gamepad(GamepadKey),
+}
+
+/// A custom action pressed or released, see `Keymap::tick`.
+#[derive(Debug, defmt::Format, Clone, Copy, PartialEq)]
+pub enum CustomEvent {
+    NoEvent,
+    Press(CustomAction),
+    Release(CustomAction),
+}
+
+/// The HID devices beside the keyboard a board has, the keymap rejects keycodes of the others.
+#[derive(Debug, defmt::Format, Clone, Copy, Default, PartialEq)]
+pub struct Devices(u8);
+
+impl Devices {
+    pub const CONSUMER: u8 = 1 << 0;
+    pub const SYSTEM: u8 = 1 << 1;
+    pub const MOUSE: u8 = 1 << 2;
+    pub const GAMEPAD: u8 = 1 << 3;
+
+    pub const fn from_bits(bits: u8) -> Devices {
+        Devices(bits & (Self::CONSUMER | Self::SYSTEM | Self::MOUSE | Self::GAMEPAD))
+    }
+
+    pub const fn bits(self) -> u8 {
+        self.0
+    }
+
+    /// Returns whether the board has the device that sends `action`.
+    pub fn supports(self, action: &Action<CustomAction>) -> bool {
+        let device = match action {
+            Action::Custom(CustomAction::Consumer(_)) => Self::CONSUMER,
+            Action::Custom(CustomAction::System(_)) => Self::SYSTEM,
+            Action::Custom(CustomAction::Mouse(_)) => Self::MOUSE,
+            Action::Custom(CustomAction::Gamepad(_)) => Self::GAMEPAD,
+            _ => return true,
+        };
+        self.0 & device != 0
+    }
+}
+
+/// Number of keyboard keys `Keymap::pressed_keys` returns at most.
+pub const MAX_PRESSED_KEYS: usize = 32;
+
+/// Decodes a keycode, `None` for keycodes without an action and layers past `layers`.
+fn keycode_action(code: u16, layers: usize) -> Option<Action<CustomAction>> {
+    let custom = match code {
+        keycode::NO => return Some(Action::NoOp),
+        keycode::TRANSPARENT => return Some(Action::Trans),
+        keycode::SYSTEM_POWER => CustomAction::System(SystemKey::PowerDown),
+        keycode::SYSTEM_SLEEP => CustomAction::System(SystemKey::Sleep),
+        keycode::SYSTEM_WAKE => CustomAction::System(SystemKey::WakeUp),
+        keycode::AUDIO_MUTE..=keycode::BRIGHTNESS_DOWN => {
+            CustomAction::Consumer(CONSUMER_USAGES[usize::from(code - keycode::AUDIO_MUTE)])
+        }
+        keycode::BASIC_FIRST..=keycode::BASIC_LAST => {
+            return key_code(code as u8).map(Action::KeyCode)
+        }
+        keycode::MS_UP..=keycode::MS_WH_RIGHT => {
+            CustomAction::Mouse(MOUSE_KEYS[usize::from(code - keycode::MS_UP)])
+        }
+        _ if code & 0xffe0 == keycode::momentary(0) => {
+            let layer = usize::from(code & 0x1f);
+            return Some(Action::Layer(layer)).filter(|_| layer < layers);
+        }
+        _ if code & 0xffe0 == keycode::default_layer(0) => {
+            let layer = usize::from(code & 0x1f);
+            return Some(Action::DefaultLayer(layer)).filter(|_| layer < layers);
+        }
+        keycode::RESET => CustomAction::Reset,
+        _ if code >= keycode::USER00 => {
+            let user = code - keycode::USER00;
+            let directions = u16::from(NUM_BUTTONS);
+            match user {
+                _ if user < directions => CustomAction::Gamepad(GamepadKey::Button(user as u8)),
+                _ if user < directions + 4 => {
+                    CustomAction::Gamepad(GAMEPAD_DIRECTIONS[usize::from(user - directions)])
+                }
+                _ => return None,
+            }
+        }
+        _ => return None,
+    };
+    Some(Action::Custom(custom))
+}
+
+/// Encodes an action, `keycode::COMPILED` for actions without keycode.
+fn action_keycode(action: &Action<CustomAction>) -> u16 {
+    let code = match *action {
+        Action::NoOp => Some(keycode::NO),
+        Action::Trans => Some(keycode::TRANSPARENT),
+        Action::KeyCode(code) => {
+            Some(u16::from(code as u8)).filter(|_| key_code(code as u8).is_some())
+        }
+        Action::Layer(layer) if layer < 0x20 => Some(keycode::momentary(layer as u8)),
+        Action::DefaultLayer(layer) if layer < 0x20 => Some(keycode::default_layer(layer as u8)),
+        Action::Custom(custom) => custom_keycode(custom),
+        _ => None,
+    };
+    code.unwrap_or(keycode::COMPILED)
+}
+
+fn custom_keycode(custom: CustomAction) -> Option<u16> {
+    match custom {
+        CustomAction::Reset => Some(keycode::RESET),
+        CustomAction::Consumer(consumer) => CONSUMER_USAGES
+            .iter()
+            .position(|&u| u == consumer)
+            .map(|i| keycode::AUDIO_MUTE + i as u16),
+        CustomAction::System(SystemKey::PowerDown) => Some(keycode::SYSTEM_POWER),
+        CustomAction::System(SystemKey::Sleep) => Some(keycode::SYSTEM_SLEEP),
+        CustomAction::System(SystemKey::WakeUp) => Some(keycode::SYSTEM_WAKE),
+        CustomAction::Mouse(mouse) => MOUSE_KEYS
+            .iter()
+            .position(|&m| m == mouse)
+            .map(|i| keycode::MS_UP + i as u16),
+        CustomAction::Gamepad(GamepadKey::Button(button)) => {
+            Some(keycode::USER00 + u16::from(button)).filter(|_| button < NUM_BUTTONS)
+        }
+        CustomAction::Gamepad(direction) => GAMEPAD_DIRECTIONS
+            .iter()
+            .position(|&d| d == direction)
+            .map(|i| keycode::USER00 + u16::from(NUM_BUTTONS) + i as u16),
+    }
+}
+
+/// Returns the key of a keycode of the Keyboard page, `None` past keyberon's `KeyCode`.
+fn key_code(code: u8) -> Option<KeyCode> {
+    let code = usize::from(code);
+    if code >= KeyCode::LCtrl as usize {
+        MODIFIERS.get(code - KeyCode::LCtrl as usize).copied()
+    } else {
+        KEY_CODES
+            .get(code.checked_sub(keycode::BASIC_FIRST as usize)?)
+            .copied()
+    }
+}
+
+/// The keycodes of a keymap, by layer, row and column.
+pub type Keycodes<const LAYERS: usize, const ROWS: usize, const COLS: usize> =
+    [[[u16; COLS]; ROWS]; LAYERS];
+
+type Actions<const LAYERS: usize, const ROWS: usize, const COLS: usize> =
+    [[[Action<CustomAction>; COLS]; ROWS]; LAYERS];
+
+/// The RAM a `Keymap` keeps its layers in. A keyberon `Layout` only takes `'static` layers, so
+/// this has to live in a `static`, like a `static mut` of RTIC's `init`.
+pub struct KeymapLayers<const LAYERS: usize, const ROWS: usize, const COLS: usize> {
+    actions: Actions<LAYERS, ROWS, COLS>,
+    /// The slices a `Layout` reads `actions` through.
+    rows: [[&'static [Action<CustomAction>]; ROWS]; LAYERS],
+    layers: [&'static [&'static [Action<CustomAction>]]; LAYERS],
+}
+
+impl<const LAYERS: usize, const ROWS: usize, const COLS: usize> KeymapLayers<LAYERS, ROWS, COLS> {
+    pub const fn new() -> Self {
+        KeymapLayers {
+            actions: [[[Action::NoOp; COLS]; ROWS]; LAYERS],
+            rows: [[&[]; ROWS]; LAYERS],
+            layers: [&[]; LAYERS],
+        }
+    }
+}
+
+impl<const LAYERS: usize, const ROWS: usize, const COLS: usize> Default
+    for KeymapLayers<LAYERS, ROWS, COLS>
+{
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+pub struct Keymap<const LAYERS: usize, const ROWS: usize, const COLS: usize> {
+    /// The layers compiled into the firmware, restored by `reset`.
+    defaults: Layers<CustomAction>,
+    /// Only written while `layout` doesn't borrow them, see `edit`.
+    layers: NonNull<KeymapLayers<LAYERS, ROWS, COLS>>,
+    layout: Layout<CustomAction>,
+    /// Number of keys held, the default layer is only read off `layout` while none is.
+    held: usize,
+    default_layer: usize,
+    devices: Devices,
+}
+
+// NOTE(unsafe) the keymap owns its layers, `new` took the only other reference to them
+unsafe impl<const LAYERS: usize, const ROWS: usize, const COLS: usize> Send
+    for Keymap<LAYERS, ROWS, COLS>
+{
+}
+
+impl<const LAYERS: usize, const ROWS: usize, const COLS: usize> Keymap<LAYERS, ROWS, COLS> {
+    /// Copies `defaults` to `layers`. Positions past the compiled layers are `NoOp`.
+    ///
+    /// The keymap takes keyboard keys only, see `with_devices`.
+    pub fn new(
+        defaults: Layers<CustomAction>,
+        layers: &'static mut KeymapLayers<LAYERS, ROWS, COLS>,
+    ) -> Self {
+        let mut keymap = Keymap {
+            defaults,
+            layers: NonNull::from(layers),
+            layout: Layout::new(&[]),
+            held: 0,
+            default_layer: 0,
+            devices: Devices::default(),
+        };
+        keymap.reset();
+        keymap
+    }
+
+    /// Also takes the keycodes of `devices`.
+    pub fn with_devices(mut self, devices: Devices) -> Self {
+        self.devices = devices;
+        self
+    }
+
+    fn actions(&self) -> &Actions<LAYERS, ROWS, COLS> {
+        // NOTE(unsafe) only `edit` writes the layers, through `&mut self`
+        unsafe { &self.layers.as_ref().actions }
+    }
+
+    /// Changes the actions and restarts the layout over them. Keys held meanwhile are released,
+    /// the default layer is kept.
+    fn edit(&mut self, edit: impl FnOnce(&mut Actions<LAYERS, ROWS, COLS>)) {
+        if self.held == 0 {
+            self.default_layer = self.layout.current_layer();
+        }
+        // Drop the layout, the only borrower of the layers, before writing them
+        self.layout = Layout::new(&[]);
+        // NOTE(unsafe) nothing borrows the layers now, and they live in a `static`
+        let KeymapLayers {
+            actions,
+            rows,
+            layers,
+        } = unsafe { &mut *self.layers.as_ptr() };
+        edit(actions);
+
+        let actions: &'static Actions<LAYERS, ROWS, COLS> = actions;
+        for (rows, actions) in rows.iter_mut().zip(actions.iter()) {
+            for (row, actions) in rows.iter_mut().zip(actions.iter()) {
+                *row = actions;
+            }
+        }
+        let rows: &'static [[&'static [Action<CustomAction>]; ROWS]; LAYERS] = rows;
+        for (layer, rows) in layers.iter_mut().zip(rows.iter()) {
+            *layer = rows;
+        }
+        self.layout = Layout::new(layers);
+        self.layout.set_default_layer(self.default_layer);
+    }
+
+    /// Returns the keycodes of all positions, as VIA sees them.
+    pub fn keycodes(&self) -> Keycodes<LAYERS, ROWS, COLS> {
+        let mut keycodes = [[[keycode::NO; COLS]; ROWS]; LAYERS];
+        for (codes, actions) in keycodes
+            .iter_mut()
+            .flatten()
+            .zip(self.actions().iter().flatten())
+        {
+            for (code, action) in codes.iter_mut().zip(actions.iter()) {
+                *code = action_keycode(action);
+            }
+        }
+        keycodes
+    }
+
+    /// Returns the keycode at a position, `None` when out of the keymap.
+    pub fn keycode(&self, layer: usize, row: usize, col: usize) -> Option<u16> {
+        let action = self.actions().get(layer)?.get(row)?.get(col)?;
+        Some(action_keycode(action))
+    }
+
+    /// Replaces the action at a position, returning whether the position is in the keymap and
+    /// the keycode has an action on this board.
+    pub fn set_keycode(&mut self, layer: usize, row: usize, col: usize, keycode: u16) -> bool {
+        if layer >= LAYERS || row >= ROWS || col >= COLS {
+            return false;
+        }
+        let action = match keycode {
+            keycode::COMPILED => default_action(self.defaults, layer, row, col),
+            _ => match keycode_action(keycode, LAYERS) {
+                Some(action) if self.devices.supports(&action) => action,
+                _ => return false,
+            },
+        };
+        // Keep the layout running through writes that change nothing
+        if self.actions()[layer][row][col] != action {
+            self.edit(|actions| actions[layer][row][col] = action);
+        }
+        true
+    }
+
+    /// Restores the layers compiled into the firmware.
+    pub fn reset(&mut self) {
+        let defaults = self.defaults;
+        self.edit(|actions| {
+            for (layer, rows) in actions.iter_mut().enumerate() {
+                for (row, cols) in rows.iter_mut().enumerate() {
+                    for (col, action) in cols.iter_mut().enumerate() {
+                        *action = default_action(defaults, layer, row, col);
+                    }
+                }
+            }
+        });
+    }
+
+    /// Makes `layer` the default layer, if it is in the keymap.
+    pub fn set_default_layer(&mut self, layer: usize) {
+        if layer < LAYERS {
+            self.default_layer = layer;
+            self.layout.set_default_layer(layer);
+        }
+    }
+
+    /// Passes a matrix event to the layout.
+    pub fn event(&mut self, event: Event) {
+        match event {
+            Event::Press(..) => self.held += 1,
+            Event::Release(..) => self.held = self.held.saturating_sub(1),
+        }
+        self.layout.event(event);
+    }
+
+    /// Advances the layout by a millisecond, returning the custom action it pressed or
+    /// released.
+    pub fn tick(&mut self) -> CustomEvent {
+        match self.layout.tick() {
+            layout::CustomEvent::NoEvent => CustomEvent::NoEvent,
+            layout::CustomEvent::Press(&custom) => CustomEvent::Press(custom),
+            layout::CustomEvent::Release(&custom) => CustomEvent::Release(custom),
+        }
+    }
+
+    /// Returns the keycodes of the keyboard keys held, modifiers included.
+    pub fn pressed_keys(&self) -> Vec<u8, MAX_PRESSED_KEYS> {
+        self.layout
+            .keycodes()
+            .map(|code| code as u8)
+            .take(MAX_PRESSED_KEYS)
+            .collect()
+    }
+}
+
+fn default_action(
+    defaults: Layers<CustomAction>,
+    layer: usize,
+    row: usize,
+    col: usize,
+) -> Action<CustomAction> {
+    defaults
+        .get(layer)
+        .and_then(|rows| rows.get(row))
+        .and_then(|cols| cols.get(col))
+        .copied()
+        .unwrap_or(Action::NoOp)
+}
+
+/// Detects keys held together for a while, like a factory reset combination. Keys are matrix
+/// positions rather than keycodes, so no keymap edit can disable the combination.
+pub struct Combo<const N: usize> {
+    /// Rows and columns, up to 32 keys.
+    keys: [(u8, u8); N],
+    /// Bit `i` is set while `keys[i]` is held.
+    held: u32,
+    hold_ms: u16,
+    held_ms: u16,
+}
+
+impl<const N: usize> Combo<N> {
+    pub const fn new(keys: [(u8, u8); N], hold_ms: u16) -> Self {
+        Combo {
+            keys,
+            held: 0,
+            hold_ms,
+            held_ms: 0,
+        }
+    }
+
+    pub fn event(&mut self, event: Event) {
+        match event {
+            Event::Press(row, col) => self.held |= self.bit(row, col),
+            Event::Release(row, col) => self.held &= !self.bit(row, col),
+        }
+    }
+
+    /// Advances the hold timer by one millisecond, returning true once, when all keys have
+    /// been held together for the hold duration.
+    pub fn tick(&mut self) -> bool {
+        if N == 0 || self.held.count_ones() as usize != N.min(32) {
+            self.held_ms = 0;
+            return false;
+        }
+        if self.held_ms >= self.hold_ms {
+            return false;
+        }
+        self.held_ms += 1;
+        self.held_ms == self.hold_ms
+    }
+
+    fn bit(&self, row: u8, col: u8) -> u32 {
+        self.keys
+            .iter()
+            .take(32)
+            .position(|&key| key == (row, col))
+            .map_or(0, |i| 1 << i)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        key_code, keycode, Combo, CustomAction, CustomEvent, Devices, Keymap, KeymapLayers,
+    };
+    use crate::consumer::usage;
+    use crate::gamepad::GamepadKey;
+    use crate::mouse::{MouseButton, MouseKey};
+    use crate::system::SystemKey;
+    use keyberon::action::{d, k, l, m, Action, HoldTapConfig};
+    use keyberon::key_code::KeyCode::{LCtrl, LShift, A, B, C};
+    use keyberon::layout::{Event, Layers};
+
+    const HOLD_TAP: Action<CustomAction> = Action::HoldTap {
+        timeout: 200,
+        hold: &k(LCtrl),
+        tap: &k(C),
+        config: HoldTapConfig::Default,
+        tap_hold_interval: 0,
+    };
+
+    static LAYERS: Layers<CustomAction> = &[
+        &[&[k(A), l(1), d(1), HOLD_TAP]],
+        &[&[Action::Trans, Action::NoOp, d(0), m(&[LShift, B])]],
+    ];
+
+    fn keymap() -> Keymap<2, 1, 4> {
+        Keymap::new(LAYERS, Box::leak(Box::new(KeymapLayers::new()))).with_devices(
+            Devices::from_bits(
+                Devices::CONSUMER | Devices::SYSTEM | Devices::MOUSE | Devices::GAMEPAD,
+            ),
+        )
+    }
+
+    /// Presses or releases a key and runs the layout until it took the event.
+    fn event(keymap: &mut Keymap<2, 1, 4>, event: Event) -> CustomEvent {
+        keymap.event(event);
+        keymap.tick()
+    }
+
+    #[test]
+    fn keycodes_round_trip() {
+        let mut keymap = keymap();
+        let keys = [
+            (0x0004, Action::KeyCode(A)),
+            (0x00e1, Action::KeyCode(LShift)),
+            (
+                0x00a5,
+                Action::Custom(CustomAction::System(SystemKey::PowerDown)),
+            ),
+            (
+                0x00a9,
+                Action::Custom(CustomAction::Consumer(usage::VOLUME_INCREMENT)),
+            ),
+            (
+                0x00be,
+                Action::Custom(CustomAction::Consumer(usage::BRIGHTNESS_DECREMENT)),
+            ),
+            (
+                0x00f4,
+                Action::Custom(CustomAction::Mouse(MouseKey::Button(MouseButton::Left))),
+            ),
+            (
+                0x00fc,
+                Action::Custom(CustomAction::Mouse(MouseKey::WheelRight)),
+            ),
+            (0x5101, Action::Layer(1)),
+            (0x5201, Action::DefaultLayer(1)),
+            (0x5c00, Action::Custom(CustomAction::Reset)),
+            (
+                0x5f80,
+                Action::Custom(CustomAction::Gamepad(GamepadKey::Button(0))),
+            ),
+            (
+                0x5f9f,
+                Action::Custom(CustomAction::Gamepad(GamepadKey::Button(31))),
+            ),
+            (
+                0x5fa3,
+                Action::Custom(CustomAction::Gamepad(GamepadKey::Right)),
+            ),
+        ];
+        for &(code, action) in keys.iter() {
+            assert_eq!(super::keycode_action(code, 2), Some(action));
+            assert!(keymap.set_keycode(1, 0, 1, code));
+            assert_eq!(keymap.keycode(1, 0, 1), Some(code));
+        }
+        // Modified keys, mouse acceleration and layers past the keymap have no action
+        for &code in [0x0204, 0x00fd, 0x00c0, 0x5fa4, 0x5102].iter() {
+            assert_eq!(super::keycode_action(code, 2), None);
+            assert!(!keymap.set_keycode(1, 0, 1, code));
+        }
+        assert_eq!(keymap.keycode(1, 0, 1), Some(0x5fa3));
+    }
+
+    #[test]
+    fn key_codes_match_keyberon() {
+        for code in 0..=u8::MAX {
+            if let Some(key) = key_code(code) {
+                assert_eq!(key as u8, code);
+            }
+        }
+        let mapped = (0..=u8::MAX).filter(|&code| key_code(code).is_some());
+        assert_eq!(mapped.count(), 0xa4 - 0x04 + 1 + 8);
+    }
+
+    #[test]
+    fn keycodes_of_missing_devices_are_rejected() {
+        let mut keymap = Keymap::<2, 1, 4>::new(LAYERS, Box::leak(Box::new(KeymapLayers::new())))
+            .with_devices(Devices::from_bits(Devices::MOUSE));
+        assert!(keymap.set_keycode(0, 0, 0, 0x00f4));
+        assert!(keymap.set_keycode(0, 0, 0, 0x5c00));
+        for &code in [0x00a5, 0x00a9, 0x5f80].iter() {
+            assert!(!keymap.set_keycode(0, 0, 0, code));
+        }
+        assert_eq!(keymap.keycode(0, 0, 0), Some(0x5c00));
+    }
+
+    #[test]
+    fn compiled_actions_without_keycode_are_kept() {
+        let mut keymap = keymap();
+        assert_eq!(keymap.keycode(0, 0, 3), Some(keycode::COMPILED));
+        assert_eq!(keymap.keycode(1, 0, 3), Some(keycode::COMPILED));
+        assert_eq!(keymap.keycode(1, 0, 0), Some(keycode::TRANSPARENT));
+
+        assert!(keymap.set_keycode(0, 0, 3, 0x0005));
+        assert_eq!(keymap.keycode(0, 0, 3), Some(0x0005));
+        assert!(keymap.set_keycode(0, 0, 3, keycode::COMPILED));
+        assert_eq!(
+            keymap.keycodes()[0][0],
+            [0x0004, 0x5101, 0x5201, keycode::COMPILED]
+        );
+
+        // The chord still presses both keys
+        event(&mut keymap, Event::Press(0, 1));
+        event(&mut keymap, Event::Press(0, 3));
+        assert_eq!(keymap.pressed_keys(), [0xe1, 0x05]);
+    }
+
+    #[test]
+    fn edits_reach_the_layout() {
+        let mut keymap = keymap();
+        assert_eq!(keymap.keycode(2, 0, 0), None);
+        assert!(!keymap.set_keycode(0, 1, 0, 0x0005));
+        assert!(keymap.set_keycode(0, 0, 0, 0x0005));
+        event(&mut keymap, Event::Press(0, 0));
+        assert_eq!(keymap.pressed_keys(), [0x05]);
+        event(&mut keymap, Event::Release(0, 0));
+
+        assert!(keymap.set_keycode(0, 0, 0, keycode::AUDIO_MUTE));
+        assert_eq!(
+            event(&mut keymap, Event::Press(0, 0)),
+            CustomEvent::Press(CustomAction::Consumer(usage::MUTE))
+        );
+        assert_eq!(
+            event(&mut keymap, Event::Release(0, 0)),
+            CustomEvent::Release(CustomAction::Consumer(usage::MUTE))
+        );
+
+        keymap.reset();
+        assert_eq!(keymap.keycode(0, 0, 0), Some(0x0004));
+    }
+
+    #[test]
+    fn edits_keep_the_default_layer() {
+        let mut keymap = keymap();
+        event(&mut keymap, Event::Press(0, 2));
+        event(&mut keymap, Event::Release(0, 2));
+        assert!(keymap.set_keycode(0, 0, 0, 0x0005));
+
+        // Layer 1 still presses the chord
+        event(&mut keymap, Event::Press(0, 3));
+        assert_eq!(keymap.pressed_keys(), [0xe1, 0x05]);
+        event(&mut keymap, Event::Release(0, 3));
+        keymap.set_default_layer(0);
+        event(&mut keymap, Event::Press(0, 0));
+        assert_eq!(keymap.pressed_keys(), [0x05]);
+    }
+
+    #[test]
+    fn combo_fires_once_when_held() {
+        let mut combo = Combo::new([(0, 0), (5, 4)], 3);
+        combo.event(Event::Press(0, 0));
+        combo.event(Event::Press(1, 1));
+        assert!(!combo.tick());
+        combo.event(Event::Press(5, 4));
+        assert!(!combo.tick());
+        assert!(!combo.tick());
+        assert!(combo.tick());
+        assert!(!combo.tick());
+
+        // Releasing a key starts over
+        combo.event(Event::Release(0, 0));
+        assert!(!combo.tick());
+        combo.event(Event::Press(0, 0));
+        assert!(!combo.tick());
+        assert!(!combo.tick());
+        assert!(combo.tick());
+    }
+}
@@ -0,0 +1,170 @@
+//! Keyboard LEDs set by the host.
+//!
+//! The host writes the state of its lock keys to the keyboard output report. `Keyboard` decodes
+//! it into a `LedState` and hands that to its `LedSink`, which board code implements to drive
+//! whatever GPIOs its LEDs are on, usually through `LedPin`.
+
+use core::fmt::Debug;
+use embedded_hal::digital::v2::OutputPin;
+
+/// The LED bits of the keyboard output report, see the LED page of the HID usage tables.
+#[derive(Debug, defmt::Format, Clone, Copy, Default, PartialEq)]
+pub struct LedState(u8);
+
+impl LedState {
+    pub const NUM_LOCK: u8 = 1 << 0;
+    pub const CAPS_LOCK: u8 = 1 << 1;
+    pub const SCROLL_LOCK: u8 = 1 << 2;
+    pub const COMPOSE: u8 = 1 << 3;
+    pub const KANA: u8 = 1 << 4;
+
+    /// Decodes an output report byte, ignoring the padding bits.
+    pub const fn from_bits(bits: u8) -> LedState {
+        LedState(
+            bits & (Self::NUM_LOCK
+                | Self::CAPS_LOCK
+                | Self::SCROLL_LOCK
+                | Self::COMPOSE
+                | Self::KANA),
+        )
+    }
+
+    pub const fn bits(self) -> u8 {
+        self.0
+    }
+
+    pub const fn num_lock(self) -> bool {
+        self.0 & Self::NUM_LOCK != 0
+    }
+
+    pub const fn caps_lock(self) -> bool {
+        self.0 & Self::CAPS_LOCK != 0
+    }
+
+    pub const fn scroll_lock(self) -> bool {
+        self.0 & Self::SCROLL_LOCK != 0
+    }
+
+    pub const fn compose(self) -> bool {
+        self.0 & Self::COMPOSE != 0
+    }
+
+    pub const fn kana(self) -> bool {
+        self.0 & Self::KANA != 0
+    }
+}
+
+/// Shows the LED state the host wrote.
+pub trait LedSink {
+    /// Called with every output report the host writes, whether the state changed or not, and
+    /// when the host changes the LED mode of the keyboard settings.
+    fn set_leds(&mut self, leds: LedState);
+}
+
+/// For keyboards without LEDs.
+impl LedSink for () {
+    fn set_leds(&mut self, _leds: LedState) {}
+}
+
+/// Which level of its pin turns an LED on.
+#[derive(Debug, defmt::Format, Clone, Copy, PartialEq)]
+pub enum Polarity {
+    ActiveHigh,
+    /// The LED sits between the supply and the pin, like the PC13 LED of our boards.
+    ActiveLow,
+}
+
+/// An LED on a GPIO pin.
+pub struct LedPin<P> {
+    pin: P,
+    polarity: Polarity,
+}
+
+impl<P: OutputPin> LedPin<P> {
+    pub fn new(pin: P, polarity: Polarity) -> LedPin<P> {
+        LedPin { pin, polarity }
+    }
+
+    pub fn set(&mut self, on: bool) -> Result<(), P::Error> {
+        if on == (self.polarity == Polarity::ActiveHigh) {
+            self.pin.set_high()
+        } else {
+            self.pin.set_low()
+        }
+    }
+
+    pub fn free(self) -> P {
+        self.pin
+    }
+}
+
+/// Shows caps lock on a single LED, like the PC13 LED of our boards.
+pub struct CapsLockLed<P>(pub LedPin<P>);
+
+impl<P: OutputPin> LedSink for CapsLockLed<P>
+where
+    P::Error: Debug,
+{
+    fn set_leds(&mut self, leds: LedState) {
+        self.0.set(leds.caps_lock()).unwrap();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{CapsLockLed, LedPin, LedSink, LedState, Polarity};
+    use core::convert::Infallible;
+    use embedded_hal::digital::v2::OutputPin;
+
+    #[derive(Default)]
+    struct Pin {
+        high: Option<bool>,
+    }
+
+    impl OutputPin for Pin {
+        type Error = Infallible;
+
+        fn set_low(&mut self) -> Result<(), Infallible> {
+            self.high = Some(false);
+            Ok(())
+        }
+
+        fn set_high(&mut self) -> Result<(), Infallible> {
+            self.high = Some(true);
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn state_decodes_the_output_report() {
+        let leds = LedState::from_bits(0xe0 | LedState::CAPS_LOCK | LedState::KANA);
+        assert_eq!(leds.bits(), 0b1_0010);
+        assert!(!leds.num_lock());
+        assert!(leds.caps_lock());
+        assert!(!leds.scroll_lock());
+        assert!(!leds.compose());
+        assert!(leds.kana());
+    }
+
+    #[test]
+    fn pin_follows_polarity() {
+        let mut led = LedPin::new(Pin::default(), Polarity::ActiveHigh);
+        led.set(true).unwrap();
+        assert_eq!(led.free().high, Some(true));
+
+        let mut led = LedPin::new(Pin::default(), Polarity::ActiveLow);
+        led.set(true).unwrap();
+        assert_eq!(led.pin.high, Some(false));
+        led.set(false).unwrap();
+        assert_eq!(led.free().high, Some(true));
+    }
+
+    #[test]
+    fn caps_lock_led_ignores_the_other_locks() {
+        let mut led = CapsLockLed(LedPin::new(Pin::default(), Polarity::ActiveHigh));
+        led.set_leds(LedState::from_bits(LedState::NUM_LOCK));
+        assert_eq!(led.0.pin.high, Some(false));
+        led.set_leds(LedState::from_bits(LedState::CAPS_LOCK));
+        assert_eq!(led.0.free().high, Some(true));
+    }
+}
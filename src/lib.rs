@@ -1,19 +1,39 @@
-#![no_std]
+#![cfg_attr(not(test), no_std)]
 
 use core::sync::atomic::{AtomicUsize, Ordering};
 
+#[cfg(target_os = "none")]
 use defmt_rtt as _; // global logger
 
 // TODO(5) adjust HAL import
+#[cfg(target_os = "none")]
 use stm32f3xx_hal as _; // memory layout
 
+#[cfg(target_os = "none")]
 use panic_probe as _;
 
+pub mod consumer;
+pub mod descriptor;
+#[cfg(target_os = "none")]
+pub mod flash;
+pub mod gamepad;
 pub mod hid;
 pub mod keyboard;
+pub mod keymap;
+pub mod leds;
+#[cfg(test)]
+mod mock_bus;
+pub mod mouse;
+#[cfg(target_os = "none")]
+pub mod power;
+pub mod raw;
+pub mod storage;
+pub mod system;
+pub mod via;
 
 // same panicking *behavior* as `panic-probe` but doesn't print a panic message
 // this prevents the panic message being printed *twice* when `defmt::panic` is invoked
+#[cfg(target_os = "none")]
 #[defmt::panic_handler]
 fn panic() -> ! {
     cortex_m::asm::udf()
@@ -27,7 +47,22 @@ defmt::timestamp!("{=usize}", {
     n
 });
 
+// Host tests have no probe to log to, drop everything
+#[cfg(test)]
+#[defmt::global_logger]
+struct NoopLogger;
+
+#[cfg(test)]
+unsafe impl defmt::Logger for NoopLogger {
+    fn acquire() -> Option<core::ptr::NonNull<dyn defmt::Write>> {
+        None
+    }
+
+    unsafe fn release(_: core::ptr::NonNull<dyn defmt::Write>) {}
+}
+
 /// Terminates the application and makes `probe-run` exit with exit-code = 0
+#[cfg(target_os = "none")]
 pub fn exit() -> ! {
     loop {
         cortex_m::asm::bkpt();
@@ -5,6 +5,8 @@
 
 use cortex_m::asm::delay;
 use my_app as _;
+use my_app::hid::HidError;
+use my_app::power::{self, Port, Power, PowerEvent, SuspendMonitor};
 
 use embedded_hal::digital::v2::OutputPin;
 use rtic::app;
@@ -23,6 +25,12 @@ use usb_device::device::UsbVidPid;
 
 const PERIOD: u32 = 10_000_000;
 
+// Keycode of the `a` key
+const KEY_A: u8 = 0x04;
+
+// The button on PA4 wakes the host
+const KEY_PINS: u16 = 1 << 4;
+
 // Generic keyboard from
 // https://github.com/obdev/v-usb/blob/master/usbdrv/USB-IDs-for-free.txt
 const PID: u16 = 0x27db;
@@ -40,6 +48,12 @@ const APP: () = {
         // output: gpioa::PA5<Output<PushPull>>,
         led: gpioc::PC13<Output<PushPull>>,
         timer: Timer<stm32f3xx_hal::stm32::TIM3>,
+        power: Power,
+        exti: stm32f3xx_hal::stm32::EXTI,
+        #[init(SuspendMonitor::new())]
+        suspend_monitor: SuspendMonitor,
+        #[init(false)]
+        suspended: bool,
     }
 
     #[init(schedule = [blinker])]
@@ -97,12 +111,16 @@ const APP: () = {
             .as_ref()
             .expect("Couldn't make the USB_BUS a static reference");
 
-        let usb_class = my_app::hid::HidClass::new(my_app::keyboard::Keyboard::new(), &usb_bus);
+        // Poll every millisecond for 1000 Hz reporting
+        let usb_class = my_app::hid::HidClass::builder(my_app::keyboard::Keyboard::new(), &usb_bus)
+            .poll_interval(1)
+            .build();
         let usb_device = UsbDeviceBuilder::new(&usb_bus, UsbVidPid(VID, PID))
             .manufacturer("ando")
             .product("nano")
             .serial_number(env!("CARGO_PKG_VERSION"))
             .device_class(3)
+            .supports_remote_wakeup(true)
             .build();
 
         let mut timer = timer::Timer::tim3(device.TIM3, 1.khz(), clocks, &mut rcc.apb1);
@@ -122,6 +140,9 @@ const APP: () = {
             .into_push_pull_output(&mut gpioc.moder, &mut gpioc.otyper);
         led.set_low().unwrap();
 
+        let mut power = Power::new(device.PWR, core.SCB, &device.EXTI, clocks.sysclk());
+        power.wake_on_keys(&device.EXTI, &device.SYSCFG, Port::A, KEY_PINS);
+
         // Schedule the blinking task
         cx.schedule.blinker(cx.start + PERIOD.cycles()).unwrap();
 
@@ -132,30 +153,97 @@ const APP: () = {
             button,
             // output,
             timer,
+            power,
+            exti: device.EXTI,
         }
     }
 
-    #[idle]
-    fn idle(_ctx: idle::Context) -> ! {
-        loop {}
+    #[idle(resources = [power, suspended, usb_device])]
+    fn idle(cx: idle::Context) -> ! {
+        let idle::Resources {
+            power,
+            mut suspended,
+            mut usb_device,
+        } = cx.resources;
+        loop {
+            // With interrupts off a resume can't slip in between the check and going to sleep
+            cortex_m::interrupt::free(|_| {
+                if suspended.lock(|suspended| *suspended) {
+                    power.stop();
+                    // Woken by a key rather than the host
+                    if power.keys_pressed() {
+                        usb_device.lock(|usb_device| power.wake_host(usb_device));
+                    }
+                }
+            });
+        }
     }
 
-    #[task(binds=USB_HP_CAN_TX, priority = 2, resources = [usb_device, usb_class])]
+    #[task(binds=USB_HP_CAN_TX, priority = 2, resources = [usb_device, usb_class, suspend_monitor], spawn = [power_state])]
     fn hp_handler(mut cx: hp_handler::Context) {
         defmt::info!("hp handler");
-        usb_poll(&mut cx.resources.usb_device, &mut cx.resources.usb_class);
+        if let Some(event) = usb_poll(
+            &mut cx.resources.usb_device,
+            &mut cx.resources.usb_class,
+            &mut cx.resources.suspend_monitor,
+        ) {
+            cx.spawn.power_state(event).unwrap();
+        }
     }
 
-    #[task(binds=USB_LP_CAN_RX0, priority = 2, resources = [usb_device, usb_class])]
+    #[task(binds=USB_LP_CAN_RX0, priority = 2, resources = [usb_device, usb_class, suspend_monitor], spawn = [power_state])]
     fn lp_handler(mut cx: lp_handler::Context) {
         defmt::info!("lp handler");
-        usb_poll(&mut cx.resources.usb_device, &mut cx.resources.usb_class);
+        if let Some(event) = usb_poll(
+            &mut cx.resources.usb_device,
+            &mut cx.resources.usb_class,
+            &mut cx.resources.suspend_monitor,
+        ) {
+            cx.spawn.power_state(event).unwrap();
+        }
     }
 
-    #[task(binds=USB_LP, priority=2, resources=[usb_device, usb_class])]
+    #[task(binds=USB_LP, priority=2, resources=[usb_device, usb_class, suspend_monitor], spawn=[power_state])]
     fn usb_lp_handler(mut cx: usb_lp_handler::Context) {
         defmt::info!("usb lp handler");
-        usb_poll(&mut cx.resources.usb_device, &mut cx.resources.usb_class);
+        if let Some(event) = usb_poll(
+            &mut cx.resources.usb_device,
+            &mut cx.resources.usb_class,
+            &mut cx.resources.suspend_monitor,
+        ) {
+            cx.spawn.power_state(event).unwrap();
+        }
+    }
+
+    #[task(binds=USB_WKUP, priority=2, resources=[exti, usb_device, usb_class, suspend_monitor], spawn=[power_state])]
+    fn usb_wakeup(mut cx: usb_wakeup::Context) {
+        defmt::info!("usb wakeup");
+        power::clear_usb_wakeup(cx.resources.exti);
+        if let Some(event) = usb_poll(
+            &mut cx.resources.usb_device,
+            &mut cx.resources.usb_class,
+            &mut cx.resources.suspend_monitor,
+        ) {
+            cx.spawn.power_state(event).unwrap();
+        }
+    }
+
+    // Suspend and resume may both be pending before this gets to run
+    #[task(capacity = 2, resources = [timer, led, suspended])]
+    fn power_state(cx: power_state::Context, event: PowerEvent) {
+        defmt::info!("usb {:?}", event);
+        match event {
+            PowerEvent::Suspend => {
+                // Stop scanning and dim the LED, idle then enters STOP mode
+                cx.resources.timer.unlisten(timer::Event::Update);
+                cx.resources.led.set_high().unwrap();
+                *cx.resources.suspended = true;
+            }
+            PowerEvent::Resume => {
+                *cx.resources.suspended = false;
+                cx.resources.timer.listen(timer::Event::Update);
+            }
+        }
     }
 
     #[task(resources = [led, button], schedule = [blinker])]
@@ -180,6 +268,9 @@ const APP: () = {
 
     #[task(binds=TIM3, priority=1, resources=[timer, button, usb_class])]
     fn tick(mut cx: tick::Context) {
+        // The key state the host last received, `None` until the first report is out
+        static mut SENT_PRESSED: Option<bool> = None;
+
         cx.resources.timer.clear_update_interrupt_flag();
 
         let key_pressed = cx
@@ -187,18 +278,29 @@ const APP: () = {
             .button
             .is_low()
             .expect("Couldn't poll pressed keys!");
-        cx.resources
-            .usb_class
-            .lock(|k| {
-                // defmt::info!("writing from tick");
+        let sent_pressed = *SENT_PRESSED;
+        let written = cx.resources.usb_class.lock(|k| {
+            // Only report changes, repeating the last report is left to the idle timer
+            let written = if sent_pressed == Some(key_pressed) {
+                None
+            } else {
                 // Type the character `a`
                 if key_pressed {
-                    k.write(&[0, 0, 4, 0, 0, 0, 0, 0])
+                    k.device_mut().press(KEY_A);
                 } else {
-                    k.write(&[0, 0, 0, 0, 0, 0, 0, 0])
+                    k.device_mut().release(KEY_A);
                 }
-            })
-            .expect("Couldn't get access to USB_CLASS!");
+                Some(k.write_device_report(0))
+            };
+            k.tick();
+            written
+        });
+        match written {
+            Some(Ok(_)) => *SENT_PRESSED = Some(key_pressed),
+            // Nothing to send, or the report queue is full and we retry on the next tick
+            None | Some(Err(HidError::Busy)) => {}
+            Some(Err(err)) => defmt::error!("Couldn't send report: {:?}", err),
+        }
     }
 
     extern "C" {
@@ -209,8 +311,10 @@ const APP: () = {
 fn usb_poll(
     usb_dev: &mut UsbDevice<'static, UsbBus<Peripheral>>,
     keyboard: &mut my_app::hid::HidClass<'static, UsbBus<Peripheral>, my_app::keyboard::Keyboard>,
-) {
+    suspend_monitor: &mut SuspendMonitor,
+) -> Option<PowerEvent> {
     if usb_dev.poll(&mut [keyboard]) {
         keyboard.poll();
     }
+    suspend_monitor.update(usb_dev)
 }
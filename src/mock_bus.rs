@@ -0,0 +1,313 @@
+//! A `UsbBus` for host tests, and the host side of it.
+//!
+//! `MockBus` stands in for the peripheral, `Host` plays the USB host: it injects SETUP and OUT
+//! packets, collects what the device wrote to its IN endpoints and runs whole control
+//! transfers through `UsbDevice::poll`.
+
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
+use std::vec::Vec;
+
+use usb_device::bus::{PollResult, UsbBus, UsbBusAllocator};
+use usb_device::class::UsbClass;
+use usb_device::device::{UsbDevice, UsbDeviceBuilder, UsbVidPid};
+use usb_device::endpoint::{EndpointAddress, EndpointType};
+use usb_device::{Result, UsbDirection, UsbError};
+
+const NUM_ENDPOINTS: usize = 8;
+
+/// Gives up on a transfer the device doesn't answer after this many polls.
+const MAX_POLLS: usize = 100;
+
+#[derive(Default)]
+struct State {
+    max_packet_size: [[u16; NUM_ENDPOINTS]; 2],
+    allocated: [u16; 2],
+    /// Packets the host sent, flagged whether they are SETUP packets.
+    out_packets: [VecDeque<(bool, Vec<u8>)>; NUM_ENDPOINTS],
+    /// Packets the device wrote that the host didn't collect yet.
+    in_packets: [Option<Vec<u8>>; NUM_ENDPOINTS],
+    in_complete: u16,
+    stalled: [u16; 2],
+    reset: bool,
+    address: u8,
+}
+
+fn direction_index(direction: UsbDirection) -> usize {
+    match direction {
+        UsbDirection::Out => 0,
+        UsbDirection::In => 1,
+    }
+}
+
+pub struct MockBus {
+    state: Arc<Mutex<State>>,
+}
+
+impl MockBus {
+    /// Returns the bus to hand to `UsbBusAllocator` and the host attached to it.
+    pub fn new() -> (MockBus, Host) {
+        let state = Arc::new(Mutex::new(State::default()));
+        (
+            MockBus {
+                state: state.clone(),
+            },
+            Host { state },
+        )
+    }
+}
+
+/// Builds a device on a new bus with the class `class` builds, and enumerates it. The allocator
+/// is leaked so the class can borrow it for the rest of the test.
+pub fn enumerated<C: UsbClass<MockBus>>(
+    class: impl FnOnce(&'static UsbBusAllocator<MockBus>) -> C,
+) -> (Host, UsbDevice<'static, MockBus>, C) {
+    let (bus, host) = MockBus::new();
+    let alloc = Box::leak(Box::new(UsbBusAllocator::new(bus)));
+    let mut class = class(alloc);
+    let mut usb = UsbDeviceBuilder::new(alloc, UsbVidPid(0x16c0, 0x27db)).build();
+    host.enumerate(&mut usb, &mut [&mut class]);
+    (host, usb, class)
+}
+
+impl UsbBus for MockBus {
+    fn alloc_ep(
+        &mut self,
+        ep_dir: UsbDirection,
+        ep_addr: Option<EndpointAddress>,
+        _ep_type: EndpointType,
+        max_packet_size: u16,
+        _interval: u8,
+    ) -> Result<EndpointAddress> {
+        let mut state = self.state.lock().unwrap();
+        let dir = direction_index(ep_dir);
+        let index = match ep_addr {
+            Some(addr) if state.allocated[dir] & (1 << addr.index()) != 0 => {
+                return Err(UsbError::InvalidEndpoint)
+            }
+            Some(addr) => addr.index(),
+            None => (1..NUM_ENDPOINTS)
+                .find(|&i| state.allocated[dir] & (1 << i) == 0)
+                .ok_or(UsbError::EndpointOverflow)?,
+        };
+        state.allocated[dir] |= 1 << index;
+        state.max_packet_size[dir][index] = max_packet_size;
+        Ok(EndpointAddress::from_parts(index, ep_dir))
+    }
+
+    fn enable(&mut self) {}
+
+    fn reset(&self) {
+        let mut state = self.state.lock().unwrap();
+        state.address = 0;
+        state.stalled = [0; 2];
+    }
+
+    fn set_device_address(&self, addr: u8) {
+        self.state.lock().unwrap().address = addr;
+    }
+
+    fn write(&self, ep_addr: EndpointAddress, buf: &[u8]) -> Result<usize> {
+        let mut state = self.state.lock().unwrap();
+        let index = ep_addr.index();
+        if buf.len() > usize::from(state.max_packet_size[1][index]) {
+            return Err(UsbError::BufferOverflow);
+        }
+        if state.in_packets[index].is_some() {
+            return Err(UsbError::WouldBlock);
+        }
+        state.in_packets[index] = Some(buf.to_vec());
+        Ok(buf.len())
+    }
+
+    fn read(&self, ep_addr: EndpointAddress, buf: &mut [u8]) -> Result<usize> {
+        let mut state = self.state.lock().unwrap();
+        let packets = &mut state.out_packets[ep_addr.index()];
+        match packets.front() {
+            None => Err(UsbError::WouldBlock),
+            Some((_, data)) if data.len() > buf.len() => Err(UsbError::BufferOverflow),
+            Some(_) => {
+                let (_, data) = packets.pop_front().unwrap();
+                buf[..data.len()].copy_from_slice(&data);
+                Ok(data.len())
+            }
+        }
+    }
+
+    fn set_stalled(&self, ep_addr: EndpointAddress, stalled: bool) {
+        let mut state = self.state.lock().unwrap();
+        let dir = direction_index(ep_addr.direction());
+        if stalled {
+            state.stalled[dir] |= 1 << ep_addr.index();
+        } else {
+            state.stalled[dir] &= !(1 << ep_addr.index());
+        }
+    }
+
+    fn is_stalled(&self, ep_addr: EndpointAddress) -> bool {
+        let state = self.state.lock().unwrap();
+        state.stalled[direction_index(ep_addr.direction())] & (1 << ep_addr.index()) != 0
+    }
+
+    fn suspend(&self) {}
+
+    fn resume(&self) {}
+
+    fn poll(&self) -> PollResult {
+        let mut state = self.state.lock().unwrap();
+        if state.reset {
+            state.reset = false;
+            return PollResult::Reset;
+        }
+        let mut ep_out = 0;
+        let mut ep_setup = 0;
+        for (i, packets) in state.out_packets.iter().enumerate() {
+            match packets.front() {
+                Some((true, _)) => ep_setup |= 1 << i,
+                Some((false, _)) => ep_out |= 1 << i,
+                None => {}
+            }
+        }
+        let ep_in_complete = core::mem::replace(&mut state.in_complete, 0);
+        if ep_out | ep_setup | ep_in_complete == 0 {
+            PollResult::None
+        } else {
+            PollResult::Data {
+                ep_out,
+                ep_in_complete,
+                ep_setup,
+            }
+        }
+    }
+}
+
+/// The device stalled a control transfer.
+#[derive(Debug, PartialEq)]
+pub struct Stalled;
+
+pub struct Host {
+    state: Arc<Mutex<State>>,
+}
+
+impl Host {
+    /// Polls the device the way our apps do.
+    pub fn poll(&self, usb: &mut UsbDevice<MockBus>, classes: &mut [&mut dyn UsbClass<MockBus>]) {
+        if usb.poll(classes) {
+            for class in classes.iter_mut() {
+                class.poll();
+            }
+        }
+    }
+
+    /// Resets the bus, then assigns address 1 and selects the first configuration.
+    pub fn enumerate(
+        &self,
+        usb: &mut UsbDevice<MockBus>,
+        classes: &mut [&mut dyn UsbClass<MockBus>],
+    ) {
+        self.state.lock().unwrap().reset = true;
+        self.poll(usb, classes);
+        // SET_ADDRESS and SET_CONFIGURATION
+        self.control_out(usb, classes, [0x00, 0x05, 1, 0, 0, 0, 0, 0], &[])
+            .unwrap();
+        self.control_out(usb, classes, [0x00, 0x09, 1, 0, 0, 0, 0, 0], &[])
+            .unwrap();
+    }
+
+    /// Sends a packet to an OUT endpoint.
+    pub fn send(&self, ep: usize, data: &[u8]) {
+        self.state.lock().unwrap().out_packets[ep].push_back((false, data.to_vec()));
+    }
+
+    /// Takes the packet the device wrote to an IN endpoint, acknowledging it.
+    pub fn receive(&self, ep: usize) -> Option<Vec<u8>> {
+        let mut state = self.state.lock().unwrap();
+        let packet = state.in_packets[ep].take();
+        if packet.is_some() {
+            state.in_complete |= 1 << ep;
+        }
+        packet
+    }
+
+    pub fn address(&self) -> u8 {
+        self.state.lock().unwrap().address
+    }
+
+    /// Runs a control transfer with an IN data stage and returns the data.
+    pub fn control_in(
+        &self,
+        usb: &mut UsbDevice<MockBus>,
+        classes: &mut [&mut dyn UsbClass<MockBus>],
+        setup: [u8; 8],
+    ) -> core::result::Result<Vec<u8>, Stalled> {
+        let length = usize::from(u16::from_le_bytes([setup[6], setup[7]]));
+        let max_packet_size = usize::from(self.state.lock().unwrap().max_packet_size[1][0]);
+        self.setup(setup);
+
+        let mut data = Vec::new();
+        for _ in 0..MAX_POLLS {
+            self.poll(usb, classes);
+            self.check_stall()?;
+            if let Some(packet) = self.receive(0) {
+                data.extend_from_slice(&packet);
+                if packet.len() < max_packet_size || data.len() >= length {
+                    // Let the device see the last packet went out, then acknowledge
+                    self.poll(usb, classes);
+                    self.send(0, &[]);
+                    self.poll(usb, classes);
+                    return Ok(data);
+                }
+            }
+        }
+        panic!("no answer to {:02x?}", setup);
+    }
+
+    /// Runs a control transfer with an optional OUT data stage.
+    pub fn control_out(
+        &self,
+        usb: &mut UsbDevice<MockBus>,
+        classes: &mut [&mut dyn UsbClass<MockBus>],
+        setup: [u8; 8],
+        data: &[u8],
+    ) -> core::result::Result<(), Stalled> {
+        let max_packet_size = usize::from(self.state.lock().unwrap().max_packet_size[0][0]);
+        self.setup(setup);
+        self.poll(usb, classes);
+        self.check_stall()?;
+        for chunk in data.chunks(max_packet_size) {
+            self.send(0, chunk);
+            self.poll(usb, classes);
+            self.check_stall()?;
+        }
+
+        // The device acknowledges with an empty IN packet
+        for _ in 0..MAX_POLLS {
+            if let Some(packet) = self.receive(0) {
+                assert!(packet.is_empty(), "status stage with data {:02x?}", packet);
+                self.poll(usb, classes);
+                return Ok(());
+            }
+            self.poll(usb, classes);
+            self.check_stall()?;
+        }
+        panic!("no answer to {:02x?}", setup);
+    }
+
+    /// Queues a SETUP packet, which clears any stall on the control endpoint like the
+    /// hardware does.
+    fn setup(&self, setup: [u8; 8]) {
+        let mut state = self.state.lock().unwrap();
+        state.stalled[0] &= !1;
+        state.stalled[1] &= !1;
+        state.out_packets[0].push_back((true, setup.to_vec()));
+    }
+
+    fn check_stall(&self) -> core::result::Result<(), Stalled> {
+        let state = self.state.lock().unwrap();
+        if (state.stalled[0] | state.stalled[1]) & 1 != 0 {
+            Err(Stalled)
+        } else {
+            Ok(())
+        }
+    }
+}
@@ -0,0 +1,640 @@
+//! A boot mouse, and mouse keys to drive it from a keyboard.
+//!
+//! `Mouse` reports five buttons, X and Y motion, the vertical wheel and horizontal panning. The
+//! first three bytes of its report are the boot mouse report, which is all it sends in boot
+//! protocol.
+//!
+//! `MouseKeys` turns held keys into motion: it moves the cursor once when a direction key goes
+//! down, and after `MouseKeysConfig::delay_ms` keeps moving it, accelerating towards
+//! `MouseKeysConfig::max_speed`. Wheel keys scroll one step at a time, repeating while held.
+
+use crate::descriptor::{generic_desktop, Collection, DescriptorBuilder, ItemFlags, UsagePage};
+use crate::hid::{HidDevice, HidError, Protocol, ReportProtocol, ReportType, Subclass};
+
+/// AC Pan on the Consumer page, the horizontal wheel.
+const AC_PAN: u16 = 0x238;
+
+const MOUSE: DescriptorBuilder = DescriptorBuilder::new()
+    .usage_page(UsagePage::GenericDesktop)
+    .usage(generic_desktop::MOUSE)
+    .collection(Collection::Application)
+    .usage(generic_desktop::POINTER)
+    .collection(Collection::Physical)
+    // Buttons
+    .usage_page(UsagePage::Button)
+    .usage_minimum(1)
+    .usage_maximum(5)
+    .logical_minimum(0)
+    .logical_maximum(1)
+    .report_size(1)
+    .report_count(5)
+    .input(ItemFlags::DATA_VARIABLE)
+    .pad_input()
+    // Motion and vertical wheel
+    .usage_page(UsagePage::GenericDesktop)
+    .usage(generic_desktop::X)
+    .usage(generic_desktop::Y)
+    .usage(generic_desktop::WHEEL)
+    .logical_minimum(-127)
+    .logical_maximum(127)
+    .report_size(8)
+    .report_count(3)
+    .input(ItemFlags::DATA_VARIABLE_RELATIVE)
+    // Horizontal wheel
+    .usage_page(UsagePage::Consumer)
+    .usage(AC_PAN)
+    .report_count(1)
+    .input(ItemFlags::DATA_VARIABLE_RELATIVE)
+    .end_collection()
+    .end_collection();
+pub const MOUSE_DESC: &[u8] = &MOUSE.finish::<{ MOUSE.len() }>();
+
+/// Size of an input report in boot protocol: buttons, X and Y.
+const BOOT_REPORT_SIZE: usize = 3;
+
+/// Size of an input report in report protocol, as laid out by `MOUSE_DESC`.
+const REPORT_REPORT_SIZE: usize = 5;
+
+#[derive(Debug, defmt::Format, Clone, Copy, PartialEq)]
+pub enum MouseButton {
+    Left,
+    Right,
+    Middle,
+    Back,
+    Forward,
+}
+
+impl MouseButton {
+    const fn bit(self) -> u8 {
+        1 << self as u8
+    }
+}
+
+/// Motion since the previous report. Positive X is right, positive Y down, positive wheel away
+/// from the user and positive pan right.
+#[derive(Debug, defmt::Format, Clone, Copy, Default, PartialEq)]
+pub struct Motion {
+    pub x: i8,
+    pub y: i8,
+    pub wheel: i8,
+    pub pan: i8,
+}
+
+impl Motion {
+    pub fn is_zero(&self) -> bool {
+        *self == Motion::default()
+    }
+}
+
+pub struct Mouse {
+    name: &'static str,
+    protocol: ReportProtocol,
+    buttons: u8,
+    report: [u8; REPORT_REPORT_SIZE],
+    /// The buttons without motion, answered to GET_REPORT.
+    buttons_report: [u8; REPORT_REPORT_SIZE],
+}
+
+impl Mouse {
+    pub fn new() -> Mouse {
+        Mouse {
+            name: "Mouse",
+            protocol: ReportProtocol::Report,
+            buttons: 0,
+            report: [0; REPORT_REPORT_SIZE],
+            buttons_report: [0; REPORT_REPORT_SIZE],
+        }
+    }
+
+    pub fn with_name(mut self, name: &'static str) -> Mouse {
+        self.name = name;
+        self
+    }
+
+    /// Returns the pressed buttons, one bit per `MouseButton`.
+    pub fn buttons(&self) -> u8 {
+        self.buttons
+    }
+
+    /// Replaces the pressed buttons and the motion of the next report, returning whether that
+    /// report needs to be sent: when the buttons changed or there is motion.
+    pub fn update(&mut self, buttons: u8, motion: Motion) -> bool {
+        let changed = buttons != self.buttons || !motion.is_zero();
+        self.buttons = buttons;
+        self.report = [
+            buttons,
+            motion.x as u8,
+            motion.y as u8,
+            motion.wheel as u8,
+            motion.pan as u8,
+        ];
+        changed
+    }
+
+    /// Returns the input report in the layout of the protocol selected by the host.
+    pub fn report(&self) -> &[u8] {
+        match self.protocol {
+            ReportProtocol::Boot => &self.report[..BOOT_REPORT_SIZE],
+            ReportProtocol::Report => &self.report,
+        }
+    }
+}
+
+impl Default for Mouse {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl HidDevice for Mouse {
+    fn subclass(&self) -> Subclass {
+        Subclass::BootInterface
+    }
+
+    fn protocol(&self) -> Protocol {
+        Protocol::Mouse
+    }
+
+    fn report_descriptor(&self) -> &[u8] {
+        MOUSE_DESC
+    }
+
+    fn get_report(&mut self, report_type: ReportType, _report_id: u8) -> Result<&[u8], HidError> {
+        if report_type != ReportType::Input {
+            return Err(HidError::UnsupportedReportType);
+        }
+        // Motion is left for the next interrupt report, the host only gets to see the buttons
+        let len = self.report().len();
+        self.buttons_report = [self.buttons, 0, 0, 0, 0];
+        Ok(&self.buttons_report[..len])
+    }
+
+    fn input_report(&self, _report_id: u8) -> Result<&[u8], HidError> {
+        Ok(self.report())
+    }
+
+    fn set_report(
+        &mut self,
+        _report_type: ReportType,
+        _report_id: u8,
+        _data: &[u8],
+    ) -> Result<(), HidError> {
+        Err(HidError::UnsupportedReportType)
+    }
+
+    fn report_len(&self, report_type: ReportType, _report_id: u8) -> Option<usize> {
+        match report_type {
+            ReportType::Input => Some(self.report().len()),
+            _ => None,
+        }
+    }
+
+    fn relative_reports(&self) -> bool {
+        true
+    }
+
+    fn interface_name(&self, _lang_id: u16) -> Option<&str> {
+        Some(self.name)
+    }
+
+    fn set_protocol(&mut self, protocol: ReportProtocol) {
+        self.protocol = protocol;
+    }
+}
+
+/// How the cursor speeds up while a direction key is held.
+#[derive(Debug, defmt::Format, Clone, Copy, PartialEq)]
+pub enum Curve {
+    /// Speed grows steadily from the initial to the max speed.
+    Linear,
+    /// Speed grows slowly at first, for precise moves, then quickly.
+    Quadratic,
+}
+
+/// Mouse keys tuning, the speeds are in mouse units per report.
+#[derive(Debug, defmt::Format, Clone, Copy, PartialEq)]
+pub struct MouseKeysConfig {
+    /// Speed of the first move and of the moves until acceleration starts.
+    pub initial_speed: u8,
+    pub max_speed: u8,
+    /// Time between the first move and the repeated ones.
+    pub delay_ms: u16,
+    /// Time between repeated moves.
+    pub interval_ms: u16,
+    /// Time from the first repeated move to max speed, 0 to get there at once.
+    pub time_to_max_ms: u16,
+    pub curve: Curve,
+    /// Time between the first wheel step and the repeated ones.
+    pub wheel_delay_ms: u16,
+    /// Time between repeated wheel steps.
+    pub wheel_interval_ms: u16,
+}
+
+impl MouseKeysConfig {
+    pub const fn new() -> MouseKeysConfig {
+        MouseKeysConfig {
+            initial_speed: 4,
+            max_speed: 24,
+            delay_ms: 100,
+            interval_ms: 16,
+            time_to_max_ms: 800,
+            curve: Curve::Quadratic,
+            wheel_delay_ms: 300,
+            wheel_interval_ms: 80,
+        }
+    }
+}
+
+impl Default for MouseKeysConfig {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[derive(Debug, defmt::Format, Clone, Copy, PartialEq)]
+pub enum MouseKey {
+    Up,
+    Down,
+    Left,
+    Right,
+    WheelUp,
+    WheelDown,
+    WheelLeft,
+    WheelRight,
+    Button(MouseButton),
+}
+
+impl MouseKey {
+    /// Bit of a direction key in `MouseKeys::directions`.
+    fn direction_bit(self) -> u8 {
+        match self {
+            MouseKey::Up => 1 << 0,
+            MouseKey::Down => 1 << 1,
+            MouseKey::Left => 1 << 2,
+            MouseKey::Right => 1 << 3,
+            MouseKey::WheelUp => 1 << 4,
+            MouseKey::WheelDown => 1 << 5,
+            MouseKey::WheelLeft => 1 << 6,
+            MouseKey::WheelRight => 1 << 7,
+            MouseKey::Button(_) => 0,
+        }
+    }
+}
+
+const MOVE_KEYS: u8 = 0x0f;
+const WHEEL_KEYS: u8 = 0xf0;
+
+/// Moving diagonally at full speed on both axes would be faster by √2, scale by 181/256.
+const DIAGONAL_SCALE: u16 = 181;
+
+/// Time a group of held keys has been moving for, and when it moves next.
+#[derive(Clone, Copy)]
+struct Repeat {
+    elapsed_ms: u32,
+    next_ms: u32,
+}
+
+impl Repeat {
+    const fn new() -> Repeat {
+        Repeat {
+            elapsed_ms: 0,
+            next_ms: 0,
+        }
+    }
+
+    /// Advances by a millisecond, returning whether a move is due first.
+    fn tick(&mut self, delay_ms: u16, interval_ms: u16) -> bool {
+        let due = self.elapsed_ms == self.next_ms;
+        if due {
+            let wait = if self.elapsed_ms == 0 {
+                delay_ms
+            } else {
+                interval_ms
+            };
+            self.next_ms += u32::from(wait.max(1));
+        }
+        self.elapsed_ms += 1;
+        due
+    }
+}
+
+/// The mouse keys engine, call `tick` at 1 kHz.
+pub struct MouseKeys {
+    config: MouseKeysConfig,
+    /// Held direction keys, see `MouseKey::direction_bit`.
+    directions: u8,
+    buttons: u8,
+    movement: Repeat,
+    wheel: Repeat,
+}
+
+impl MouseKeys {
+    pub const fn new(config: MouseKeysConfig) -> MouseKeys {
+        MouseKeys {
+            config,
+            directions: 0,
+            buttons: 0,
+            movement: Repeat::new(),
+            wheel: Repeat::new(),
+        }
+    }
+
+    pub fn config(&self) -> MouseKeysConfig {
+        self.config
+    }
+
+    pub fn set_config(&mut self, config: MouseKeysConfig) {
+        self.config = config;
+    }
+
+    pub fn press(&mut self, key: MouseKey) {
+        match key {
+            MouseKey::Button(button) => self.buttons |= button.bit(),
+            _ => self.directions |= key.direction_bit(),
+        }
+    }
+
+    pub fn release(&mut self, key: MouseKey) {
+        match key {
+            MouseKey::Button(button) => self.buttons &= !button.bit(),
+            _ => self.directions &= !key.direction_bit(),
+        }
+        // Start over from the initial speed once all keys of a group are up
+        if self.directions & MOVE_KEYS == 0 {
+            self.movement = Repeat::new();
+        }
+        if self.directions & WHEEL_KEYS == 0 {
+            self.wheel = Repeat::new();
+        }
+    }
+
+    /// Returns the held buttons, one bit per `MouseButton`, for `Mouse::update`.
+    pub fn buttons(&self) -> u8 {
+        self.buttons
+    }
+
+    /// Advances by a millisecond and returns the motion to report now, zero if none is due.
+    pub fn tick(&mut self) -> Motion {
+        let config = self.config;
+        let mut motion = Motion::default();
+
+        if self.directions & MOVE_KEYS != 0
+            && self.movement.tick(config.delay_ms, config.interval_ms)
+        {
+            let speed = self.speed();
+            let x = self.axis(MouseKey::Left, MouseKey::Right);
+            let y = self.axis(MouseKey::Up, MouseKey::Down);
+            let speed = if x != 0 && y != 0 {
+                (u16::from(speed) * DIAGONAL_SCALE / 256).max(1) as i8
+            } else {
+                speed as i8
+            };
+            motion.x = x * speed;
+            motion.y = y * speed;
+        }
+
+        if self.directions & WHEEL_KEYS != 0
+            && self
+                .wheel
+                .tick(config.wheel_delay_ms, config.wheel_interval_ms)
+        {
+            motion.wheel = self.axis(MouseKey::WheelDown, MouseKey::WheelUp);
+            motion.pan = self.axis(MouseKey::WheelLeft, MouseKey::WheelRight);
+        }
+        motion
+    }
+
+    /// Returns -1, 0 or 1 depending on which of two opposite keys is held.
+    fn axis(&self, negative: MouseKey, positive: MouseKey) -> i8 {
+        let held = |key: MouseKey| self.directions & key.direction_bit() != 0;
+        i8::from(held(positive)) - i8::from(held(negative))
+    }
+
+    /// Speed of the move due now, capped at 127.
+    fn speed(&self) -> u8 {
+        let config = &self.config;
+        let initial = u32::from(config.initial_speed.min(127));
+        let max = u32::from(config.max_speed.min(127)).max(initial);
+        let delay = u32::from(config.delay_ms);
+        if self.movement.elapsed_ms < delay {
+            return initial as u8;
+        }
+        let time_to_max = u32::from(config.time_to_max_ms);
+        let t = (self.movement.elapsed_ms - delay).min(time_to_max);
+        if t == time_to_max {
+            return max as u8;
+        }
+        let gain = match config.curve {
+            Curve::Linear => (max - initial) * t / time_to_max,
+            Curve::Quadratic => (max - initial) * t * t / (time_to_max * time_to_max),
+        };
+        (initial + gain) as u8
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        Curve, Motion, Mouse, MouseButton, MouseKey, MouseKeys, MouseKeysConfig, MOUSE_DESC,
+    };
+    use crate::descriptor;
+    use crate::hid::{HidClass, HidDevice, ReportProtocol, ReportType};
+    use crate::mock_bus;
+
+    #[rustfmt::skip]
+    const MOUSE_DESC_BYTES: &[u8] = &[
+        0x05, 0x01,       // USAGE_PAGE (Generic Desktop)
+        0x09, 0x02,       // USAGE (Mouse)
+        0xa1, 0x01,       // COLLECTION (Application)
+        0x09, 0x01,       //   USAGE (Pointer)
+        0xa1, 0x00,       //   COLLECTION (Physical)
+        0x05, 0x09,       //     USAGE_PAGE (Button)
+        0x19, 0x01,       //     USAGE_MINIMUM (Button 1)
+        0x29, 0x05,       //     USAGE_MAXIMUM (Button 5)
+        0x15, 0x00,       //     LOGICAL_MINIMUM (0)
+        0x25, 0x01,       //     LOGICAL_MAXIMUM (1)
+        0x75, 0x01,       //     REPORT_SIZE (1)
+        0x95, 0x05,       //     REPORT_COUNT (5)
+        0x81, 0x02,       //     INPUT (Data,Var,Abs)
+        0x95, 0x03,       //     REPORT_COUNT (3)
+        0x75, 0x01,       //     REPORT_SIZE (1)
+        0x81, 0x03,       //     INPUT (Cnst,Var,Abs)
+        0x05, 0x01,       //     USAGE_PAGE (Generic Desktop)
+        0x09, 0x30,       //     USAGE (X)
+        0x09, 0x31,       //     USAGE (Y)
+        0x09, 0x38,       //     USAGE (Wheel)
+        0x15, 0x81,       //     LOGICAL_MINIMUM (-127)
+        0x25, 0x7f,       //     LOGICAL_MAXIMUM (127)
+        0x75, 0x08,       //     REPORT_SIZE (8)
+        0x95, 0x03,       //     REPORT_COUNT (3)
+        0x81, 0x06,       //     INPUT (Data,Var,Rel)
+        0x05, 0x0c,       //     USAGE_PAGE (Consumer)
+        0x0a, 0x38, 0x02, //     USAGE (AC Pan)
+        0x95, 0x01,       //     REPORT_COUNT (1)
+        0x81, 0x06,       //     INPUT (Data,Var,Rel)
+        0xc0,             //   END_COLLECTION
+        0xc0,             // END_COLLECTION
+    ];
+
+    fn config(curve: Curve) -> MouseKeysConfig {
+        MouseKeysConfig {
+            initial_speed: 2,
+            max_speed: 10,
+            delay_ms: 10,
+            interval_ms: 5,
+            time_to_max_ms: 40,
+            curve,
+            wheel_delay_ms: 20,
+            wheel_interval_ms: 10,
+        }
+    }
+
+    /// Runs the engine for `ms` milliseconds, returning the motion of each report.
+    fn run(keys: &mut MouseKeys, ms: usize) -> Vec<Motion> {
+        (0..ms)
+            .map(|_| keys.tick())
+            .filter(|motion| !motion.is_zero())
+            .collect()
+    }
+
+    #[test]
+    fn descriptor_matches_report() {
+        assert_eq!(MOUSE_DESC, MOUSE_DESC_BYTES);
+        let mut mouse = Mouse::new();
+        let layout = descriptor::check_device(&mut mouse).unwrap();
+        assert_eq!(layout.report_len(ReportType::Input, 0), Some(5));
+    }
+
+    #[test]
+    fn boot_protocol_reports_buttons_and_motion() {
+        let mut mouse = Mouse::new();
+        let motion = Motion {
+            x: -3,
+            y: 4,
+            wheel: 1,
+            pan: -1,
+        };
+        assert!(mouse.update(MouseButton::Right.bit(), motion));
+        assert_eq!(mouse.report(), [0x02, 0xfd, 0x04, 0x01, 0xff]);
+        mouse.set_protocol(ReportProtocol::Boot);
+        assert_eq!(mouse.report(), [0x02, 0xfd, 0x04]);
+
+        // Only button changes and motion need a report
+        assert!(!mouse.update(MouseButton::Right.bit(), Motion::default()));
+        assert!(mouse.update(0, Motion::default()));
+    }
+
+    #[test]
+    fn get_report_keeps_unsent_motion() {
+        let mut mouse = Mouse::new();
+        let motion = Motion {
+            x: 5,
+            ..Motion::default()
+        };
+        mouse.update(MouseButton::Left.bit(), motion);
+        assert_eq!(
+            mouse.get_report(ReportType::Input, 0).unwrap(),
+            [0x01, 0, 0, 0, 0]
+        );
+        assert_eq!(mouse.report(), [0x01, 0x05, 0, 0, 0]);
+        mouse.set_protocol(ReportProtocol::Boot);
+        assert_eq!(
+            mouse.get_report(ReportType::Input, 0).unwrap(),
+            [0x01, 0, 0]
+        );
+    }
+
+    #[test]
+    fn repeated_motion_is_sent_every_time() {
+        let (host, mut usb, mut class) =
+            mock_bus::enumerated(|alloc| HidClass::new(Mouse::new(), alloc));
+
+        let motion = Motion {
+            x: 1,
+            ..Motion::default()
+        };
+        for _ in 0..2 {
+            class.device_mut().update(0, motion);
+            let mut report = [0; 5];
+            report.copy_from_slice(class.device().report());
+            class.write(&report).unwrap();
+            host.poll(&mut usb, &mut [&mut class]);
+            assert_eq!(host.receive(1), Some(vec![0, 1, 0, 0, 0]));
+        }
+        // Nor is motion repeated when idle, SET_IDLE to 4 ms
+        host.control_out(
+            &mut usb,
+            &mut [&mut class],
+            [0x21, 0x0a, 0, 1, 0, 0, 0, 0],
+            &[],
+        )
+        .unwrap();
+        for _ in 0..1000 {
+            class.tick();
+        }
+        assert_eq!(host.receive(1), None);
+    }
+
+    #[test]
+    fn direction_keys_accelerate() {
+        let mut keys = MouseKeys::new(config(Curve::Linear));
+        keys.press(MouseKey::Right);
+        let moves: Vec<i8> = run(&mut keys, 61).iter().map(|m| m.x).collect();
+        // Once on press, then every 5 ms from 10 ms on, reaching max speed at 50 ms
+        assert_eq!(moves, [2, 2, 3, 4, 5, 6, 7, 8, 9, 10, 10, 10]);
+
+        // Quadratic is slower at first
+        let mut keys = MouseKeys::new(config(Curve::Quadratic));
+        keys.press(MouseKey::Up);
+        let moves: Vec<i8> = run(&mut keys, 61).iter().map(|m| m.y).collect();
+        assert_eq!(moves, [-2, -2, -2, -2, -3, -4, -5, -6, -8, -10, -10, -10]);
+    }
+
+    #[test]
+    fn diagonal_moves_are_scaled() {
+        let mut keys = MouseKeys::new(config(Curve::Linear));
+        keys.press(MouseKey::Left);
+        keys.press(MouseKey::Down);
+        let motion = keys.tick();
+        assert_eq!((motion.x, motion.y), (-1, 1));
+        let motion = run(&mut keys, 60).pop().unwrap();
+        assert_eq!((motion.x, motion.y), (-7, 7));
+    }
+
+    #[test]
+    fn release_restarts_acceleration() {
+        let mut keys = MouseKeys::new(config(Curve::Linear));
+        keys.press(MouseKey::Right);
+        run(&mut keys, 100);
+        keys.press(MouseKey::Down);
+        keys.release(MouseKey::Right);
+        // Still moving, so still at full speed
+        assert_eq!(run(&mut keys, 5)[0].y, 10);
+        keys.release(MouseKey::Down);
+        assert!(run(&mut keys, 100).is_empty());
+        keys.press(MouseKey::Down);
+        assert_eq!(keys.tick().y, 2);
+    }
+
+    #[test]
+    fn wheel_keys_repeat() {
+        let mut keys = MouseKeys::new(config(Curve::Linear));
+        keys.press(MouseKey::WheelUp);
+        keys.press(MouseKey::WheelRight);
+        let steps = run(&mut keys, 50);
+        // Once on press, then at 20, 30 and 40 ms
+        assert_eq!(steps.len(), 4);
+        assert!(steps.iter().all(|m| m.wheel == 1 && m.pan == 1 && m.x == 0));
+    }
+
+    #[test]
+    fn buttons_are_held() {
+        let mut keys = MouseKeys::new(MouseKeysConfig::new());
+        keys.press(MouseKey::Button(MouseButton::Left));
+        keys.press(MouseKey::Button(MouseButton::Forward));
+        assert_eq!(keys.buttons(), 0b1_0001);
+        assert!(keys.tick().is_zero());
+        keys.release(MouseKey::Button(MouseButton::Left));
+        assert_eq!(keys.buttons(), 0b1_0000);
+    }
+}
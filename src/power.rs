@@ -0,0 +1,253 @@
+//! USB suspend handling and the STM32F303 STOP mode.
+//!
+//! The host suspends the bus when it sleeps, and a bus-powered device then has to get by on a
+//! few milliamps. `SuspendMonitor` turns the `UsbDevice` state into suspend and resume events,
+//! the app stops its tick on `PowerEvent::Suspend`, and `Power::stop` puts the core into STOP
+//! mode until resume signalling on the bus wakes it through EXTI line 18.
+//!
+//! A key press wakes the core too, when the app passed its pins to `Power::wake_on_keys`. If the
+//! host enabled remote wakeup, `Power::wake_host` then signals resume on the bus. Apps that keep
+//! running while suspended signal it with `RemoteWakeup` instead.
+
+use cortex_m::peripheral::SCB;
+use stm32f3xx_hal::pac;
+use stm32f3xx_hal::time::Hertz;
+use usb_device::bus::UsbBus;
+use usb_device::device::{UsbDevice, UsbDeviceState};
+
+/// A change of the bus state seen between two polls.
+#[derive(Debug, defmt::Format, Clone, Copy, PartialEq)]
+pub enum PowerEvent {
+    Suspend,
+    Resume,
+}
+
+/// Reports each suspend and resume of the bus once.
+pub struct SuspendMonitor {
+    suspended: bool,
+}
+
+impl SuspendMonitor {
+    pub const fn new() -> SuspendMonitor {
+        SuspendMonitor { suspended: false }
+    }
+
+    /// Call after every `UsbDevice::poll`.
+    pub fn update<B: UsbBus>(&mut self, usb_device: &UsbDevice<'_, B>) -> Option<PowerEvent> {
+        let suspended = usb_device.state() == UsbDeviceState::Suspend;
+        if suspended == self.suspended {
+            return None;
+        }
+        self.suspended = suspended;
+        if suspended {
+            Some(PowerEvent::Suspend)
+        } else {
+            Some(PowerEvent::Resume)
+        }
+    }
+}
+
+impl Default for SuspendMonitor {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Resume signalling has to last between 1 and 15 ms, see USB 2.0 section 7.1.7.7.
+const RESUME_SIGNALLING_MS: u32 = 10;
+
+/// A GPIO port with keys on it.
+#[derive(Debug, defmt::Format, Clone, Copy, PartialEq)]
+pub enum Port {
+    A = 0,
+    B = 1,
+    C = 2,
+}
+
+/// Signals resume on a suspended bus.
+pub struct RemoteWakeup {
+    resume_cycles: u32,
+}
+
+impl RemoteWakeup {
+    /// `sysclk` is the frozen system clock, used to time resume signalling.
+    pub fn new(sysclk: Hertz) -> RemoteWakeup {
+        RemoteWakeup {
+            resume_cycles: sysclk.0 / 1000 * RESUME_SIGNALLING_MS,
+        }
+    }
+
+    /// Signals resume to a suspended host that enabled remote wakeup, returning whether it did.
+    /// The host then resumes the bus, which `SuspendMonitor` reports as usual.
+    ///
+    /// Blocks for the length of the resume signalling, 10 ms.
+    pub fn wake_host<B: UsbBus>(&self, usb_device: &UsbDevice<'_, B>) -> bool {
+        if usb_device.state() != UsbDeviceState::Suspend || !usb_device.remote_wakeup_enabled() {
+            return false;
+        }
+        // `UsbBus` has no way to signal resume, so drive the peripheral directly
+        // NOTE(unsafe) the bus is suspended, nothing else touches the control register now
+        let usb = unsafe { &*pac::USB::ptr() };
+        usb.cntr.modify(|_, w| {
+            w.fsusp()
+                .clear_bit()
+                .lpmode()
+                .clear_bit()
+                .resume()
+                .set_bit()
+        });
+        cortex_m::asm::delay(self.resume_cycles);
+        usb.cntr.modify(|_, w| w.resume().clear_bit());
+        true
+    }
+}
+
+pub struct Power {
+    pwr: pac::PWR,
+    scb: SCB,
+    remote_wakeup: RemoteWakeup,
+    /// Port and pins of the keys that wake the core, pressed keys read low.
+    keys: Option<(Port, u16)>,
+}
+
+impl Power {
+    /// Routes USB wakeup to the `USB_WKUP` interrupt, which the app has to bind and clear with
+    /// `clear_usb_wakeup`. `sysclk` is the frozen system clock, used to time resume signalling.
+    pub fn new(pwr: pac::PWR, mut scb: SCB, exti: &pac::EXTI, sysclk: Hertz) -> Power {
+        // The PWR registers are only writable with their clock on
+        // NOTE(unsafe) the HAL owns RCC by now, this sets a single enable bit nobody else touches
+        let rcc = unsafe { &*pac::RCC::ptr() };
+        rcc.apb1enr.modify(|_, w| w.pwren().set_bit());
+
+        // Resume signalling raises EXTI line 18
+        exti.imr1.modify(|_, w| w.mr18().set_bit());
+        exti.rtsr1.modify(|_, w| w.tr18().set_bit());
+
+        // Key presses are events rather than interrupts, and those only wake `wfe`. Make
+        // interrupts wake it as well.
+        scb.set_sevonpend();
+
+        Power {
+            pwr,
+            scb,
+            remote_wakeup: RemoteWakeup::new(sysclk),
+            keys: None,
+        }
+    }
+
+    /// Wakes the core from STOP mode on a falling edge of any of the given pins, which need
+    /// pull-ups and are expected to read low while their key is pressed. For a matrix, these are
+    /// the columns, with all rows driven low while suspended.
+    ///
+    /// The pins raise EXTI events rather than interrupts, so no handler is needed.
+    pub fn wake_on_keys(&mut self, exti: &pac::EXTI, syscfg: &pac::SYSCFG, port: Port, pins: u16) {
+        // NOTE(unsafe) the HAL owns RCC by now, this sets a single enable bit nobody else touches
+        let rcc = unsafe { &*pac::RCC::ptr() };
+        rcc.apb2enr.modify(|_, w| w.syscfgen().set_bit());
+
+        for pin in 0..16 {
+            if pins & (1 << pin) == 0 {
+                continue;
+            }
+            // Each EXTICR register selects the port for four lines, four bits each
+            let shift = (pin % 4) * 4;
+            let select = |bits: u32| bits & !(0xf << shift) | (port as u32) << shift;
+            // NOTE(unsafe) any port number below 6 is a valid selection
+            unsafe {
+                match pin / 4 {
+                    0 => syscfg.exticr1.modify(|r, w| w.bits(select(r.bits()))),
+                    1 => syscfg.exticr2.modify(|r, w| w.bits(select(r.bits()))),
+                    2 => syscfg.exticr3.modify(|r, w| w.bits(select(r.bits()))),
+                    _ => syscfg.exticr4.modify(|r, w| w.bits(select(r.bits()))),
+                }
+            }
+        }
+        // NOTE(unsafe) lines 0 to 15 are the GPIO lines, which all have both registers
+        exti.emr1
+            .modify(|r, w| unsafe { w.bits(r.bits() | u32::from(pins)) });
+        exti.ftsr1
+            .modify(|r, w| unsafe { w.bits(r.bits() | u32::from(pins)) });
+
+        self.keys = Some((port, pins));
+    }
+
+    /// Returns whether any of the keys passed to `wake_on_keys` is pressed.
+    pub fn keys_pressed(&self) -> bool {
+        match self.keys {
+            Some((port, pins)) => !read_pins(port) & pins != 0,
+            None => false,
+        }
+    }
+
+    /// Signals resume to a suspended host that enabled remote wakeup, see
+    /// `RemoteWakeup::wake_host`.
+    pub fn wake_host<B: UsbBus>(&mut self, usb_device: &UsbDevice<'_, B>) -> bool {
+        self.remote_wakeup.wake_host(usb_device)
+    }
+
+    /// Enters STOP mode with the regulator in low-power mode and returns once an interrupt or a
+    /// key woke the core, running from the same clocks as before.
+    ///
+    /// Call with interrupts disabled, so a resume that arrives between checking the suspend
+    /// state and getting here still wakes the core, and no handler runs on the slow wakeup
+    /// clock. It may also return early on a stale event, so call it in a loop.
+    pub fn stop(&mut self) {
+        self.pwr
+            .cr
+            .modify(|_, w| w.pdds().clear_bit().lpds().set_bit());
+        self.scb.set_sleepdeep();
+        cortex_m::asm::dsb();
+        cortex_m::asm::wfe();
+        self.scb.clear_sleepdeep();
+        restore_clocks();
+    }
+}
+
+/// Drives the given pins high or low, whatever mode the HAL put them in. Meant for matrix rows
+/// owned by a scanner, which have to be low for a key press to wake the core.
+pub fn drive_pins(port: Port, pins: u16, high: bool) {
+    let bits = if high {
+        u32::from(pins)
+    } else {
+        u32::from(pins) << 16
+    };
+    // NOTE(unsafe) BSRR writes are atomic and only touch the given pins
+    unsafe {
+        match port {
+            Port::A => (*pac::GPIOA::ptr()).bsrr.write(|w| w.bits(bits)),
+            Port::B => (*pac::GPIOB::ptr()).bsrr.write(|w| w.bits(bits)),
+            Port::C => (*pac::GPIOC::ptr()).bsrr.write(|w| w.bits(bits)),
+        }
+    }
+}
+
+fn read_pins(port: Port) -> u16 {
+    // NOTE(unsafe) reading the input data register has no side effects
+    let bits = unsafe {
+        match port {
+            Port::A => (*pac::GPIOA::ptr()).idr.read().bits(),
+            Port::B => (*pac::GPIOB::ptr()).idr.read().bits(),
+            Port::C => (*pac::GPIOC::ptr()).idr.read().bits(),
+        }
+    };
+    bits as u16
+}
+
+/// Acknowledges the USB wakeup, call from the `USB_WKUP` handler.
+pub fn clear_usb_wakeup(exti: &pac::EXTI) {
+    exti.pr1.write(|w| w.pr18().set_bit());
+}
+
+/// STOP mode switches the system clock to the HSI and turns the HSE and PLL off. Turning them
+/// back on is enough to get the clocks `init` froze, as the PLL, prescaler and flash settings
+/// survive STOP. All our apps run from the PLL fed by the HSE.
+fn restore_clocks() {
+    // NOTE(unsafe) the HAL owns RCC by now, but it never touches it again after `freeze`
+    let rcc = unsafe { &*pac::RCC::ptr() };
+    rcc.cr.modify(|_, w| w.hseon().set_bit());
+    while rcc.cr.read().hserdy().bit_is_clear() {}
+    rcc.cr.modify(|_, w| w.pllon().set_bit());
+    while rcc.cr.read().pllrdy().bit_is_clear() {}
+    rcc.cfgr.modify(|_, w| w.sw().pll());
+    while !rcc.cfgr.read().sws().is_pll() {}
+}
@@ -0,0 +1,363 @@
+//! Raw HID, a vendor-defined channel for host tools.
+//!
+//! `RawHid` moves fixed size reports of 32 or 64 bytes in both directions, on the usage page
+//! and usages QMK and VIA use, so existing host libraries find it. Build its `HidClass` with
+//! an interrupt OUT endpoint and a max packet size of the report size.
+//!
+//! `process` adds a small command/response framing on top, for our own tools:
+//!
+//! - request: command, sequence number, payload length, payload
+//! - response: command, sequence number, `Status`, payload length, payload
+//!
+//! Both are padded with zeros to the report size. The command and sequence number are echoed
+//! so the host can match responses to requests.
+
+use crate::descriptor::{Collection, DescriptorBuilder, ItemFlags, UsagePage};
+use crate::hid::{HidDevice, HidError, Protocol, Report, ReportType, Subclass};
+
+const RAW_USAGE_PAGE: u16 = 0xff60;
+const RAW_USAGE: u16 = 0x61;
+const RAW_USAGE_DATA_IN: u16 = 0x62;
+const RAW_USAGE_DATA_OUT: u16 = 0x63;
+
+const fn raw_descriptor(report_size: u32) -> DescriptorBuilder {
+    DescriptorBuilder::new()
+        .usage_page(UsagePage::Vendor(RAW_USAGE_PAGE))
+        .usage(RAW_USAGE)
+        .collection(Collection::Application)
+        .logical_minimum(0)
+        .logical_maximum(0xff)
+        .report_size(8)
+        .report_count(report_size)
+        .usage(RAW_USAGE_DATA_IN)
+        .input(ItemFlags::DATA_VARIABLE)
+        .usage(RAW_USAGE_DATA_OUT)
+        .output(ItemFlags::DATA_VARIABLE)
+        .end_collection()
+}
+
+const RAW_32: DescriptorBuilder = raw_descriptor(32);
+pub const RAW_32_DESC: &[u8] = &RAW_32.finish::<{ RAW_32.len() }>();
+const RAW_64: DescriptorBuilder = raw_descriptor(64);
+pub const RAW_64_DESC: &[u8] = &RAW_64.finish::<{ RAW_64.len() }>();
+
+/// Length of the input and output reports.
+#[derive(Debug, defmt::Format, Clone, Copy, PartialEq)]
+pub enum RawReportSize {
+    /// What VIA expects.
+    Bytes32 = 32,
+    Bytes64 = 64,
+}
+
+pub struct RawHid {
+    name: &'static str,
+    report_size: RawReportSize,
+    /// The last output report, until the app takes it.
+    received: Option<Report>,
+    /// The last input report, for GET_REPORT.
+    sent: [u8; RawReportSize::Bytes64 as usize],
+}
+
+impl RawHid {
+    pub fn new(report_size: RawReportSize) -> RawHid {
+        RawHid {
+            name: "Raw HID",
+            report_size,
+            received: None,
+            sent: [0; RawReportSize::Bytes64 as usize],
+        }
+    }
+
+    pub fn with_name(mut self, name: &'static str) -> RawHid {
+        self.name = name;
+        self
+    }
+
+    pub fn report_size(&self) -> usize {
+        self.report_size as usize
+    }
+
+    /// Returns the output report the host sent, if any. The host has to wait for the
+    /// response before sending the next one: reports arriving before this is called are
+    /// dropped.
+    pub fn take_report(&mut self) -> Option<Report> {
+        self.received.take()
+    }
+
+    /// Remembers the input report written to the `HidClass`, which the host can also read
+    /// with GET_REPORT.
+    pub fn set_sent_report(&mut self, report: &[u8]) {
+        let len = report.len().min(self.report_size());
+        self.sent = [0; RawReportSize::Bytes64 as usize];
+        self.sent[..len].copy_from_slice(&report[..len]);
+    }
+}
+
+impl HidDevice for RawHid {
+    fn subclass(&self) -> Subclass {
+        Subclass::None
+    }
+
+    fn protocol(&self) -> Protocol {
+        Protocol::None
+    }
+
+    fn report_descriptor(&self) -> &[u8] {
+        match self.report_size {
+            RawReportSize::Bytes32 => RAW_32_DESC,
+            RawReportSize::Bytes64 => RAW_64_DESC,
+        }
+    }
+
+    fn get_report(&mut self, report_type: ReportType, _report_id: u8) -> Result<&[u8], HidError> {
+        if report_type != ReportType::Input {
+            return Err(HidError::UnsupportedReportType);
+        }
+        Ok(&self.sent[..self.report_size()])
+    }
+
+    fn set_report(
+        &mut self,
+        report_type: ReportType,
+        report_id: u8,
+        data: &[u8],
+    ) -> Result<(), HidError> {
+        if report_type != ReportType::Output {
+            return Err(HidError::UnsupportedReportType);
+        }
+        if report_id != 0 {
+            return Err(HidError::UnknownReportId);
+        }
+        if data.len() != self.report_size() {
+            return Err(HidError::BadLength);
+        }
+        if self.received.is_some() {
+            return Err(HidError::Busy);
+        }
+        self.received = Report::from_slice(data).ok();
+        Ok(())
+    }
+
+    fn report_len(&self, report_type: ReportType, _report_id: u8) -> Option<usize> {
+        match report_type {
+            ReportType::Input | ReportType::Output => Some(self.report_size()),
+            _ => None,
+        }
+    }
+
+    /// Input reports are responses, each is sent once even if it equals the previous one.
+    fn relative_reports(&self) -> bool {
+        true
+    }
+
+    fn interface_name(&self, _lang_id: u16) -> Option<&str> {
+        Some(self.name)
+    }
+}
+
+/// Outcome of a command, the third byte of a response.
+#[derive(Debug, defmt::Format, Clone, Copy, PartialEq)]
+#[repr(u8)]
+pub enum Status {
+    Ok = 0x00,
+    UnknownCommand = 0x01,
+    /// The request or response payload doesn't fit the report, or has the wrong length.
+    BadLength = 0x02,
+    BadValue = 0x03,
+    /// The firmware can't handle the command right now, retry later.
+    Busy = 0x04,
+}
+
+/// Answers with `PROTOCOL_VERSION`, handled by `process` itself.
+pub const CMD_PROTOCOL_VERSION: u8 = 0x00;
+/// Answers with the request payload, handled by `process` itself.
+pub const CMD_ECHO: u8 = 0x01;
+
+/// Version of the framing and of the commands handled by `process`.
+pub const PROTOCOL_VERSION: u8 = 1;
+
+const REQUEST_HEADER_LEN: usize = 3;
+const RESPONSE_HEADER_LEN: usize = 4;
+
+/// Commands implemented by the firmware, from 0x02 on.
+pub trait CommandHandler {
+    /// Handles `command`, writing the response payload to `response` and returning its
+    /// length.
+    fn handle(&mut self, command: u8, request: &[u8], response: &mut [u8])
+        -> Result<usize, Status>;
+}
+
+/// Handles a request report, writing the response report to `response`, which has to be as
+/// long as `request`.
+pub fn process<H: CommandHandler>(handler: &mut H, request: &[u8], response: &mut [u8]) {
+    for byte in response.iter_mut() {
+        *byte = 0;
+    }
+    if request.len() < REQUEST_HEADER_LEN || response.len() <= RESPONSE_HEADER_LEN {
+        return;
+    }
+    let (command, sequence, len) = (request[0], request[1], usize::from(request[2]));
+    response[0] = command;
+    response[1] = sequence;
+
+    let (header, payload) = response.split_at_mut(RESPONSE_HEADER_LEN);
+    let result = match request[REQUEST_HEADER_LEN..].get(..len) {
+        None => Err(Status::BadLength),
+        Some(_) if command == CMD_PROTOCOL_VERSION => {
+            payload[0] = PROTOCOL_VERSION;
+            Ok(1)
+        }
+        Some(data) if command == CMD_ECHO => match payload.get_mut(..data.len()) {
+            Some(echo) => {
+                echo.copy_from_slice(data);
+                Ok(data.len())
+            }
+            None => Err(Status::BadLength),
+        },
+        Some(data) => handler.handle(command, data, payload),
+    };
+    match result {
+        Ok(len) if len <= payload.len() => {
+            header[2] = Status::Ok as u8;
+            header[3] = len as u8;
+        }
+        Ok(_) | Err(Status::Ok) => {
+            payload.iter_mut().for_each(|byte| *byte = 0);
+            header[2] = Status::BadLength as u8;
+        }
+        Err(status) => {
+            payload.iter_mut().for_each(|byte| *byte = 0);
+            header[2] = status as u8;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        process, CommandHandler, RawHid, RawReportSize, Status, CMD_ECHO, CMD_PROTOCOL_VERSION,
+        PROTOCOL_VERSION, RAW_32_DESC, RAW_64_DESC,
+    };
+    use crate::descriptor;
+    use crate::hid::{HidClass, HidDevice, HidError, ReportType};
+    use crate::mock_bus;
+
+    /// Adds up the request bytes.
+    struct Sum;
+
+    impl CommandHandler for Sum {
+        fn handle(
+            &mut self,
+            command: u8,
+            request: &[u8],
+            response: &mut [u8],
+        ) -> Result<usize, Status> {
+            match command {
+                0x10 => {
+                    let sum: u32 = request.iter().map(|&b| u32::from(b)).sum();
+                    response[..4].copy_from_slice(&sum.to_le_bytes());
+                    Ok(4)
+                }
+                0x11 => Ok(response.len() + 1),
+                _ => Err(Status::UnknownCommand),
+            }
+        }
+    }
+
+    fn request(command: u8, sequence: u8, payload: &[u8]) -> [u8; 32] {
+        let mut report = [0; 32];
+        report[..3].copy_from_slice(&[command, sequence, payload.len() as u8]);
+        report[3..3 + payload.len()].copy_from_slice(payload);
+        report
+    }
+
+    #[test]
+    fn descriptors_declare_both_reports() {
+        for (descriptor, size) in [(RAW_32_DESC, 32), (RAW_64_DESC, 64)].iter() {
+            let layout = descriptor::parse(descriptor).unwrap();
+            assert_eq!(layout.report_len(ReportType::Input, 0), Some(*size));
+            assert_eq!(layout.report_len(ReportType::Output, 0), Some(*size));
+        }
+        // The usage page and usage VIA looks for
+        assert_eq!(RAW_32_DESC[..6], [0x06, 0x60, 0xff, 0x09, 0x61, 0xa1]);
+        let mut raw = RawHid::new(RawReportSize::Bytes64);
+        assert!(descriptor::check_device(&mut raw).is_ok());
+    }
+
+    #[test]
+    fn output_reports_wait_to_be_taken() {
+        let mut raw = RawHid::new(RawReportSize::Bytes32);
+        assert_eq!(
+            raw.set_report(ReportType::Output, 0, &[1; 31]),
+            Err(HidError::BadLength)
+        );
+        assert_eq!(raw.set_report(ReportType::Output, 0, &[1; 32]), Ok(()));
+        assert_eq!(
+            raw.set_report(ReportType::Output, 0, &[2; 32]),
+            Err(HidError::Busy)
+        );
+        assert_eq!(raw.take_report().unwrap()[..], [1; 32]);
+        assert_eq!(raw.take_report(), None);
+    }
+
+    #[test]
+    fn requests_arrive_over_the_interrupt_pipe() {
+        let (host, mut usb, mut class) = mock_bus::enumerated(|alloc| {
+            HidClass::builder(RawHid::new(RawReportSize::Bytes32), alloc)
+                .max_packet_size(32)
+                .interrupt_out(true)
+                .build()
+        });
+
+        host.send(1, &request(CMD_ECHO, 7, b"hi"));
+        host.poll(&mut usb, &mut [&mut class]);
+        let request = class.device_mut().take_report().unwrap();
+
+        let mut response = [0; 32];
+        process(&mut Sum, &request, &mut response);
+        class.write(&response).unwrap();
+        class.device_mut().set_sent_report(&response);
+        let sent = host.receive(1).unwrap();
+        assert_eq!(sent[..6], [CMD_ECHO, 7, 0, 2, b'h', b'i']);
+        assert_eq!(
+            class.device_mut().get_report(ReportType::Input, 0),
+            Ok(&sent[..])
+        );
+
+        // Answers to a retried request aren't coalesced
+        class.write(&response).unwrap();
+        host.poll(&mut usb, &mut [&mut class]);
+        assert_eq!(host.receive(1), Some(sent));
+    }
+
+    #[test]
+    fn framing_wraps_commands() {
+        let mut response = [0xff; 32];
+        process(
+            &mut Sum,
+            &request(CMD_PROTOCOL_VERSION, 1, &[]),
+            &mut response,
+        );
+        assert_eq!(
+            response[..5],
+            [CMD_PROTOCOL_VERSION, 1, 0, 1, PROTOCOL_VERSION]
+        );
+        assert!(response[5..].iter().all(|&b| b == 0));
+
+        process(&mut Sum, &request(0x10, 2, &[200, 100, 1]), &mut response);
+        assert_eq!(response[..8], [0x10, 2, 0, 4, 45, 1, 0, 0]);
+
+        process(&mut Sum, &request(0x12, 3, &[]), &mut response);
+        assert_eq!(response[..4], [0x12, 3, Status::UnknownCommand as u8, 0]);
+
+        // Responses that don't fit
+        process(&mut Sum, &request(0x11, 4, &[]), &mut response);
+        assert_eq!(response[..4], [0x11, 4, Status::BadLength as u8, 0]);
+
+        // Payloads longer than the report
+        let mut bad = request(CMD_ECHO, 5, &[]);
+        bad[2] = 30;
+        process(&mut Sum, &bad, &mut response);
+        assert_eq!(response[..4], [CMD_ECHO, 5, Status::BadLength as u8, 0]);
+    }
+}
@@ -0,0 +1,377 @@
+//! The format the keymap and VIA settings are saved in, see `flash` for where.
+//!
+//! A header with a magic number, `FORMAT_VERSION` and the keymap dimensions is followed by the
+//! keycodes, the layout options and the macros, and a CRC-32 of all that. `load` only applies
+//! data that passes every check, so the firmware falls back to its compiled keymap when the
+//! flash is blank, written by an older firmware or corrupted. Keycodes without an action in
+//! this firmware leave the compiled action in place.
+//!
+//! `Saver` writes the data to a `Store` once edits settle, and erases it on a factory reset.
+
+use crate::keymap::Keymap;
+use crate::via::{Via, MACRO_BUFFER_SIZE};
+
+/// Bumped whenever the format changes, stored data of other versions is ignored.
+pub const FORMAT_VERSION: u16 = 1;
+
+/// "KMAP", also tells stored data from erased flash.
+const MAGIC: u32 = 0x5041_4d4b;
+
+const HEADER_LEN: usize = 10;
+const CRC_LEN: usize = 4;
+
+/// Why stored data wasn't loaded.
+#[derive(Debug, defmt::Format, Clone, Copy, PartialEq)]
+pub enum LoadError {
+    /// Nothing was saved yet, or it was erased.
+    Empty,
+    /// Saved by a firmware with another format version.
+    Version(u16),
+    /// Saved by a firmware with a keymap of another size.
+    Dimensions,
+    /// The data is truncated or corrupted.
+    Crc,
+}
+
+/// Where the data is kept, the settings page of `flash` on our boards.
+pub trait Store {
+    type Error: defmt::Format;
+
+    /// Returns what was written last, all 0xff when erased.
+    fn read(&self) -> &[u8];
+
+    /// Replaces the stored data with `data`.
+    fn write(&mut self, data: &[u8]) -> Result<(), Self::Error>;
+
+    fn erase(&mut self) -> Result<(), Self::Error>;
+}
+
+/// Saves the keymap and VIA settings once they were left alone for a while, as writing a
+/// `Store` can be slow and wears it out.
+pub struct Saver<S> {
+    store: S,
+    delay_ms: u16,
+    /// Milliseconds until the next save, 0 when there is nothing to save.
+    save_in_ms: u16,
+}
+
+impl<S: Store> Saver<S> {
+    /// Saves `delay_ms` after the last change.
+    pub fn new(store: S, delay_ms: u16) -> Saver<S> {
+        Saver {
+            store,
+            delay_ms: delay_ms.max(1),
+            save_in_ms: 0,
+        }
+    }
+
+    /// Replaces the keymap and VIA settings with the stored ones, see `load`.
+    pub fn load<const LAYERS: usize, const ROWS: usize, const COLS: usize>(
+        &self,
+        keymap: &mut Keymap<LAYERS, ROWS, COLS>,
+        via: &mut Via,
+    ) -> Result<(), LoadError> {
+        load(self.store.read(), keymap, via)
+    }
+
+    /// Restarts the delay, call it on every edit.
+    pub fn changed(&mut self) {
+        self.save_in_ms = self.delay_ms;
+    }
+
+    /// Counts down a millisecond, returning whether the delay is over and `save` is due.
+    pub fn tick(&mut self) -> bool {
+        if self.save_in_ms == 0 {
+            return false;
+        }
+        self.save_in_ms -= 1;
+        self.save_in_ms == 0
+    }
+
+    /// Writes the keymap and VIA settings, `buf` has to be at least `stored_len` long.
+    pub fn save<const LAYERS: usize, const ROWS: usize, const COLS: usize>(
+        &mut self,
+        keymap: &Keymap<LAYERS, ROWS, COLS>,
+        via: &Via,
+        buf: &mut [u8],
+    ) {
+        let len = save(keymap, via, buf);
+        match self.store.write(&buf[..len]) {
+            Ok(()) => defmt::info!("saved the keymap"),
+            Err(err) => defmt::error!("Couldn't save the keymap: {:?}", err),
+        }
+    }
+
+    /// Restores the compiled keymap and default VIA settings, and erases the stored ones.
+    pub fn factory_reset<const LAYERS: usize, const ROWS: usize, const COLS: usize>(
+        &mut self,
+        keymap: &mut Keymap<LAYERS, ROWS, COLS>,
+        via: &mut Via,
+    ) {
+        defmt::info!("restoring the default keymap");
+        keymap.reset();
+        *via = Via::new();
+        self.save_in_ms = 0;
+        if let Err(err) = self.store.erase() {
+            defmt::error!("Couldn't erase the saved keymap: {:?}", err);
+        }
+    }
+}
+
+/// Returns the length of the data `save` writes for a keymap of the given dimensions.
+pub const fn stored_len(layers: usize, rows: usize, cols: usize) -> usize {
+    HEADER_LEN + layers * rows * cols * 2 + 4 + MACRO_BUFFER_SIZE + CRC_LEN
+}
+
+/// Writes the keymap and VIA settings to `buf`, which has to be at least `stored_len` long,
+/// returning the length written.
+pub fn save<const LAYERS: usize, const ROWS: usize, const COLS: usize>(
+    keymap: &Keymap<LAYERS, ROWS, COLS>,
+    via: &Via,
+    buf: &mut [u8],
+) -> usize {
+    let len = stored_len(LAYERS, ROWS, COLS);
+    assert!(buf.len() >= len, "buffer too short for the keymap");
+    let mut writer = Writer { buf, pos: 0 };
+    writer.put(&MAGIC.to_le_bytes());
+    writer.put(&FORMAT_VERSION.to_le_bytes());
+    writer.put(&[LAYERS as u8, ROWS as u8, COLS as u8, 0]);
+    for &code in keymap.keycodes().iter().flatten().flatten() {
+        writer.put(&code.to_le_bytes());
+    }
+    writer.put(&via.layout_options().to_le_bytes());
+    writer.put(via.macros());
+    let crc = crc32(&writer.buf[..writer.pos]);
+    writer.put(&crc.to_le_bytes());
+    writer.pos
+}
+
+/// Replaces the keymap and VIA settings with the ones in `data`, if it passes every check.
+pub fn load<const LAYERS: usize, const ROWS: usize, const COLS: usize>(
+    data: &[u8],
+    keymap: &mut Keymap<LAYERS, ROWS, COLS>,
+    via: &mut Via,
+) -> Result<(), LoadError> {
+    let mut reader = Reader { data, pos: 0 };
+    if reader.u32() != Some(MAGIC) {
+        return Err(LoadError::Empty);
+    }
+    match reader.u16() {
+        Some(FORMAT_VERSION) => {}
+        Some(version) => return Err(LoadError::Version(version)),
+        None => return Err(LoadError::Crc),
+    }
+    if reader.take(4) != Some(&[LAYERS as u8, ROWS as u8, COLS as u8, 0][..]) {
+        return Err(LoadError::Dimensions);
+    }
+    let len = stored_len(LAYERS, ROWS, COLS);
+    let crc_ok = data.len() >= len && {
+        let (stored, crc) = data[..len].split_at(len - CRC_LEN);
+        crc32(stored).to_le_bytes() == crc
+    };
+    if !crc_ok {
+        return Err(LoadError::Crc);
+    }
+
+    for layer in 0..LAYERS {
+        for row in 0..ROWS {
+            for col in 0..COLS {
+                let code = reader.u16().ok_or(LoadError::Crc)?;
+                // Rejected keycodes keep the compiled action
+                keymap.set_keycode(layer, row, col, code);
+            }
+        }
+    }
+    via.set_layout_options(reader.u32().ok_or(LoadError::Crc)?);
+    let mut macros = [0; MACRO_BUFFER_SIZE];
+    macros.copy_from_slice(reader.take(MACRO_BUFFER_SIZE).ok_or(LoadError::Crc)?);
+    via.set_macros(&macros);
+    Ok(())
+}
+
+struct Writer<'a> {
+    buf: &'a mut [u8],
+    pos: usize,
+}
+
+impl Writer<'_> {
+    fn put(&mut self, bytes: &[u8]) {
+        self.buf[self.pos..self.pos + bytes.len()].copy_from_slice(bytes);
+        self.pos += bytes.len();
+    }
+}
+
+struct Reader<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, len: usize) -> Option<&'a [u8]> {
+        let bytes = self.data.get(self.pos..self.pos + len)?;
+        self.pos += len;
+        Some(bytes)
+    }
+
+    fn u16(&mut self) -> Option<u16> {
+        let bytes = self.take(2)?;
+        Some(u16::from_le_bytes([bytes[0], bytes[1]]))
+    }
+
+    fn u32(&mut self) -> Option<u32> {
+        let bytes = self.take(4)?;
+        Some(u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+    }
+}
+
+/// The CRC-32 of zlib and Ethernet.
+fn crc32(data: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for &byte in data {
+        crc ^= u32::from(byte);
+        for _ in 0..8 {
+            let mask = (crc & 1).wrapping_neg();
+            crc = (crc >> 1) ^ (0xedb8_8320 & mask);
+        }
+    }
+    !crc
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{crc32, load, save, stored_len, LoadError, Saver, Store, FORMAT_VERSION};
+    use crate::keymap::{CustomAction, Keymap, KeymapLayers};
+    use crate::via::{Via, MACRO_BUFFER_SIZE};
+    use keyberon::action::{k, l, Action};
+    use keyberon::key_code::KeyCode::{A, B};
+    use keyberon::layout::Layers;
+
+    const LEN: usize = stored_len(2, 1, 2);
+
+    static LAYERS: Layers<CustomAction> = &[&[&[k(A), l(1)]], &[&[k(B), Action::Trans]]];
+
+    /// A `Store` in RAM that counts writes.
+    #[derive(Default)]
+    struct Ram {
+        data: Vec<u8>,
+        writes: usize,
+    }
+
+    impl Store for Ram {
+        type Error = ();
+
+        fn read(&self) -> &[u8] {
+            &self.data
+        }
+
+        fn write(&mut self, data: &[u8]) -> Result<(), ()> {
+            self.data = data.to_vec();
+            self.writes += 1;
+            Ok(())
+        }
+
+        fn erase(&mut self) -> Result<(), ()> {
+            self.data = vec![0xff; LEN];
+            Ok(())
+        }
+    }
+
+    fn defaults() -> Keymap<2, 1, 2> {
+        Keymap::new(LAYERS, Box::leak(Box::new(KeymapLayers::new())))
+    }
+
+    fn edited() -> (Keymap<2, 1, 2>, Via) {
+        let mut keymap = defaults();
+        keymap.set_keycode(1, 0, 0, 0x29);
+        let mut via = Via::new();
+        via.set_layout_options(7);
+        let mut macros = [0; MACRO_BUFFER_SIZE];
+        macros[..2].copy_from_slice(b"hi");
+        via.set_macros(&macros);
+        (keymap, via)
+    }
+
+    #[test]
+    fn crc_matches_zlib() {
+        assert_eq!(crc32(b"123456789"), 0xcbf4_3926);
+    }
+
+    #[test]
+    fn edits_survive_a_round_trip() {
+        let (keymap, via) = edited();
+        let mut buf = [0xff; LEN + 4];
+        assert_eq!(save(&keymap, &via, &mut buf), LEN);
+        assert_eq!(buf[..10], [b'K', b'M', b'A', b'P', 1, 0, 2, 1, 2, 0]);
+        assert_eq!(buf[10..18], [0x04, 0, 0x01, 0x51, 0x29, 0, 0x01, 0]);
+
+        let (mut loaded, mut loaded_via) = (defaults(), Via::new());
+        assert_eq!(load(&buf, &mut loaded, &mut loaded_via), Ok(()));
+        assert_eq!(loaded.keycodes(), keymap.keycodes());
+        assert_eq!(loaded_via.layout_options(), 7);
+        assert_eq!(loaded_via.macros()[..3], *b"hi\0");
+
+        // The compiled keymap is still what resets restore
+        loaded.reset();
+        assert_eq!(loaded.keycode(1, 0, 0), Some(0x05));
+    }
+
+    #[test]
+    fn bad_data_leaves_the_defaults() {
+        let (keymap, via) = edited();
+        let mut buf = [0; LEN];
+        save(&keymap, &via, &mut buf);
+        let check = |data: &[u8]| {
+            let (mut loaded, mut loaded_via) = (defaults(), Via::new());
+            let result = load(data, &mut loaded, &mut loaded_via);
+            assert_eq!(loaded.keycode(1, 0, 0), Some(0x05));
+            assert_eq!(loaded_via.layout_options(), 0);
+            result
+        };
+
+        assert_eq!(check(&[0xff; LEN]), Err(LoadError::Empty));
+        assert_eq!(check(&buf[..LEN - 1]), Err(LoadError::Crc));
+
+        let mut corrupted = buf;
+        corrupted[12] ^= 1;
+        assert_eq!(check(&corrupted), Err(LoadError::Crc));
+
+        let mut newer = buf;
+        newer[4] = (FORMAT_VERSION + 1) as u8;
+        assert_eq!(check(&newer), Err(LoadError::Version(FORMAT_VERSION + 1)));
+
+        let mut other_size = buf;
+        other_size[6] = 3;
+        assert_eq!(check(&other_size), Err(LoadError::Dimensions));
+    }
+
+    #[test]
+    fn saver_waits_for_edits_to_settle() {
+        let (keymap, via) = edited();
+        let mut buf = [0; LEN];
+        let mut saver = Saver::new(Ram::default(), 3);
+        saver.changed();
+        assert!(!saver.tick());
+        saver.changed();
+        assert!(!saver.tick());
+        assert!(!saver.tick());
+        assert!(saver.tick());
+        saver.save(&keymap, &via, &mut buf);
+        assert!(!saver.tick());
+        assert_eq!(saver.store.writes, 1);
+
+        let (mut loaded, mut loaded_via) = (defaults(), Via::new());
+        assert_eq!(saver.load(&mut loaded, &mut loaded_via), Ok(()));
+        assert_eq!(loaded.keycodes(), keymap.keycodes());
+
+        // A factory reset drops pending saves along with the stored data
+        saver.changed();
+        saver.factory_reset(&mut loaded, &mut loaded_via);
+        assert_eq!(loaded.keycode(1, 0, 0), Some(0x05));
+        assert_eq!(loaded_via.layout_options(), 0);
+        assert!((0..3).all(|_| !saver.tick()));
+        assert_eq!(
+            saver.load(&mut loaded, &mut loaded_via),
+            Err(LoadError::Empty)
+        );
+    }
+}
@@ -0,0 +1,235 @@
+//! System control, the power keys of a keyboard.
+//!
+//! The input report is a single byte with one bit per `SystemKey`. Like `ConsumerControl`, the
+//! device either gets its own interface, or declares a report ID so it can share one.
+
+use crate::descriptor::{
+    generic_desktop, Collection, DescriptorBuilder, ItemFlags, SharedDescriptor, UsagePage,
+};
+use crate::hid::{HidDevice, HidError, Protocol, ReportType, Subclass};
+
+/// The System Control usages of the Generic Desktop page, in report bit order.
+#[derive(Debug, defmt::Format, Clone, Copy, PartialEq)]
+pub enum SystemKey {
+    PowerDown,
+    Sleep,
+    /// Only reaches a sleeping host through remote wakeup, see `power::Power::wake_host`.
+    WakeUp,
+}
+
+impl SystemKey {
+    const fn bit(self) -> u8 {
+        1 << self as u8
+    }
+}
+
+const REPORT_SIZE: usize = 1;
+
+/// The system control collection, with a report ID unless `report_id` is 0.
+pub const fn system_collection(builder: DescriptorBuilder, report_id: u8) -> DescriptorBuilder {
+    builder
+        .usage_page(UsagePage::GenericDesktop)
+        .usage(generic_desktop::SYSTEM_CONTROL)
+        .collection(Collection::Application)
+        .optional_report_id(report_id)
+        .logical_minimum(0)
+        .logical_maximum(1)
+        .usage_minimum(generic_desktop::SYSTEM_POWER_DOWN)
+        .usage_maximum(generic_desktop::SYSTEM_WAKE_UP)
+        .report_size(1)
+        .report_count(3)
+        .input(ItemFlags::DATA_VARIABLE)
+        .pad_input()
+        .end_collection()
+}
+
+const SYSTEM: DescriptorBuilder = system_collection(DescriptorBuilder::new(), 0);
+pub const SYSTEM_DESC: &[u8] = &SYSTEM.finish::<{ SYSTEM.len() }>();
+
+/// Length of `SYSTEM_DESC` with the two byte Report ID item added.
+const SYSTEM_DESC_WITH_ID_LEN: usize = SYSTEM.len() + 2;
+
+/// `SYSTEM_DESC` declaring the reports `ID`.
+struct SystemDescWithId<const ID: u8>;
+
+impl<const ID: u8> SystemDescWithId<ID> {
+    const DESC: [u8; SYSTEM_DESC_WITH_ID_LEN] = {
+        assert!(ID != 0, "report ID 0 is reserved");
+        system_collection(DescriptorBuilder::new(), ID).finish::<SYSTEM_DESC_WITH_ID_LEN>()
+    };
+}
+
+pub struct SystemControl {
+    name: &'static str,
+    descriptor: SharedDescriptor,
+    report: [u8; REPORT_SIZE],
+}
+
+impl SystemControl {
+    /// Creates a device for an interface of its own, with reports without ID.
+    pub fn new() -> SystemControl {
+        SystemControl {
+            name: "System control",
+            descriptor: SharedDescriptor::new(SYSTEM_DESC),
+            report: [0; REPORT_SIZE],
+        }
+    }
+
+    /// Prefixes the reports with `ID`, which must not be 0. Write them with
+    /// `HidClass::write_report`.
+    pub fn with_report_id<const ID: u8>(mut self) -> SystemControl {
+        self.descriptor = SharedDescriptor::with_report_id(&SystemDescWithId::<ID>::DESC, ID);
+        self
+    }
+
+    pub fn with_name(mut self, name: &'static str) -> SystemControl {
+        self.name = name;
+        self
+    }
+
+    /// Returns the report ID, 0 when the reports have none.
+    pub fn report_id(&self) -> u8 {
+        self.descriptor.report_id()
+    }
+
+    /// Adds a key to the report, returning whether the report changed.
+    pub fn press(&mut self, key: SystemKey) -> bool {
+        self.set_report_byte(self.report[0] | key.bit())
+    }
+
+    /// Removes a key from the report, returning whether the report changed.
+    pub fn release(&mut self, key: SystemKey) -> bool {
+        self.set_report_byte(self.report[0] & !key.bit())
+    }
+
+    /// Releases all keys, returning whether the report changed.
+    pub fn clear(&mut self) -> bool {
+        self.set_report_byte(0)
+    }
+
+    /// Returns the input report, without report ID.
+    pub fn report(&self) -> &[u8] {
+        &self.report
+    }
+
+    fn set_report_byte(&mut self, byte: u8) -> bool {
+        let changed = self.report[0] != byte;
+        self.report[0] = byte;
+        changed
+    }
+}
+
+impl Default for SystemControl {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl HidDevice for SystemControl {
+    fn subclass(&self) -> Subclass {
+        Subclass::None
+    }
+
+    fn protocol(&self) -> Protocol {
+        Protocol::None
+    }
+
+    fn report_descriptor(&self) -> &[u8] {
+        self.descriptor.bytes()
+    }
+
+    fn get_report(&mut self, report_type: ReportType, report_id: u8) -> Result<&[u8], HidError> {
+        self.descriptor.check_input(report_type, report_id)?;
+        Ok(&self.report)
+    }
+
+    fn input_report(&self, _report_id: u8) -> Result<&[u8], HidError> {
+        Ok(&self.report)
+    }
+
+    fn set_report(
+        &mut self,
+        _report_type: ReportType,
+        _report_id: u8,
+        _data: &[u8],
+    ) -> Result<(), HidError> {
+        Err(HidError::UnsupportedReportType)
+    }
+
+    fn report_len(&self, report_type: ReportType, _report_id: u8) -> Option<usize> {
+        match report_type {
+            ReportType::Input => Some(REPORT_SIZE),
+            _ => None,
+        }
+    }
+
+    fn report_ids(&self) -> &[u8] {
+        self.descriptor.report_ids()
+    }
+
+    fn interface_name(&self, _lang_id: u16) -> Option<&str> {
+        Some(self.name)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{SystemControl, SystemKey, SYSTEM_DESC};
+    use crate::descriptor;
+    use crate::hid::{HidDevice, ReportType};
+
+    #[rustfmt::skip]
+    const SYSTEM_DESC_BYTES: &[u8] = &[
+        0x05, 0x01,       // USAGE_PAGE (Generic Desktop)
+        0x09, 0x80,       // USAGE (System Control)
+        0xa1, 0x01,       // COLLECTION (Application)
+        0x15, 0x00,       //   LOGICAL_MINIMUM (0)
+        0x25, 0x01,       //   LOGICAL_MAXIMUM (1)
+        0x19, 0x81,       //   USAGE_MINIMUM (System Power Down)
+        0x29, 0x83,       //   USAGE_MAXIMUM (System Wake Up)
+        0x75, 0x01,       //   REPORT_SIZE (1)
+        0x95, 0x03,       //   REPORT_COUNT (3)
+        0x81, 0x02,       //   INPUT (Data,Var,Abs)
+        0x95, 0x05,       //   REPORT_COUNT (5)
+        0x75, 0x01,       //   REPORT_SIZE (1)
+        0x81, 0x03,       //   INPUT (Cnst,Var,Abs)
+        0xc0,             // END_COLLECTION
+    ];
+
+    #[test]
+    fn descriptor_declares_three_keys() {
+        assert_eq!(SYSTEM_DESC, SYSTEM_DESC_BYTES);
+        let mut system = SystemControl::new();
+        let layout = descriptor::check_device(&mut system).unwrap();
+        assert!(!layout.uses_report_ids());
+        assert_eq!(layout.report_len(ReportType::Input, 0), Some(1));
+    }
+
+    #[test]
+    fn report_id_is_declared() {
+        let mut system = SystemControl::new().with_report_id::<4>();
+        assert_eq!(system.report_descriptor()[6..8], [0x85, 4]);
+        let layout = descriptor::check_device(&mut system).unwrap();
+        assert_eq!(layout.report_ids().collect::<Vec<_>>(), [4]);
+        assert_eq!(layout.report_len(ReportType::Input, 4), Some(1));
+    }
+
+    #[test]
+    fn keys_set_report_bits() {
+        let mut system = SystemControl::new();
+        assert!(system.press(SystemKey::Sleep));
+        assert_eq!(system.report(), [0b010]);
+        assert!(system.press(SystemKey::WakeUp));
+        assert!(!system.press(SystemKey::WakeUp));
+        assert_eq!(system.report(), [0b110]);
+        assert!(system.release(SystemKey::Sleep));
+        assert!(!system.release(SystemKey::PowerDown));
+        assert_eq!(system.report(), [0b100]);
+        assert!(system.press(SystemKey::PowerDown));
+        assert_eq!(system.get_report(ReportType::Input, 0), Ok(&[0b101][..]));
+
+        assert!(system.clear());
+        assert!(!system.clear());
+        assert_eq!(system.report(), [0]);
+    }
+}
@@ -0,0 +1,370 @@
+//! The VIA remapping protocol, over `RawHid` with 32 byte reports.
+//!
+//! `Via::process` answers a request in place, like QMK: the response is the request with the
+//! values asked for filled in, or with 0xff in the first byte for unsupported requests. It
+//! edits the `Keymap`, and keeps the layout options and macros buffer VIA stores on the
+//! keyboard.
+
+use crate::hid::HidClass;
+use crate::keymap::Keymap;
+use crate::raw::RawHid;
+use usb_device::bus::UsbBus;
+
+/// The protocol version VIA checks before anything else, that of the QMK keycodes in `keymap`.
+pub const PROTOCOL_VERSION: u16 = 0x0009;
+
+/// Length of the requests and responses.
+pub const REPORT_SIZE: usize = 32;
+
+/// Number of macros VIA shows, stored one after the other in the macros buffer.
+pub const MACRO_COUNT: u8 = 16;
+pub const MACRO_BUFFER_SIZE: usize = 512;
+
+const GET_PROTOCOL_VERSION: u8 = 0x01;
+const GET_KEYBOARD_VALUE: u8 = 0x02;
+const SET_KEYBOARD_VALUE: u8 = 0x03;
+const DYNAMIC_KEYMAP_GET_KEYCODE: u8 = 0x04;
+const DYNAMIC_KEYMAP_SET_KEYCODE: u8 = 0x05;
+const DYNAMIC_KEYMAP_RESET: u8 = 0x06;
+const EEPROM_RESET: u8 = 0x0a;
+const DYNAMIC_KEYMAP_MACRO_GET_COUNT: u8 = 0x0c;
+const DYNAMIC_KEYMAP_MACRO_GET_BUFFER_SIZE: u8 = 0x0d;
+const DYNAMIC_KEYMAP_MACRO_GET_BUFFER: u8 = 0x0e;
+const DYNAMIC_KEYMAP_MACRO_SET_BUFFER: u8 = 0x0f;
+const DYNAMIC_KEYMAP_MACRO_RESET: u8 = 0x10;
+const DYNAMIC_KEYMAP_GET_LAYER_COUNT: u8 = 0x11;
+const DYNAMIC_KEYMAP_GET_BUFFER: u8 = 0x12;
+const DYNAMIC_KEYMAP_SET_BUFFER: u8 = 0x13;
+const UNHANDLED: u8 = 0xff;
+
+/// Keyboard value of `GET_KEYBOARD_VALUE` and `SET_KEYBOARD_VALUE`.
+const LAYOUT_OPTIONS: u8 = 0x02;
+
+/// Offset of the data in buffer requests, after the command, offset and size.
+const BUFFER_DATA: usize = 4;
+const MAX_BUFFER_CHUNK: usize = REPORT_SIZE - BUFFER_DATA;
+
+pub struct Via {
+    layout_options: u32,
+    macros: [u8; MACRO_BUFFER_SIZE],
+}
+
+impl Via {
+    pub const fn new() -> Via {
+        Via {
+            layout_options: 0,
+            macros: [0; MACRO_BUFFER_SIZE],
+        }
+    }
+
+    /// Returns the layout options, a bitfield defined by the keyboard's VIA definition.
+    pub fn layout_options(&self) -> u32 {
+        self.layout_options
+    }
+
+    pub fn set_layout_options(&mut self, layout_options: u32) {
+        self.layout_options = layout_options;
+    }
+
+    /// Returns the macros, null-terminated in QMK's format.
+    pub fn macros(&self) -> &[u8; MACRO_BUFFER_SIZE] {
+        &self.macros
+    }
+
+    pub fn set_macros(&mut self, macros: &[u8; MACRO_BUFFER_SIZE]) {
+        self.macros = *macros;
+    }
+
+    /// Answers a request, turning `report` into the response. Returns whether the keymap,
+    /// layout options or macros changed.
+    pub fn process<const LAYERS: usize, const ROWS: usize, const COLS: usize>(
+        &mut self,
+        keymap: &mut Keymap<LAYERS, ROWS, COLS>,
+        report: &mut [u8],
+    ) -> bool {
+        if report.len() != REPORT_SIZE {
+            return false;
+        }
+        let mut changed = false;
+        match report[0] {
+            GET_PROTOCOL_VERSION => report[1..3].copy_from_slice(&PROTOCOL_VERSION.to_be_bytes()),
+            GET_KEYBOARD_VALUE if report[1] == LAYOUT_OPTIONS => {
+                report[2..6].copy_from_slice(&self.layout_options.to_be_bytes())
+            }
+            SET_KEYBOARD_VALUE if report[1] == LAYOUT_OPTIONS => {
+                let mut options = [0; 4];
+                options.copy_from_slice(&report[2..6]);
+                self.layout_options = u32::from_be_bytes(options);
+                changed = true;
+            }
+            DYNAMIC_KEYMAP_GET_KEYCODE => {
+                let (layer, row, col) = position(report);
+                let code = keymap.keycode(layer, row, col).unwrap_or(0);
+                report[4..6].copy_from_slice(&code.to_be_bytes());
+            }
+            DYNAMIC_KEYMAP_SET_KEYCODE => {
+                let (layer, row, col) = position(report);
+                let code = u16::from_be_bytes([report[4], report[5]]);
+                changed = keymap.set_keycode(layer, row, col, code);
+            }
+            DYNAMIC_KEYMAP_RESET => {
+                keymap.reset();
+                changed = true;
+            }
+            EEPROM_RESET => {
+                keymap.reset();
+                *self = Via::new();
+                changed = true;
+            }
+            DYNAMIC_KEYMAP_MACRO_GET_COUNT => report[1] = MACRO_COUNT,
+            DYNAMIC_KEYMAP_MACRO_GET_BUFFER_SIZE => {
+                report[1..3].copy_from_slice(&(MACRO_BUFFER_SIZE as u16).to_be_bytes())
+            }
+            DYNAMIC_KEYMAP_MACRO_GET_BUFFER => {
+                let (offset, data) = buffer_chunk(report, MACRO_BUFFER_SIZE);
+                data.copy_from_slice(&self.macros[offset..offset + data.len()]);
+            }
+            DYNAMIC_KEYMAP_MACRO_SET_BUFFER => {
+                let (offset, data) = buffer_chunk(report, MACRO_BUFFER_SIZE);
+                self.macros[offset..offset + data.len()].copy_from_slice(data);
+                changed = true;
+            }
+            DYNAMIC_KEYMAP_MACRO_RESET => {
+                self.macros = [0; MACRO_BUFFER_SIZE];
+                changed = true;
+            }
+            DYNAMIC_KEYMAP_GET_LAYER_COUNT => report[1] = LAYERS as u8,
+            DYNAMIC_KEYMAP_GET_BUFFER => {
+                let (offset, data) = buffer_chunk(report, LAYERS * ROWS * COLS * 2);
+                for (i, byte) in data.iter_mut().enumerate() {
+                    *byte = keymap_byte(keymap, offset + i);
+                }
+            }
+            DYNAMIC_KEYMAP_SET_BUFFER => {
+                let (offset, data) = buffer_chunk(report, LAYERS * ROWS * COLS * 2);
+                set_keymap_bytes(keymap, offset, data);
+                changed = true;
+            }
+            _ => report[0] = UNHANDLED,
+        }
+        changed
+    }
+
+    /// Answers the request VIA sent over `raw_hid`, if any, see `process`.
+    pub fn answer<B: UsbBus, const LAYERS: usize, const ROWS: usize, const COLS: usize>(
+        &mut self,
+        raw_hid: &mut HidClass<'_, B, RawHid>,
+        keymap: &mut Keymap<LAYERS, ROWS, COLS>,
+    ) -> bool {
+        let mut report = match raw_hid.device_mut().take_report() {
+            Some(report) => report,
+            None => return false,
+        };
+        let changed = self.process(keymap, &mut report);
+        match raw_hid.write(&report) {
+            Ok(_) => raw_hid.device_mut().set_sent_report(&report),
+            Err(err) => defmt::error!("Couldn't answer VIA: {:?}", err),
+        }
+        changed
+    }
+}
+
+impl Default for Via {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Reads the layer, row and column of a keycode request.
+fn position(report: &[u8]) -> (usize, usize, usize) {
+    (
+        usize::from(report[1]),
+        usize::from(report[2]),
+        usize::from(report[3]),
+    )
+}
+
+/// Splits a buffer request into the offset and the data, clamped to a buffer of `len` bytes.
+fn buffer_chunk(report: &mut [u8], len: usize) -> (usize, &mut [u8]) {
+    let offset = usize::from(u16::from_be_bytes([report[1], report[2]])).min(len);
+    let size = usize::from(report[3])
+        .min(MAX_BUFFER_CHUNK)
+        .min(len - offset);
+    (offset, &mut report[BUFFER_DATA..BUFFER_DATA + size])
+}
+
+/// Returns a byte of the keymap, laid out as VIA expects: big endian keycodes by layer, row
+/// and column.
+fn keymap_byte<const LAYERS: usize, const ROWS: usize, const COLS: usize>(
+    keymap: &Keymap<LAYERS, ROWS, COLS>,
+    offset: usize,
+) -> u8 {
+    let (layer, row, col) = keymap_position::<ROWS, COLS>(offset / 2);
+    let code = keymap.keycode(layer, row, col).unwrap_or(0);
+    code.to_be_bytes()[offset % 2]
+}
+
+/// Writes bytes of the keymap a keycode at a time, the keymap rejects half-written ones.
+fn set_keymap_bytes<const LAYERS: usize, const ROWS: usize, const COLS: usize>(
+    keymap: &mut Keymap<LAYERS, ROWS, COLS>,
+    offset: usize,
+    data: &[u8],
+) {
+    let end = offset + data.len();
+    for index in (offset / 2..).take_while(|index| index * 2 < end) {
+        let (layer, row, col) = keymap_position::<ROWS, COLS>(index);
+        if let Some(code) = keymap.keycode(layer, row, col) {
+            let mut bytes = code.to_be_bytes();
+            for (i, byte) in bytes.iter_mut().enumerate() {
+                if let Some(&new) = (index * 2 + i)
+                    .checked_sub(offset)
+                    .and_then(|i| data.get(i))
+                {
+                    *byte = new;
+                }
+            }
+            keymap.set_keycode(layer, row, col, u16::from_be_bytes(bytes));
+        }
+    }
+}
+
+fn keymap_position<const ROWS: usize, const COLS: usize>(index: usize) -> (usize, usize, usize) {
+    (index / (ROWS * COLS), index / COLS % ROWS, index % COLS)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Via, MACRO_BUFFER_SIZE, REPORT_SIZE};
+    use crate::hid::HidClass;
+    use crate::keymap::{keycode, CustomAction, Keymap, KeymapLayers};
+    use crate::mock_bus;
+    use crate::raw::{RawHid, RawReportSize};
+    use keyberon::action::{k, l, Action};
+    use keyberon::key_code::KeyCode::*;
+    use keyberon::layout::Layers;
+
+    static LAYERS: Layers<CustomAction> = &[
+        &[&[k(A), k(B), k(C)], &[k(D), k(E), l(1)]],
+        &[&[k(Kb1), k(Kb2), k(Kb3)], &[k(Kb4), k(Kb5), Action::Trans]],
+    ];
+
+    fn keymap() -> Keymap<2, 2, 3> {
+        Keymap::new(LAYERS, Box::leak(Box::new(KeymapLayers::new())))
+    }
+
+    fn request(bytes: &[u8]) -> [u8; REPORT_SIZE] {
+        let mut report = [0; REPORT_SIZE];
+        report[..bytes.len()].copy_from_slice(bytes);
+        report
+    }
+
+    #[test]
+    fn reports_versions_and_sizes() {
+        let (mut via, mut keymap) = (Via::new(), keymap());
+        let mut report = request(&[0x01]);
+        assert!(!via.process(&mut keymap, &mut report));
+        assert_eq!(report[..3], [0x01, 0x00, 0x09]);
+
+        let mut report = request(&[0x11]);
+        via.process(&mut keymap, &mut report);
+        assert_eq!(report[..2], [0x11, 2]);
+
+        let mut report = request(&[0x0c]);
+        via.process(&mut keymap, &mut report);
+        assert_eq!(report[..2], [0x0c, 16]);
+        let mut report = request(&[0x0d]);
+        via.process(&mut keymap, &mut report);
+        assert_eq!(report[..3], [0x0d, 0x02, 0x00]);
+
+        // Lighting isn't supported
+        let mut report = request(&[0x08, 0x80, 0x01]);
+        via.process(&mut keymap, &mut report);
+        assert_eq!(report[..3], [0xff, 0x80, 0x01]);
+    }
+
+    #[test]
+    fn keycodes_are_read_and_written() {
+        let (mut via, mut keymap) = (Via::new(), keymap());
+        let mut report = request(&[0x04, 1, 0, 2]);
+        via.process(&mut keymap, &mut report);
+        assert_eq!(report[..6], [0x04, 1, 0, 2, 0x00, 0x20]);
+
+        let mut report = request(&[0x05, 0, 1, 2, 0x52, 0x01]);
+        assert!(via.process(&mut keymap, &mut report));
+        assert_eq!(keymap.keycode(0, 1, 2), Some(keycode::default_layer(1)));
+        // Out of the keymap
+        let mut report = request(&[0x05, 2, 0, 0, 0x00, 0x04]);
+        assert!(!via.process(&mut keymap, &mut report));
+
+        let mut report = request(&[0x06]);
+        assert!(via.process(&mut keymap, &mut report));
+        assert_eq!(keymap.keycode(0, 1, 2), Some(keycode::momentary(1)));
+    }
+
+    #[test]
+    fn keymap_buffer_is_big_endian() {
+        let (mut via, mut keymap) = (Via::new(), keymap());
+        // The last keycode of layer 0 and the first of layer 1
+        let mut report = request(&[0x12, 0x00, 10, 4]);
+        via.process(&mut keymap, &mut report);
+        assert_eq!(report[..8], [0x12, 0x00, 10, 4, 0x51, 0x01, 0x00, 0x1e]);
+
+        // Past the end
+        let mut report = request(&[0x12, 0x00, 22, 28]);
+        via.process(&mut keymap, &mut report);
+        assert_eq!(report[4..8], [0x00, 0x01, 0, 0]);
+
+        let mut report = request(&[0x13, 0x00, 1, 3, 0x29, 0x00, 0x2a]);
+        assert!(via.process(&mut keymap, &mut report));
+        assert_eq!(keymap.keycode(0, 0, 0), Some(0x29));
+        assert_eq!(keymap.keycode(0, 0, 1), Some(0x2a));
+
+        // Whole keycodes are written, no byte on its own is a keycode with an action
+        let mut report = request(&[0x13, 0x00, 0, 2, 0x52, 0x01]);
+        via.process(&mut keymap, &mut report);
+        assert_eq!(keymap.keycode(0, 0, 0), Some(keycode::default_layer(1)));
+    }
+
+    #[test]
+    fn layout_options_and_macros_are_stored() {
+        let (mut via, mut keymap) = (Via::new(), keymap());
+        let mut report = request(&[0x03, 0x02, 0, 0, 1, 2]);
+        assert!(via.process(&mut keymap, &mut report));
+        assert_eq!(via.layout_options(), 0x0102);
+        let mut report = request(&[0x02, 0x02]);
+        via.process(&mut keymap, &mut report);
+        assert_eq!(report[..6], [0x02, 0x02, 0, 0, 1, 2]);
+
+        let end = (MACRO_BUFFER_SIZE - 2) as u16;
+        let mut report = request(&[0x0f, (end >> 8) as u8, end as u8, 4, b'a', 0, b'b', 0]);
+        assert!(via.process(&mut keymap, &mut report));
+        assert_eq!(via.macros()[MACRO_BUFFER_SIZE - 2..], [b'a', 0]);
+        let mut report = request(&[0x0e, (end >> 8) as u8, end as u8, 28]);
+        via.process(&mut keymap, &mut report);
+        assert_eq!(report[4..8], [b'a', 0, 0, 0]);
+
+        let mut report = request(&[0x0a]);
+        assert!(via.process(&mut keymap, &mut report));
+        assert_eq!(via.layout_options(), 0);
+        assert_eq!(via.macros()[MACRO_BUFFER_SIZE - 2], 0);
+    }
+
+    #[test]
+    fn answers_over_raw_hid() {
+        let (host, mut usb, mut raw_hid) = mock_bus::enumerated(|alloc| {
+            HidClass::builder(RawHid::new(RawReportSize::Bytes32), alloc)
+                .max_packet_size(REPORT_SIZE as u16)
+                .interrupt_out(true)
+                .build()
+        });
+        let (mut via, mut keymap) = (Via::new(), keymap());
+        assert!(!via.answer(&mut raw_hid, &mut keymap));
+
+        host.send(1, &request(&[0x05, 0, 0, 1, 0x00, 0x29]));
+        host.poll(&mut usb, &mut [&mut raw_hid]);
+        assert!(via.answer(&mut raw_hid, &mut keymap));
+        assert_eq!(keymap.keycode(0, 0, 1), Some(0x29));
+        let response = host.receive(1).unwrap();
+        assert_eq!(response[..6], [0x05, 0, 0, 1, 0x00, 0x29]);
+    }
+}